@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Load generation over loopback, comparing servers with different numbers of `SO_REUSEPORT` UDP
+//! sockets.
+
+#[macro_use]
+extern crate criterion;
+extern crate libc;
+extern crate num_cpus;
+extern crate pepbut;
+extern crate pepbut_nsd;
+extern crate tokio;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{context, QUERY};
+use criterion::Criterion;
+use pepbut_nsd::config::Config;
+use pepbut_nsd::server;
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+use tokio::prelude::Future;
+use tokio::runtime::Runtime;
+
+const CLIENTS: usize = 16;
+const QUERIES_PER_CLIENT: usize = 200;
+
+fn start_server(sockets: usize) -> (Runtime, server::Running, SocketAddr) {
+    let context = context(&Config::default());
+
+    // The same steps nsd takes on startup
+    let runtime = Runtime::new().unwrap();
+    let udp_sockets = server::bind_udp(&"127.0.0.1:0".parse().unwrap(), sockets).unwrap();
+    let addr = udp_sockets[0].local_addr().unwrap();
+    let servers = udp_sockets
+        .into_iter()
+        .map(|socket| server::serve_udp(socket, context.clone()).unwrap())
+        .collect();
+    let running = server::spawn(servers, &runtime.executor());
+    (runtime, running, addr)
+}
+
+/// Sends queries from many client sockets at once, each waiting for its response before sending
+/// the next query.
+fn run_clients(addr: SocketAddr) {
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            thread::spawn(move || {
+                let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.connect(addr).unwrap();
+                socket
+                    .set_read_timeout(Some(Duration::from_secs(1)))
+                    .unwrap();
+                let mut buf = [0; 512];
+                for _ in 0..QUERIES_PER_CLIENT {
+                    socket.send(QUERY).unwrap();
+                    socket.recv(&mut buf).unwrap();
+                }
+            })
+        }).collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+}
+
+fn udp_load(c: &mut Criterion) {
+    let mut counts = vec![1, 2, 4, num_cpus::get()];
+    counts.sort();
+    counts.dedup();
+    c.bench_function_over_inputs(
+        &format!(
+            "{} UDP queries from {} clients, by server socket count",
+            CLIENTS * QUERIES_PER_CLIENT,
+            CLIENTS
+        ),
+        |b, &sockets| {
+            let (runtime, running, addr) = start_server(sockets);
+            b.iter(|| run_clients(addr));
+            drop(running);
+            runtime.shutdown_now().wait().unwrap();
+        },
+        counts,
+    );
+}
+
+criterion_group!(
+    name = udp;
+    config = Criterion::default().sample_size(10);
+    targets = udp_load
+);
+criterion_main!(udp);
@@ -10,13 +10,17 @@ extern crate pepbut_nsd;
 extern crate serde_json;
 extern crate tabwriter;
 
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use failure::ResultExt;
-use pepbut_nsd::ctl::Request;
-use std::collections::HashMap;
+use pepbut_nsd::ctl::{
+    QueryResponse, Request, RequestEnvelope, ResourceRecord, Response, ResponseEnvelope,
+    PROTOCOL_VERSION,
+};
+use pepbut_nsd::events::Event;
+use serde_json::de::{Deserializer, IoRead, StreamDeserializer};
 use std::fs;
-use std::io::{self, Write};
-use std::net::Shutdown;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown};
 use std::os::unix::net::UnixStream;
 use tabwriter::TabWriter;
 
@@ -33,6 +37,103 @@ fn main() -> Result<(), failure::Error> {
                     "Unix control socket to listen on (default {})",
                     DEFAULT_SOCKET_PATH
                 )).takes_value(true),
+        ).subcommand(
+            SubCommand::with_name("activate-zone")
+                .about("Make the staged version of a zone live")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("add-record")
+                .about("Add a record to a loaded zone")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("Fully qualified name of the record")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("ttl")
+                        .value_name("TTL")
+                        .help("TTL of the record")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("type")
+                        .value_name("TYPE")
+                        .help("Type of the record")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("rdata")
+                        .value_name("RDATA")
+                        .help("Record data, as in a zone file")
+                        .multiple(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("write")
+                        .long("write")
+                        .help("Also write the updated zone to the file it was loaded from"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("compare-zone")
+                .about("Show how the staged version of a zone differs from the live one")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("discard-zone")
+                .about("Throw away the staged version of a zone")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("dump-zone")
+                .about("Print the records of a loaded zone as a zone file")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("name")
+                        .long("name")
+                        .value_name("NAME")
+                        .help("Only print records at or below this name")
+                        .takes_value(true),
+                ).arg(
+                    Arg::with_name("type")
+                        .long("type")
+                        .value_name("TYPE")
+                        .help("Only print records of this type")
+                        .takes_value(true),
+                ).arg(
+                    Arg::with_name("staged")
+                        .long("staged")
+                        .help("Print the staged version of the zone"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("hello")
+                .about("Show the server version, and what this user may do with it"),
         ).subcommand(SubCommand::with_name("list-zones").about("List loaded zones"))
         .subcommand(
             SubCommand::with_name("load-zone")
@@ -44,6 +145,128 @@ fn main() -> Result<(), failure::Error> {
                         .takes_value(true)
                         .required(true),
                 ),
+        ).subcommand(
+            SubCommand::with_name("query")
+                .about("Answer a query without going over the network, and show the response")
+                .arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("Name to query")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("type")
+                        .value_name("TYPE")
+                        .help("Record type to query (default A)")
+                        .takes_value(true),
+                ).arg(
+                    Arg::with_name("staged")
+                        .long("staged")
+                        .help("Answer from staged zones in place of the live ones"),
+                ).arg(
+                    Arg::with_name("client")
+                        .long("client")
+                        .value_name("ADDRESS")
+                        .help(
+                            "Also show whether UDP responses to this address are being rate \
+                             limited",
+                        ).takes_value(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("remove-record")
+                .about("Remove a record from a loaded zone")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("name")
+                        .value_name("NAME")
+                        .help("Fully qualified name of the record")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("type")
+                        .value_name("TYPE")
+                        .help("Type of the record")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("rdata")
+                        .value_name("RDATA")
+                        .help("Record data, as in a zone file")
+                        .multiple(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("write")
+                        .long("write")
+                        .help("Also write the updated zone to the file it was loaded from"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("reload-tls").about("Reload the TLS certificate and key"),
+        ).subcommand(
+            SubCommand::with_name("reopen-query-log")
+                .about("Reopen the query log file after it has been rotated"),
+        ).subcommand(
+            SubCommand::with_name("shadow-report")
+                .about("Show how the staged version of a zone answered real queries")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("shadow-zone")
+                .about(
+                    "Compare the answers the staged version of a zone would give to real queries \
+                     with the live answers",
+                ).arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ).arg(
+                    Arg::with_name("sample")
+                        .long("sample")
+                        .value_name("N")
+                        .help("Compare one in every N queries (default 100)")
+                        .takes_value(true),
+                ).arg(
+                    Arg::with_name("stop")
+                        .long("stop")
+                        .help("Stop comparing")
+                        .conflicts_with("sample"),
+                ),
+        ).subcommand(
+            SubCommand::with_name("stage-zone")
+                .about("Load a zone from a file without making it live")
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("FILE")
+                        .help("Zone file to load")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        ).subcommand(
+            SubCommand::with_name("upgrade")
+                .about("Replace the server with a new process started from its binary"),
+        ).subcommand(
+            SubCommand::with_name("watch").about("Print events, such as zones being loaded"),
+        ).subcommand(
+            SubCommand::with_name("zone-stats")
+                .about("Show query statistics for a zone")
+                .arg(
+                    Arg::with_name("origin")
+                        .value_name("ORIGIN")
+                        .help("Origin of the zone")
+                        .takes_value(true)
+                        .required(true),
+                ),
         ).get_matches();
     if matches.subcommand_name().is_none() {
         eprintln!("error: a subcommand is required\n");
@@ -62,41 +285,351 @@ fn main() -> Result<(), failure::Error> {
     })?;
 
     let request = match matches.subcommand() {
+        ("add-record", Some(matches)) => Request::AddRecords {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+            records: vec![ResourceRecord {
+                name: matches.value_of("name").expect("unreachable").to_owned(),
+                ttl: matches
+                    .value_of("ttl")
+                    .expect("unreachable")
+                    .parse::<u32>()
+                    .context("invalid TTL")?,
+                record_type: matches.value_of("type").expect("unreachable").to_owned(),
+                rdata: rdata(matches),
+            }],
+            write: matches.is_present("write"),
+        },
+        ("remove-record", Some(matches)) => Request::RemoveRecords {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+            records: vec![ResourceRecord {
+                name: matches.value_of("name").expect("unreachable").to_owned(),
+                ttl: 0,
+                record_type: matches.value_of("type").expect("unreachable").to_owned(),
+                rdata: rdata(matches),
+            }],
+            write: matches.is_present("write"),
+        },
+        ("dump-zone", Some(matches)) => Request::GetZone {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+            name: matches.value_of("name").map(String::from),
+            qtype: matches.value_of("type").map(String::from),
+            staged: matches.is_present("staged"),
+        },
+        ("hello", _) => Request::Hello {
+            capabilities: Vec::new(),
+        },
         ("list-zones", _) => Request::ListZones,
         ("load-zone", Some(matches)) => Request::LoadZone {
             path: fs::canonicalize(matches.value_of("path").expect("unreachable").to_owned())
                 .context("could not canonicalize path")?,
         },
+        ("query", Some(matches)) => Request::Query {
+            name: matches.value_of("name").expect("unreachable").to_owned(),
+            qtype: matches.value_of("type").unwrap_or("A").to_owned(),
+            staged: matches.is_present("staged"),
+            client: match matches.value_of("client") {
+                Some(client) => Some(
+                    client
+                        .parse::<IpAddr>()
+                        .context("could not parse client address")?,
+                ),
+                None => None,
+            },
+        },
+        ("stage-zone", Some(matches)) => Request::StageZone {
+            path: fs::canonicalize(matches.value_of("path").expect("unreachable").to_owned())
+                .context("could not canonicalize path")?,
+        },
+        ("compare-zone", Some(matches)) => Request::CompareZone {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+        },
+        ("activate-zone", Some(matches)) => Request::ActivateZone {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+        },
+        ("discard-zone", Some(matches)) => Request::DiscardZone {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+        },
+        ("shadow-zone", Some(matches)) => Request::ShadowZone {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+            sample_every: if matches.is_present("stop") {
+                0
+            } else {
+                matches
+                    .value_of("sample")
+                    .unwrap_or("100")
+                    .parse::<u32>()
+                    .context("invalid sample rate")?
+            },
+        },
+        ("shadow-report", Some(matches)) => Request::ShadowReport {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+        },
+        ("reload-tls", _) => Request::ReloadTls,
+        ("upgrade", _) => Request::Upgrade,
+        ("watch", _) => Request::Subscribe,
+        ("reopen-query-log", _) => Request::ReopenQueryLog,
+        ("zone-stats", Some(matches)) => Request::ZoneStats {
+            origin: matches.value_of("origin").expect("unreachable").to_owned(),
+        },
         _ => unreachable!(),
     };
 
+    // The progress of an upgrade is followed on a second connection, subscribed before it starts
+    let mut events = match request {
+        Request::Upgrade => Some(subscribe(ctl_socket_path)?),
+        _ => None,
+    };
+
+    let request = RequestEnvelope {
+        version: PROTOCOL_VERSION,
+        id: 1,
+        request,
+    };
     serde_json::to_writer(&ctl_socket, &request).context("Unable to write request to socket")?;
     ctl_socket
         .shutdown(Shutdown::Write)
         .context("Unable to shutdown write half of socket")?;
 
-    macro_rules! response {
-        () => {
-            serde_json::from_reader(ctl_socket).context("Unable to read response from socket")
-        };
-    }
-
-    match request {
-        Request::ListZones => {
-            let response: HashMap<String, u32> = response!()?;
+    let mut responses = Deserializer::from_reader(ctl_socket).into_iter::<ResponseEnvelope>();
+    let response = read_response(&mut responses, request.id)?;
+    match response.ok_or_else(|| format_err!("Connection closed without a response"))? {
+        Response::Hello(hello) => {
+            let mut tw = TabWriter::new(io::stdout());
+            writeln!(tw, "server\t{}", hello.server)?;
+            writeln!(
+                tw,
+                "versions\t{}",
+                hello
+                    .versions
+                    .iter()
+                    .map(|version| version.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            )?;
+            writeln!(tw, "access\t{:?}", hello.access)?;
+            writeln!(tw, "capabilities\t{}", hello.capabilities.join(" "))?;
+            tw.flush()?;
+        }
+        Response::Zones(zones) => {
             let mut tw = TabWriter::new(io::stdout());
-            for (zone, serial) in response {
+            for (zone, serial) in zones {
                 writeln!(tw, "{}\t{}", zone, serial)?;
             }
             tw.flush()?;
         }
-        Request::LoadZone { .. } => {
-            let response: Result<(String, u32), String> = response!()?;
-            if let Err(err) = response {
-                bail!(err);
+        Response::ZoneLoaded { .. } | Response::Done => {}
+        Response::Serial(serial) => println!("serial {}", serial),
+        Response::Zone(contents) => {
+            let mut tw = TabWriter::new(io::stdout());
+            writeln!(tw, "$ORIGIN {}", contents.origin)?;
+            writeln!(tw, "; serial {}", contents.serial)?;
+            for record in &contents.records {
+                print_record(&mut tw, record)?;
+            }
+            tw.flush()?;
+        }
+        Response::Query(response) => print_response(&response)?,
+        Response::ShadowReport(report) => {
+            let mut tw = TabWriter::new(io::stdout());
+            writeln!(tw, "sample every\t{}", report.sample_every)?;
+            writeln!(tw, "queries\t{}", report.queries)?;
+            writeln!(tw, "compared\t{}", report.compared)?;
+            writeln!(tw, "mismatched\t{}", report.mismatched)?;
+            for mismatch in &report.mismatches {
+                writeln!(
+                    tw,
+                    "\n;{}\t\tIN\t{}",
+                    mismatch.question.name, mismatch.question.record_type
+                )?;
+                writeln!(tw, "--- live: {}", mismatch.live.rcode)?;
+                for record in &mismatch.live.records {
+                    write!(tw, "-")?;
+                    print_record(&mut tw, record)?;
+                }
+                writeln!(tw, "+++ staged: {}", mismatch.candidate.rcode)?;
+                for record in &mismatch.candidate.records {
+                    write!(tw, "+")?;
+                    print_record(&mut tw, record)?;
+                }
+            }
+            tw.flush()?;
+        }
+        Response::ZoneDiff(diff) => {
+            match diff.live_serial {
+                Some(serial) => println!("--- {} serial {} (live)", diff.origin, serial),
+                None => println!("--- {} (not loaded)", diff.origin),
+            }
+            println!("+++ {} serial {} (staged)", diff.origin, diff.staged_serial);
+            let mut tw = TabWriter::new(io::stdout());
+            for record in &diff.removed {
+                write!(tw, "-")?;
+                print_record(&mut tw, record)?;
+            }
+            for record in &diff.added {
+                write!(tw, "+")?;
+                print_record(&mut tw, record)?;
+            }
+            tw.flush()?;
+        }
+        Response::Subscribed => {
+            // Events are printed as they arrive, one JSON object per line
+            loop {
+                match read_response(&mut responses, request.id)? {
+                    Some(Response::Event(event)) => {
+                        println!("{}", serde_json::to_string(&event)?);
+                        io::stdout().flush()?;
+                    }
+                    Some(response) => bail!("unexpected response: {:?}", response),
+                    None => break,
+                }
+            }
+        }
+        Response::Event(event) => bail!("unexpected event: {:?}", event),
+        Response::ZoneStats(stats) => {
+            let mut tw = TabWriter::new(io::stdout());
+            writeln!(tw, "queries\t{}", stats.queries)?;
+            writeln!(tw, "nxdomain\t{}", stats.nxdomain)?;
+            writeln!(tw, "nodata\t{}", stats.nodata)?;
+            writeln!(tw, "\nTYPE\tQUERIES")?;
+            for (record_type, count) in stats.record_types {
+                writeln!(tw, "{}\t{}", record_type, count)?;
+            }
+            writeln!(tw, "\nNAME\tQUERIES (APPROX.)")?;
+            for (name, count) in stats.top_names {
+                writeln!(tw, "{}\t{}", name, count)?;
+            }
+            tw.flush()?;
+        }
+    }
+
+    if let Some(ref mut events) = events {
+        loop {
+            match read_response(events, 1)? {
+                Some(Response::Event(Event::UpgradeStarted { pid })) => {
+                    println!("started process {}", pid);
+                }
+                Some(Response::Event(Event::Upgraded { pid })) => {
+                    println!("process {} has taken over", pid);
+                    break;
+                }
+                Some(Response::Event(Event::UpgradeFailed { error })) => {
+                    bail!("upgrade failed: {}", error)
+                }
+                Some(_) => {}
+                None => bail!("Connection closed before the upgrade finished"),
             }
         }
     }
 
     Ok(())
 }
+
+/// Opens a second connection to the control socket and subscribes to events on it.
+fn subscribe(
+    ctl_socket_path: &str,
+) -> Result<StreamDeserializer<'static, IoRead<UnixStream>, ResponseEnvelope>, failure::Error> {
+    let ctl_socket = UnixStream::connect(ctl_socket_path).with_context(|e| {
+        format!(
+            "Failed to connect to Unix socket at {}: {}",
+            ctl_socket_path, e
+        )
+    })?;
+    let request = RequestEnvelope {
+        version: PROTOCOL_VERSION,
+        id: 1,
+        request: Request::Subscribe,
+    };
+    serde_json::to_writer(&ctl_socket, &request).context("Unable to write request to socket")?;
+    ctl_socket
+        .shutdown(Shutdown::Write)
+        .context("Unable to shutdown write half of socket")?;
+    let mut responses = Deserializer::from_reader(ctl_socket).into_iter::<ResponseEnvelope>();
+    match read_response(&mut responses, request.id)? {
+        Some(Response::Subscribed) => Ok(responses),
+        response => bail!("unexpected response: {:?}", response),
+    }
+}
+
+/// Reads the next response to the request with ID `id`, returning `Ok(None)` if the server
+/// closed the connection.
+fn read_response<R: Read>(
+    responses: &mut StreamDeserializer<IoRead<R>, ResponseEnvelope>,
+    id: u64,
+) -> Result<Option<Response>, failure::Error> {
+    let response = match responses.next() {
+        Some(response) => response.context("Unable to read response from socket")?,
+        None => return Ok(None),
+    };
+    // A response without an ID is an error for a request the server could not read
+    match response.id {
+        Some(response_id) if response_id != id || response.version != PROTOCOL_VERSION => bail!(
+            "unexpected response: version {}, request ID {}",
+            response.version,
+            response_id
+        ),
+        _ => {}
+    }
+    Ok(Some(response.into_result().map_err(|err| format_err!("{}", err.message))?))
+}
+
+/// Joins the record data arguments, so that it does not need to be quoted as one argument.
+fn rdata(matches: &ArgMatches) -> String {
+    matches
+        .values_of("rdata")
+        .expect("unreachable")
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints a query response in the style of dig.
+fn print_response(response: &QueryResponse) -> Result<(), io::Error> {
+    let mut tw = TabWriter::new(io::stdout());
+    writeln!(
+        tw,
+        ";; ->>HEADER<<- opcode: QUERY, status: {}, id: {}",
+        response.rcode, response.id
+    )?;
+    writeln!(
+        tw,
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        response.flags.join(" "),
+        response.question.len(),
+        response.answer.len(),
+        response.authority.len(),
+        response.additional.len()
+    )?;
+    writeln!(tw, "\n;; QUESTION SECTION:")?;
+    for question in &response.question {
+        writeln!(tw, ";{}\t\tIN\t{}", question.name, question.record_type)?;
+    }
+    for (title, records) in &[
+        ("ANSWER", &response.answer),
+        ("AUTHORITY", &response.authority),
+        ("ADDITIONAL", &response.additional),
+    ] {
+        if records.is_empty() {
+            continue;
+        }
+        writeln!(tw, "\n;; {} SECTION:", title)?;
+        for record in records.iter() {
+            print_record(&mut tw, record)?;
+        }
+    }
+    writeln!(tw, "\n;; MSG SIZE  rcvd: {}", response.size)?;
+    if let Some(rate_limited) = response.rate_limited {
+        writeln!(
+            tw,
+            ";; RATE LIMITED: {}",
+            if rate_limited { "yes" } else { "no" }
+        )?;
+    }
+    tw.flush()
+}
+
+fn print_record(w: &mut impl Write, record: &ResourceRecord) -> Result<(), io::Error> {
+    writeln!(
+        w,
+        "{}\t{}\tIN\t{}\t{}",
+        record.name, record.ttl, record.record_type, record.rdata
+    )
+}
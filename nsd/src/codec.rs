@@ -23,6 +23,14 @@ impl DnsCodec {
     pub fn udp() -> DnsCodec {
         DnsCodec::Udp
     }
+
+    /// Returns true if a TCP message's length marker has been read but the message has not.
+    pub fn in_message(&self) -> bool {
+        match self {
+            DnsCodec::Tcp { len } => len.is_some(),
+            DnsCodec::Udp => false,
+        }
+    }
 }
 
 impl Decoder for DnsCodec {
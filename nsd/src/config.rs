@@ -0,0 +1,832 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The pepbut-nsd configuration file.
+//!
+//! The configuration file is TOML. Every key is optional; a missing key takes the same default
+//! the command line would.
+//!
+//! ```toml
+//! # Listeners serve both UDP and TCP unless `transports` is given. `listen` may also be an array
+//! # of address strings, such as `listen = ["192.0.2.53:53", "[2001:db8::53]:53"]`.
+//! [[listen]]
+//! address = "192.0.2.53:53"
+//!
+//! [[listen]]
+//! address = "127.0.0.1:53"
+//! transports = ["tcp"]
+//!
+//! # DNS over TLS and DNS over HTTPS; these require the [tls] section.
+//! [[listen]]
+//! address = "192.0.2.53:853"
+//! transports = ["tls"]
+//!
+//! [[listen]]
+//! address = "192.0.2.53:443"
+//! transports = ["https"]
+//!
+//! [udp]
+//! sockets = 4
+//!
+//! [tcp]
+//! max-connections = 1024
+//! max-connections-per-ip = 32
+//! idle-timeout = 10
+//! # Seconds open connections have to finish on shutdown, or once a new process has taken over.
+//! drain-timeout = 30
+//!
+//! # PEM files, reloaded on SIGHUP.
+//! [tls]
+//! certificate = "/etc/pepbut/tls/fullchain.pem"
+//! key = "/etc/pepbut/tls/key.pem"
+//!
+//! # Connections from these networks must begin with a PROXY protocol header, which gives the
+//! # address of the client the connection is proxied for.
+//! [proxy-protocol]
+//! trusted = ["198.51.100.0/28", "2001:db8:1::10"]
+//!
+//! # Callers are identified by their user and primary group IDs. nsd's own user and root may use
+//! # every method; other callers must be listed.
+//! [control]
+//! path = "/run/pepbut/nsd.sock"
+//! mode = "0660"
+//! group = "pepbut"
+//! admin-uids = [1000]
+//! read-only-gids = [110]
+//!
+//! # nsd refuses to run as root unless it is told who to become once its sockets are bound. It can
+//! # also chroot to a directory first, after which paths given through the control socket, the
+//! # TLS certificate and key when reloaded, the query log when reopened, and the dnstap socket when
+//! # reconnecting are found inside that directory. Zone files loaded on startup must also be inside
+//! # it for records added or removed through the control socket to be written back to them.
+//! [privileges]
+//! user = "pepbut"
+//! group = "pepbut"
+//! chroot = "/var/lib/pepbut"
+//!
+//! # Once running, nsd is only allowed the system calls it needs. Any other kills the process, or
+//! # with action = "log", is logged by the kernel and allowed, to find out what would be killed.
+//! [seccomp]
+//! action = "kill"
+//!
+//! [zones]
+//! files = ["/var/lib/pepbut/example.invalid.zone"]
+//! directories = ["/var/lib/pepbut/zones"]
+//!
+//! [soa]
+//! mname = "ns1.wob.zone"
+//! rname = "hostmistress.as64241.net"
+//! minimum = 300
+//!
+//! [rate-limit]
+//! responses-per-second = 20
+//! burst = 40
+//!
+//! [log]
+//! level = "info"
+//!
+//! # Newline-delimited JSON, one line for each logged query. A path of "-" logs to standard output.
+//! # The file is reopened on SIGUSR1, for log rotation.
+//! [query-log]
+//! path = "/var/log/pepbut/queries.log"
+//! sample = 10
+//! queue-size = 4096
+//!
+//! # dnstap output of every query and response, to a Frame Streams socket or file.
+//! [dnstap]
+//! socket = "/run/dnstap/dnstap.sock"
+//! queue-size = 4096
+//!
+//! # Prometheus metrics, served over plain HTTP at /metrics.
+//! [metrics]
+//! address = "127.0.0.1:9153"
+//!
+//! [identity]
+//! name = "ns1.example.invalid"
+//! ```
+
+use failure::{self, ResultExt};
+use libc;
+use log::LevelFilter;
+use num_cpus;
+use pepbut::authority::Authority;
+use pepbut::name::Name;
+use pepbut::zone::SOAParams;
+use serde::de::{self, Deserialize, Deserializer};
+use std::collections::HashSet;
+use std::ffi::{CStr, OsStr};
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use toml;
+
+use ctl::Access;
+use proxy::Cidr;
+use tls;
+
+pub static DEFAULT_LISTEN_ADDR: &str = "[::]:53";
+pub static DEFAULT_SOCKET_PATH: &str = "/run/pepbut/nsd.sock";
+
+/// The longest idle timeout that can be advertised with `edns-tcp-keepalive`, in seconds.
+const MAX_IDLE_TIMEOUT: u64 = 6553;
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Addresses to listen on for DNS queries.
+    pub listen: Vec<ListenConfig>,
+    pub udp: UdpConfig,
+    pub tcp: TcpConfig,
+    pub tls: Option<TlsConfig>,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub control: ControlConfig,
+    pub privileges: PrivilegesConfig,
+    pub seccomp: Option<SeccompConfig>,
+    pub zones: ZonesConfig,
+    pub soa: SoaConfig,
+    pub rate_limit: RateLimitConfig,
+    pub log: LogConfig,
+    pub query_log: Option<QueryLogConfig>,
+    pub dnstap: Option<DnstapConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub identity: IdentityConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: vec![ListenConfig::new(
+                DEFAULT_LISTEN_ADDR.parse().expect("cannot fail"),
+            )],
+            udp: UdpConfig::default(),
+            tcp: TcpConfig::default(),
+            tls: None,
+            proxy_protocol: ProxyProtocolConfig::default(),
+            control: ControlConfig::default(),
+            privileges: PrivilegesConfig::default(),
+            seccomp: None,
+            zones: ZonesConfig::default(),
+            soa: SoaConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            log: LogConfig::default(),
+            query_log: None,
+            dnstap: None,
+            metrics: None,
+            identity: IdentityConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads a configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, failure::Error> {
+        let path = path.as_ref();
+        let s = fs::read_to_string(path)
+            .with_context(|e| format!("failed to read config {}: {}", path.display(), e))?;
+        Ok(toml::from_str(&s)
+            .with_context(|e| format!("failed to parse config {}: {}", path.display(), e))?)
+    }
+
+    /// Checks the parts of the configuration that cannot be checked while deserializing, without
+    /// binding any sockets or loading any zones.
+    pub fn validate(&self) -> Result<(), failure::Error> {
+        if self.listen.is_empty() {
+            bail!("at least one listen address is required");
+        }
+        let mut seen = HashSet::new();
+        for listener in &self.listen {
+            if listener.transports.is_empty() {
+                bail!("listener {} has no transports", listener.address);
+            }
+            for transport in &listener.transports {
+                if !seen.insert((listener.address, *transport)) {
+                    bail!("{} is configured more than once for {}", listener.address, transport);
+                }
+            }
+        }
+        if self.udp.sockets == Some(0) {
+            bail!("udp.sockets must be at least 1");
+        }
+        self.tcp.validate()?;
+        match self.tls {
+            Some(ref tls) => {
+                tls::server_config(tls)?;
+            }
+            None => {
+                if let Some(listener) = self.listen.iter().find(|listener| {
+                    listener.transports.contains(&Transport::Tls)
+                        || listener.transports.contains(&Transport::Https)
+                }) {
+                    bail!("listener {} uses TLS, but [tls] is not configured", listener.address);
+                }
+            }
+        }
+        if self.privileges.user.is_none()
+            && (self.privileges.group.is_some() || self.privileges.chroot.is_some())
+        {
+            bail!("privileges.user is required to set privileges.group or privileges.chroot");
+        }
+        self.soa.params()?;
+        self.zones.paths()?;
+        if let Some(ref query_log) = self.query_log {
+            if query_log.sample == 0 {
+                bail!("query-log.sample must be at least 1");
+            }
+            if query_log.queue_size == 0 {
+                bail!("query-log.queue-size must be at least 1");
+            }
+        }
+        if let Some(ref dnstap) = self.dnstap {
+            if dnstap.socket.is_some() == dnstap.file.is_some() {
+                bail!("exactly one of dnstap.socket and dnstap.file must be set");
+            }
+            if dnstap.queue_size == 0 {
+                bail!("dnstap.queue-size must be at least 1");
+            }
+        }
+        if self.rate_limit.responses_per_second > 0 && self.rate_limit.burst == 0 {
+            bail!("rate-limit.burst must be at least 1 when rate limiting is enabled");
+        }
+        Ok(())
+    }
+
+    /// Creates an authority and loads all configured zones into it.
+    pub fn load_authority(&self) -> Result<Authority, failure::Error> {
+        let mut authority = Authority::with_soa(self.soa.params()?);
+        for path in self.zones.paths()? {
+            authority
+                .load_zonefile(&path)
+                .with_context(|e| format!("failed to load zone {}: {}", path.display(), e))?;
+        }
+        Ok(authority)
+    }
+}
+
+/// A transport DNS queries can arrive over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over TLS ([RFC 7858](https://tools.ietf.org/html/rfc7858)).
+    Tls,
+    /// DNS over HTTPS ([RFC 8484](https://tools.ietf.org/html/rfc8484)).
+    Https,
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+        })
+    }
+}
+
+/// An address to listen on and the transports to serve there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenConfig {
+    pub address: SocketAddr,
+    pub transports: Vec<Transport>,
+}
+
+impl ListenConfig {
+    /// Creates a listener serving both UDP and TCP.
+    pub fn new(address: SocketAddr) -> ListenConfig {
+        ListenConfig {
+            address,
+            transports: vec![Transport::Udp, Transport::Tcp],
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ListenConfig, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Table {
+            address: SocketAddr,
+            #[serde(default = "all_transports")]
+            transports: Vec<Transport>,
+        }
+
+        fn all_transports() -> Vec<Transport> {
+            vec![Transport::Udp, Transport::Tcp]
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Address(SocketAddr),
+            Table(Table),
+        }
+
+        match Repr::deserialize(deserializer) {
+            Ok(Repr::Address(address)) => Ok(ListenConfig::new(address)),
+            Ok(Repr::Table(Table {
+                address,
+                transports,
+            })) => Ok(ListenConfig {
+                address,
+                transports,
+            }),
+            Err(_) => Err(de::Error::custom(
+                "expected \"ipaddr:port\" or a table with an address and optional transports",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UdpConfig {
+    /// Number of `SO_REUSEPORT` sockets to open for each UDP listen address, each served by its
+    /// own task. Defaults to the number of CPUs.
+    pub sockets: Option<usize>,
+}
+
+impl UdpConfig {
+    pub fn sockets(&self) -> usize {
+        self.sockets.unwrap_or_else(num_cpus::get)
+    }
+}
+
+/// Limits on TCP connections, following the recommendations of
+/// [RFC 7766 § 6.2](https://tools.ietf.org/html/rfc7766#section-6.2).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TcpConfig {
+    /// Maximum number of open TCP connections across all listeners.
+    pub max_connections: usize,
+    /// Maximum number of open TCP connections from a single client address.
+    pub max_connections_per_ip: usize,
+    /// Seconds a connection may wait between queries before it is closed.
+    pub idle_timeout: u64,
+    /// Seconds a client has to finish sending a query once it has started.
+    pub read_timeout: u64,
+    /// Maximum number of responses to a connection that may be waiting to be written. Once
+    /// reached, no more queries are read from the connection until the client reads responses.
+    pub max_pipelined: usize,
+    /// Seconds open connections have to finish when the server stops, after which they are
+    /// closed. Connections waiting for a query are closed straight away.
+    pub drain_timeout: u64,
+}
+
+impl Default for TcpConfig {
+    fn default() -> TcpConfig {
+        TcpConfig {
+            max_connections: 1024,
+            max_connections_per_ip: 32,
+            idle_timeout: 10,
+            read_timeout: 5,
+            max_pipelined: 64,
+            drain_timeout: 30,
+        }
+    }
+}
+
+impl TcpConfig {
+    fn validate(&self) -> Result<(), failure::Error> {
+        for &(key, value) in &[
+            ("max-connections", self.max_connections as u64),
+            ("max-connections-per-ip", self.max_connections_per_ip as u64),
+            ("idle-timeout", self.idle_timeout),
+            ("read-timeout", self.read_timeout),
+            ("max-pipelined", self.max_pipelined as u64),
+        ] {
+            if value == 0 {
+                bail!("tcp.{} must be at least 1", key);
+            }
+        }
+        // The idle timeout is advertised with edns-tcp-keepalive in a 16-bit count of 100 ms units
+        if self.idle_timeout > MAX_IDLE_TIMEOUT {
+            bail!("tcp.idle-timeout must be at most {}", MAX_IDLE_TIMEOUT);
+        }
+        Ok(())
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout)
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+}
+
+/// The certificate and key for DNS over TLS.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain, starting with the server's certificate.
+    pub certificate: PathBuf,
+    /// PEM file containing the private key, in PKCS #8 or PKCS #1 format.
+    pub key: PathBuf,
+}
+
+/// Proxies that pass on client addresses with the PROXY protocol, for the TCP, TLS, and HTTPS
+/// transports.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProxyProtocolConfig {
+    /// Networks whose connections must begin with a PROXY header. Connections from anywhere else
+    /// are never expected to.
+    pub trusted: Vec<Cidr>,
+}
+
+impl ProxyProtocolConfig {
+    /// Returns true if connections from this address must begin with a PROXY header.
+    pub fn is_trusted(&self, addr: &IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(addr))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ControlConfig {
+    /// Path of the Unix control socket.
+    pub path: PathBuf,
+    /// Permissions of the control socket, as an octal string. If unset, the process umask
+    /// applies.
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub mode: Option<u32>,
+    /// Name of the group to give ownership of the control socket.
+    pub group: Option<String>,
+    /// Users and groups allowed to use every control method.
+    pub admin_uids: Vec<u32>,
+    pub admin_gids: Vec<u32>,
+    /// Users and groups allowed to use only the methods that change nothing.
+    pub read_only_uids: Vec<u32>,
+    pub read_only_gids: Vec<u32>,
+}
+
+impl ControlConfig {
+    /// Returns what a caller with the given user and primary group IDs may do. nsd's own user and
+    /// root may always use every method.
+    pub fn access(&self, uid: u32, gid: u32) -> Access {
+        if uid == 0
+            || uid == unsafe { libc::geteuid() }
+            || self.admin_uids.contains(&uid)
+            || self.admin_gids.contains(&gid)
+        {
+            Access::Admin
+        } else if self.read_only_uids.contains(&uid) || self.read_only_gids.contains(&gid) {
+            Access::ReadOnly
+        } else {
+            Access::Denied
+        }
+    }
+}
+
+impl Default for ControlConfig {
+    fn default() -> ControlConfig {
+        ControlConfig {
+            path: PathBuf::from(DEFAULT_SOCKET_PATH),
+            mode: None,
+            group: None,
+            admin_uids: Vec::new(),
+            admin_gids: Vec::new(),
+            read_only_uids: Vec::new(),
+            read_only_gids: Vec::new(),
+        }
+    }
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    match u32::from_str_radix(&s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(Some(mode)),
+        _ => Err(de::Error::custom(format!("invalid octal file mode: {}", s))),
+    }
+}
+
+/// Who to run as after starting as root.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PrivilegesConfig {
+    /// Name of the user to switch to once every socket is bound.
+    pub user: Option<String>,
+    /// Name of the group to switch to. Defaults to the user's primary group.
+    pub group: Option<String>,
+    /// Directory to change the root directory to before switching user.
+    pub chroot: Option<PathBuf>,
+}
+
+/// The seccomp filter installed once nsd is running.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SeccompConfig {
+    /// What happens when a system call that is not allowed is made.
+    pub action: SeccompAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SeccompAction {
+    /// Kill the whole process.
+    Kill,
+    /// Allow the system call, but have the kernel log it.
+    Log,
+}
+
+impl Default for SeccompConfig {
+    fn default() -> SeccompConfig {
+        SeccompConfig {
+            action: SeccompAction::Kill,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ZonesConfig {
+    /// Zone files to load on start.
+    pub files: Vec<PathBuf>,
+    /// Directories to load zone files from on start. Every file ending in `.zone` is loaded.
+    pub directories: Vec<PathBuf>,
+}
+
+impl ZonesConfig {
+    /// Returns the zone files to load, with the contents of each directory sorted by name.
+    pub fn paths(&self) -> Result<Vec<PathBuf>, failure::Error> {
+        let mut paths = self.files.clone();
+        for dir in &self.directories {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(dir)
+                .with_context(|e| format!("failed to read zone directory {}: {}", dir.display(), e))?
+            {
+                let path = entry?.path();
+                if path.extension() == Some(OsStr::new("zone")) {
+                    entries.push(path);
+                }
+            }
+            entries.sort();
+            paths.extend(entries);
+        }
+        Ok(paths)
+    }
+}
+
+/// Values for the SOA record of every zone. See [`SOAParams`].
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SoaConfig {
+    pub mname: String,
+    pub rname: String,
+    pub ttl: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    pub minimum: u32,
+}
+
+impl Default for SoaConfig {
+    fn default() -> SoaConfig {
+        let params = SOAParams::default();
+        SoaConfig {
+            mname: params.mname.to_string(),
+            rname: params.rname.to_string(),
+            ttl: params.ttl,
+            refresh: params.refresh,
+            retry: params.retry,
+            expire: params.expire,
+            minimum: params.minimum,
+        }
+    }
+}
+
+impl SoaConfig {
+    pub fn params(&self) -> Result<SOAParams, failure::Error> {
+        Ok(SOAParams {
+            mname: Name::from_str(&self.mname)
+                .with_context(|e| format!("invalid soa.mname {:?}: {}", self.mname, e))?,
+            rname: Name::from_str(&self.rname)
+                .with_context(|e| format!("invalid soa.rname {:?}: {}", self.rname, e))?,
+            ttl: self.ttl,
+            refresh: self.refresh,
+            retry: self.retry,
+            expire: self.expire,
+            minimum: self.minimum,
+        })
+    }
+}
+
+/// Per-client response rate limiting for UDP. TCP is never rate limited, as the client address
+/// cannot be spoofed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RateLimitConfig {
+    /// Responses per second allowed for each client address. 0 disables rate limiting.
+    pub responses_per_second: u32,
+    /// Number of responses a client may receive in a burst above the steady rate.
+    pub burst: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    /// Log level for pepbut's own messages. Overridden by `-v`.
+    #[serde(deserialize_with = "deserialize_level")]
+    pub level: LevelFilter,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct QueryLogConfig {
+    /// File to append the query log to, or `-` for standard output.
+    pub path: PathBuf,
+    /// Log one query in every `sample`.
+    pub sample: u32,
+    /// How many sampled queries can wait to be written before more are dropped.
+    pub queue_size: usize,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> QueryLogConfig {
+        QueryLogConfig {
+            path: PathBuf::from("-"),
+            sample: 1,
+            queue_size: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DnstapConfig {
+    /// Unix socket of a Frame Streams reader to connect to. Exactly one of `socket` and `file`
+    /// must be set.
+    pub socket: Option<PathBuf>,
+    /// File to write dnstap frames to. It is replaced on startup.
+    pub file: Option<PathBuf>,
+    /// How many queries can wait to be written before more are dropped.
+    pub queue_size: usize,
+}
+
+impl Default for DnstapConfig {
+    fn default() -> DnstapConfig {
+        DnstapConfig {
+            socket: None,
+            file: None,
+            queue_size: 4096,
+        }
+    }
+}
+
+/// The HTTP server for Prometheus metrics.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    /// Address to serve metrics on. The server has no access control, so this should not be
+    /// reachable from untrusted networks.
+    pub address: SocketAddr,
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LevelFilter, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    LevelFilter::from_str(&s).map_err(|_| de::Error::custom(format!("invalid log level: {}", s)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct IdentityConfig {
+    /// Name this server identifies itself as. Defaults to the system hostname.
+    pub name: Option<String>,
+    /// Version this server reports. Defaults to the pepbut-nsd version.
+    pub version: Option<String>,
+}
+
+impl IdentityConfig {
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(hostname)
+    }
+
+    pub fn version(&self) -> String {
+        self.version
+            .clone()
+            .unwrap_or_else(|| format!("pepbut-nsd {}", env!("CARGO_PKG_VERSION")))
+    }
+}
+
+fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } == 0 {
+        unsafe { CStr::from_ptr(buf.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        String::from("localhost")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use log::LevelFilter;
+    use std::path::PathBuf;
+    use toml;
+
+    use super::{Config, ListenConfig, SeccompAction, Transport};
+    use ctl::Access;
+
+    #[test]
+    fn parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[listen]]
+            address = "127.0.0.1:5353"
+
+            [[listen]]
+            address = "[::1]:5353"
+            transports = ["tcp"]
+
+            [control]
+            mode = "0660"
+            admin-uids = [1000]
+            read-only-gids = [100]
+
+            [privileges]
+            user = "pepbut"
+
+            [tcp]
+            drain-timeout = 5
+
+            [seccomp]
+
+            [soa]
+            minimum = 300
+
+            [log]
+            level = "debug"
+            "#,
+        ).unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenConfig::new("127.0.0.1:5353".parse().unwrap()),
+                ListenConfig {
+                    address: "[::1]:5353".parse().unwrap(),
+                    transports: vec![Transport::Tcp],
+                },
+            ]
+        );
+        assert_eq!(config.control.path, PathBuf::from("/run/pepbut/nsd.sock"));
+        assert_eq!(config.control.mode, Some(0o660));
+        assert_eq!(config.control.access(0, 0), Access::Admin);
+        assert_eq!(config.control.access(1000, 100), Access::Admin);
+        assert_eq!(config.control.access(1001, 100), Access::ReadOnly);
+        assert_eq!(config.control.access(1002, 1002), Access::Denied);
+        assert_eq!(config.privileges.user, Some(String::from("pepbut")));
+        assert_eq!(config.tcp.drain_timeout().as_secs(), 5);
+        assert_eq!(config.tcp.idle_timeout, 10);
+        assert_eq!(
+            config.seccomp.as_ref().map(|seccomp| seccomp.action),
+            Some(SeccompAction::Kill)
+        );
+        assert_eq!(config.soa.params().unwrap().minimum, 300);
+        assert_eq!(config.soa.params().unwrap().ttl, 3600);
+        assert_eq!(config.log.level, LevelFilter::Debug);
+        assert!(config.proxy_protocol.trusted.is_empty());
+        config.validate().unwrap();
+
+        let config: Config =
+            toml::from_str("[proxy-protocol]\ntrusted = [\"192.0.2.0/24\", \"2001:db8::1\"]")
+                .unwrap();
+        assert!(config.proxy_protocol.is_trusted(&"192.0.2.1".parse().unwrap()));
+        assert!(!config.proxy_protocol.is_trusted(&"2001:db8::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn reject_bad_config() {
+        assert!(toml::from_str::<Config>("lisen = [\"[::]:53\"]").is_err());
+        assert!(toml::from_str::<Config>("listen = [\"localhost\"]").is_err());
+        assert!(toml::from_str::<Config>("[control]\nmode = \"0999\"").is_err());
+        assert!(toml::from_str::<Config>("[log]\nlevel = \"loud\"").is_err());
+        assert!(toml::from_str::<Config>("[proxy-protocol]\ntrusted = [\"192.0.2/24\"]").is_err());
+        assert!(toml::from_str::<Config>("[seccomp]\naction = \"trap\"").is_err());
+        let config: Config = toml::from_str("[soa]\nmname = \"bad..name\"").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("listen = [\"[::]:53\", \"[::]:53\"]").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config =
+            toml::from_str("[[listen]]\naddress = \"[::]:853\"\ntransports = [\"tls\"]").unwrap();
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("[privileges]\nchroot = \"/var/empty\"").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
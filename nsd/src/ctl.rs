@@ -1,30 +1,723 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 
-use erased_serde;
+//! The control socket protocol.
+//!
+//! Clients send newline-delimited or concatenated JSON request envelopes, and get one response
+//! envelope for each request, in order:
+//!
+//! ```json
+//! {"version": 1, "id": 7, "method": "zone-stats", "origin": "example.invalid"}
+//! {"version": 1, "id": 7, "result": {"zone-stats": {"queries": 12, ...}}}
+//! {"version": 1, "id": 8, "error": {"code": "not-found", "message": "..."}}
+//! ```
+//!
+//! A `subscribe` request is answered with `subscribed`, then with an `event` response carrying the
+//! same ID for each event, until the server shuts down. Requests sent after it on the same
+//! connection are not answered.
+
+use bytes::{BufMut, Bytes, BytesMut};
 use failure;
 use pepbut::authority::Authority;
-use serde::Serialize;
+use pepbut::name::Name;
+use pepbut::record::{self, RData, Record, RecordTrait};
+use pepbut::shadow;
+use pepbut::wire::{rcode_name, DecodedRecord, DecodedResponse, ProtocolDecode, ProtocolEncode};
+use pepbut::zone::Zone;
+use serde_json::{self, Value};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::io::{self, Cursor};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
+use tokio::prelude::{stream, Stream};
+
+use events::Event;
+use server::Context;
+
+/// The version of the control protocol this server speaks.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The methods this server supports, returned by `Hello`.
+static METHODS: &[&str] = &[
+    "hello",
+    "list-zones",
+    "load-zone",
+    "reload-tls",
+    "zone-stats",
+    "reopen-query-log",
+    "query",
+    "get-zone",
+    "add-records",
+    "remove-records",
+    "subscribe",
+    "stage-zone",
+    "compare-zone",
+    "activate-zone",
+    "discard-zone",
+    "shadow-zone",
+    "shadow-report",
+    "upgrade",
+];
+
+/// A request, with the protocol version and an ID chosen by the client.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RequestEnvelope {
+    pub version: u32,
+    pub id: u64,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+/// The response to a request.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResponseEnvelope {
+    pub version: u32,
+    /// The ID of the request this is the response to, or `None` if the request could not be read.
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl ResponseEnvelope {
+    pub fn into_result(self) -> Result<Response, Error> {
+        match self.outcome {
+            Outcome::Result(response) => Ok(response),
+            Outcome::Error(err) => Err(err),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Result(Response),
+    Error(Error),
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "method")]
 #[serde(rename_all = "kebab-case")]
 pub enum Request {
+    /// Returns the protocol versions and methods the server supports, and what the caller may do.
+    /// `capabilities` are the client's own, and are currently only logged.
+    Hello {
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     ListZones,
     LoadZone { path: PathBuf },
+    ReloadTls,
+    ZoneStats { origin: String },
+    ReopenQueryLog,
+    /// Answers a query without going over the network. If `staged` is set, staged zones answer in
+    /// place of the loaded zones with the same origin. The query is not counted in zone statistics
+    /// or compared with a staged zone.
+    ///
+    /// `qtype` is a mnemonic such as `AAAA`, or a type number.
+    ///
+    /// `client` is the address the query is treated as coming from. Answers never depend on it,
+    /// as pepbut has no views or access lists; it is only used to report whether the rate limiter
+    /// would drop a UDP response to that client, without taking a token from it.
+    Query {
+        name: String,
+        qtype: String,
+        #[serde(default)]
+        staged: bool,
+        #[serde(default)]
+        client: Option<IpAddr>,
+    },
+    /// Returns the records in a loaded zone, or the staged zone if `staged` is set, including its
+    /// SOA record. If `name` is set, only records at or below that name are returned; if `qtype`
+    /// is set, only records of that type.
+    GetZone {
+        origin: String,
+        name: Option<String>,
+        qtype: Option<String>,
+        #[serde(default)]
+        staged: bool,
+    },
+    /// Adds records to a loaded zone and increments its serial, returning the new serial. Either
+    /// all of the records are added or, on error, none are. If `write` is set, the zone is also
+    /// written to the file it was loaded from.
+    AddRecords {
+        origin: String,
+        records: Vec<ResourceRecord>,
+        #[serde(default)]
+        write: bool,
+    },
+    /// Removes records from a loaded zone, like `AddRecords`. Records are matched by name, type,
+    /// and data; their TTL is ignored.
+    RemoveRecords {
+        origin: String,
+        records: Vec<ResourceRecord>,
+        #[serde(default)]
+        write: bool,
+    },
+    /// Streams events, such as zones being loaded. See the module documentation.
+    Subscribe,
+    /// Loads a zone file without making it live, replacing any zone already staged for its
+    /// origin. The staged zone can be queried with `Query` and `GetZone`, then made live with
+    /// `ActivateZone` or thrown away with `DiscardZone`.
+    StageZone { path: PathBuf },
+    /// Returns the records that differ between the staged zone for an origin and the loaded one.
+    CompareZone { origin: String },
+    ActivateZone { origin: String },
+    DiscardZone { origin: String },
+    /// Starts comparing the answers to one in `sample-every` real queries for a loaded zone with
+    /// the answers the staged zone for the same origin would give, or stops if it is 0. Clients
+    /// are always answered from the loaded zone.
+    ShadowZone { origin: String, sample_every: u32 },
+    /// Returns the results of comparison started with `ShadowZone`.
+    ShadowReport { origin: String },
+    /// Starts replacing the server with a new process; see the `upgrade` module. The response
+    /// does not wait for the upgrade, whose progress is sent to event subscribers.
+    Upgrade,
+}
+
+impl Request {
+    /// Returns the access a caller needs to make this request.
+    pub fn required_access(&self) -> Access {
+        match self {
+            // Anyone who can connect may find out what they are allowed to do
+            Request::Hello { .. } => Access::Denied,
+            Request::ListZones
+            | Request::ZoneStats { .. }
+            | Request::Query { .. }
+            | Request::GetZone { .. }
+            | Request::Subscribe
+            | Request::CompareZone { .. }
+            | Request::ShadowReport { .. } => Access::ReadOnly,
+            Request::LoadZone { .. }
+            | Request::StageZone { .. }
+            | Request::ActivateZone { .. }
+            | Request::DiscardZone { .. }
+            | Request::ShadowZone { .. }
+            | Request::ReloadTls
+            | Request::ReopenQueryLog
+            | Request::AddRecords { .. }
+            | Request::RemoveRecords { .. }
+            | Request::Upgrade => Access::Admin,
+        }
+    }
+
+    /// Returns true if this request replaces a loaded or staged zone, and so must not run at the
+    /// same time as another such request.
+    fn replaces_zone(&self) -> bool {
+        match self {
+            Request::LoadZone { .. }
+            | Request::StageZone { .. }
+            | Request::ActivateZone { .. }
+            | Request::DiscardZone { .. }
+            | Request::AddRecords { .. }
+            | Request::RemoveRecords { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+/// The result of a successful request.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Response {
+    Hello(Hello),
+    /// The origin and serial of each loaded zone.
+    Zones(HashMap<String, u32>),
+    ZoneLoaded { origin: String, serial: u32 },
+    ZoneStats(ZoneStats),
+    Query(QueryResponse),
+    Zone(ZoneContents),
+    ZoneDiff(ZoneDiff),
+    ShadowReport(ShadowReport),
+    /// The new serial of an updated zone.
+    Serial(u32),
+    /// The request succeeded, and there is nothing to return.
+    Done,
+    /// Events will follow, each as an `Event` response.
+    Subscribed,
+    Event(Event),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Hello {
+    /// The name and version of the server software.
+    pub server: String,
+    /// The protocol versions the server speaks.
+    pub versions: Vec<u32>,
+    /// The methods the server supports.
+    pub capabilities: Vec<String>,
+    pub access: Access,
+}
+
+/// Why a request failed.
+#[derive(Debug, Deserialize, Serialize, Fail)]
+#[fail(display = "{}", message)]
+#[serde(rename_all = "kebab-case")]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Error {
+        Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<failure::Error> for Error {
+    fn from(err: failure::Error) -> Error {
+        Error::new(ErrorCode::Failed, err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The request could not be read, or has an unknown method.
+    InvalidRequest,
+    /// The request is for a protocol version the server does not speak.
+    UnsupportedVersion,
+    /// The caller is not allowed to make the request.
+    PermissionDenied,
+    /// A parameter of the request is invalid, such as a malformed name.
+    InvalidArgument,
+    /// The zone the request is for is not loaded.
+    NotFound,
+    /// The request is for a feature that is not enabled, such as TLS.
+    NotConfigured,
+    /// The request was valid, but could not be carried out.
+    Failed,
+}
+
+/// What a control socket caller may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Access {
+    Denied,
+    /// Only requests that change nothing, such as listing zones.
+    ReadOnly,
+    Admin,
+}
+
+/// A control socket caller, identified by the peer credentials of its connection.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub access: Access,
+}
+
+/// How many of the most queried names in a zone are returned by `ZoneStats`.
+const TOP_NAMES: usize = 10;
+
+/// Query statistics for a zone, as returned by `ZoneStats`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ZoneStats {
+    pub queries: u64,
+    pub nxdomain: u64,
+    pub nodata: u64,
+    /// Queries by record type, most queried first.
+    pub record_types: Vec<(String, u64)>,
+    /// The most queried names, most queried first. Counts are approximate.
+    pub top_names: Vec<(String, u64)>,
 }
 
-pub fn handle_request(request: Request, authority: &Arc<RwLock<Authority>>) -> impl Serialize {
+/// The response to a `Query`, decoded for display.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QueryResponse {
+    pub id: u16,
+    pub rcode: String,
+    /// The header flags that are set, such as `aa`.
+    pub flags: Vec<String>,
+    pub question: Vec<Question>,
+    pub answer: Vec<ResourceRecord>,
+    pub authority: Vec<ResourceRecord>,
+    pub additional: Vec<ResourceRecord>,
+    /// The size of the encoded response in bytes.
+    pub size: usize,
+    /// Whether the rate limiter would drop a UDP response to the client given in the query. Not
+    /// set if no client was given, or if rate limiting is disabled.
+    #[serde(default)]
+    pub rate_limited: Option<bool>,
+}
+
+/// The contents of a zone, as returned by `GetZone`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ZoneContents {
+    pub origin: String,
+    pub serial: u32,
+    /// The records in canonical order, with the SOA record first.
+    pub records: Vec<ResourceRecord>,
+}
+
+/// The differences between a staged zone and the loaded zone it would replace, as returned by
+/// `CompareZone`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ZoneDiff {
+    pub origin: String,
+    /// The serial of the loaded zone, or `None` if the zone is not loaded.
+    pub live_serial: Option<u32>,
+    pub staged_serial: u32,
+    /// Records in the loaded zone but not the staged one.
+    pub removed: Vec<ResourceRecord>,
+    /// Records in the staged zone but not the loaded one.
+    pub added: Vec<ResourceRecord>,
+}
+
+/// The results of comparing a staged zone with the loaded zone using real queries, as returned by
+/// `ShadowReport`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ShadowReport {
+    pub origin: String,
+    pub sample_every: u32,
+    /// Queries for the zone since comparison started, whether or not they were compared.
+    pub queries: u64,
+    pub compared: u64,
+    pub mismatched: u64,
+    /// The most recent mismatches, oldest first.
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// A query the staged zone would have answered differently from the loaded zone.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Mismatch {
+    pub question: Question,
+    pub live: Answer,
+    pub candidate: Answer,
+}
+
+/// The response code and records of an answer. The SOA record of a negative answer is left out.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Answer {
+    pub rcode: String,
+    pub records: Vec<ResourceRecord>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Question {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceRecord {
+    pub name: String,
+    pub ttl: u32,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    /// The record data in zone file presentation format.
+    pub rdata: String,
+}
+
+/// Handles a request envelope read from the control socket.
+pub fn handle_request(request: Value, caller: &Caller, context: &Context) -> ResponseEnvelope {
+    let id = request.get("id").and_then(Value::as_u64);
+    let outcome = match request.get("version").and_then(Value::as_u64) {
+        Some(version) if version == u64::from(PROTOCOL_VERSION) => {
+            match serde_json::from_value::<RequestEnvelope>(request) {
+                Ok(envelope) => match handle(envelope.request, caller, context) {
+                    Ok(response) => Outcome::Result(response),
+                    Err(err) => Outcome::Error(err),
+                },
+                Err(err) => Outcome::Error(Error::new(ErrorCode::InvalidRequest, err.to_string())),
+            }
+        }
+        Some(version) => Outcome::Error(Error::new(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "unsupported protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+        )),
+        None => Outcome::Error(Error::new(
+            ErrorCode::InvalidRequest,
+            "missing protocol version",
+        )),
+    };
+    ResponseEnvelope {
+        version: PROTOCOL_VERSION,
+        id,
+        outcome,
+    }
+}
+
+/// The responses to a request.
+pub type Responses = Box<Stream<Item = ResponseEnvelope, Error = io::Error> + Send>;
+
+/// Handles a request envelope like `handle_request`, but for a `Subscribe` request follows the
+/// response with each event.
+pub fn handle_stream(request: Value, caller: &Caller, context: &Context) -> Responses {
+    let response = handle_request(request, caller, context);
+    match response.outcome {
+        Outcome::Result(Response::Subscribed) => {
+            let id = response.id;
+            Box::new(
+                stream::once(Ok(response)).chain(
+                    context
+                        .events
+                        .subscribe()
+                        .map(move |event| ResponseEnvelope {
+                            version: PROTOCOL_VERSION,
+                            id,
+                            outcome: Outcome::Result(Response::Event(event)),
+                        }).map_err(|()| unreachable!()),
+                ),
+            )
+        }
+        _ => Box::new(stream::once(Ok(response))),
+    }
+}
+
+fn handle(request: Request, caller: &Caller, context: &Context) -> Result<Response, Error> {
     debug!("control socket request: {:?}", request);
-    let b: Box<erased_serde::Serialize + Send> = match request {
-        Request::ListZones => Box::new(list_zones(authority)),
+    let required = request.required_access();
+    if caller.access < required {
+        warn!(
+            "control socket: denied request from uid {} gid {}: {:?}",
+            caller.uid, caller.gid, request
+        );
+        return Err(Error::new(ErrorCode::PermissionDenied, "permission denied"));
+    }
+    if required == Access::Admin {
+        info!(
+            "control socket: request from uid {} gid {}: {:?}",
+            caller.uid, caller.gid, request
+        );
+    }
+    // Zone updates write their zone file without holding the authority lock, so they must not
+    // overlap with anything else that replaces the zone.
+    let _guard = if request.replaces_zone() {
+        Some(
+            context
+                .zone_updates
+                .lock()
+                .unwrap_or_else(|_| fatal!("zone updates are poisoned")),
+        )
+    } else {
+        None
+    };
+    let authority = &context.authority;
+    Ok(match request {
+        Request::Hello { capabilities } => {
+            debug!("control socket client capabilities: {:?}", capabilities);
+            Response::Hello(Hello {
+                server: String::from(concat!("pepbut-nsd ", env!("CARGO_PKG_VERSION"))),
+                versions: vec![PROTOCOL_VERSION],
+                capabilities: METHODS.iter().map(|&method| String::from(method)).collect(),
+                access: caller.access,
+            })
+        }
+        Request::ListZones => Response::Zones(list_zones(authority)),
         Request::LoadZone { path } => {
-            Box::new(load_zone(authority, path).map_err(|err| format!("{}", err)))
+            let result = load_zone(authority, &path);
+            context.metrics.record_zone_load(result.is_ok());
+            match result {
+                Ok((origin, serial, reloaded)) => {
+                    context.events.send(&if reloaded {
+                        Event::ZoneReloaded {
+                            origin: origin.clone(),
+                            serial,
+                        }
+                    } else {
+                        Event::ZoneLoaded {
+                            origin: origin.clone(),
+                            serial,
+                        }
+                    });
+                    Response::ZoneLoaded { origin, serial }
+                }
+                Err(err) => {
+                    context.events.send(&Event::ZoneLoadFailed {
+                        path,
+                        error: err.to_string(),
+                    });
+                    return Err(err.into());
+                }
+            }
         }
-    };
-    b
+        Request::ReloadTls => {
+            reload_tls(context)?;
+            Response::Done
+        }
+        Request::ReopenQueryLog => {
+            reopen_query_log(context)?;
+            Response::Done
+        }
+        Request::ZoneStats { origin } => Response::ZoneStats(zone_stats(authority, &origin)?),
+        Request::AddRecords {
+            origin,
+            records,
+            write,
+        } => Response::Serial(update_zone(context, &origin, &[], &records, write)?),
+        Request::RemoveRecords {
+            origin,
+            records,
+            write,
+        } => Response::Serial(update_zone(context, &origin, &records, &[], write)?),
+        Request::Subscribe => Response::Subscribed,
+        Request::Upgrade => {
+            if !context.upgrade.is_enabled() {
+                return Err(Error::new(
+                    ErrorCode::NotConfigured,
+                    "upgrades are not enabled",
+                ));
+            }
+            context.upgrade.request()?;
+            Response::Done
+        }
+        Request::GetZone {
+            origin,
+            name,
+            qtype,
+            staged,
+        } => Response::Zone(get_zone(
+            authority,
+            &origin,
+            name.as_ref(),
+            qtype.as_ref(),
+            staged,
+        )?),
+        Request::Query {
+            name,
+            qtype,
+            staged,
+            client,
+        } => {
+            let mut response = query(authority, &name, &qtype, staged)?;
+            if let (Some(client), Some(ref rate_limiter)) = (client, &context.rate_limiter) {
+                response.rate_limited = Some(rate_limiter.would_drop(client));
+            }
+            Response::Query(response)
+        }
+        Request::StageZone { path } => {
+            let result = authority
+                .write()
+                .unwrap_or_else(|_| fatal!("authority is poisoned"))
+                .stage_zonefile(&path);
+            match result {
+                Ok((origin, serial)) => {
+                    let origin = origin.to_string();
+                    context.events.send(&Event::ZoneStaged {
+                        origin: origin.clone(),
+                        serial,
+                    });
+                    Response::ZoneLoaded { origin, serial }
+                }
+                Err(err) => {
+                    context.metrics.record_zone_load(false);
+                    context.events.send(&Event::ZoneLoadFailed {
+                        path,
+                        error: err.to_string(),
+                    });
+                    return Err(err.into());
+                }
+            }
+        }
+        Request::CompareZone { origin } => Response::ZoneDiff(compare_zone(authority, &origin)?),
+        Request::ActivateZone { origin } => {
+            let origin = parse_name(&origin)?;
+            let (serial, reloaded) = {
+                let mut authority = authority
+                    .write()
+                    .unwrap_or_else(|_| fatal!("authority is poisoned"));
+                let reloaded = authority.zones.contains_key(&origin);
+                match authority.activate_zone(&origin) {
+                    Some(serial) => (serial, reloaded),
+                    None => return Err(not_found(&origin, true)),
+                }
+            };
+            context.metrics.record_zone_load(true);
+            let origin = origin.to_string();
+            context.events.send(&if reloaded {
+                Event::ZoneReloaded {
+                    origin: origin.clone(),
+                    serial,
+                }
+            } else {
+                Event::ZoneLoaded {
+                    origin: origin.clone(),
+                    serial,
+                }
+            });
+            Response::ZoneLoaded { origin, serial }
+        }
+        Request::ShadowZone {
+            origin,
+            sample_every,
+        } => {
+            let origin = parse_name(&origin)?;
+            if !authority
+                .write()
+                .unwrap_or_else(|_| fatal!("authority is poisoned"))
+                .shadow_zone(&origin, sample_every)
+            {
+                return Err(not_found(&origin, true));
+            }
+            Response::Done
+        }
+        Request::ShadowReport { origin } => {
+            Response::ShadowReport(shadow_report(authority, &origin)?)
+        }
+        Request::DiscardZone { origin } => {
+            let origin = parse_name(&origin)?;
+            if !authority
+                .write()
+                .unwrap_or_else(|_| fatal!("authority is poisoned"))
+                .discard_zone(&origin)
+            {
+                return Err(not_found(&origin, true));
+            }
+            context.events.send(&Event::ZoneDiscarded {
+                origin: origin.to_string(),
+            });
+            Response::Done
+        }
+    })
+}
+
+fn invalid_argument(message: impl Display) -> Error {
+    Error::new(ErrorCode::InvalidArgument, message.to_string())
+}
+
+fn parse_name(name: &str) -> Result<Name, Error> {
+    Name::from_str(name).map_err(|err| invalid_argument(format!("invalid name {}: {}", name, err)))
+}
+
+fn parse_qtype(qtype: &str) -> Result<u16, Error> {
+    record::type_from_str(qtype)
+        .ok_or_else(|| invalid_argument(format!("unknown record type {}", qtype)))
+}
+
+fn not_loaded(origin: &Name) -> Error {
+    not_found(origin, false)
+}
+
+fn not_found(origin: &Name, staged: bool) -> Error {
+    Error::new(
+        ErrorCode::NotFound,
+        format!(
+            "zone {} is not {}",
+            origin,
+            if staged { "staged" } else { "loaded" }
+        ),
+    )
 }
 
 fn list_zones(authority: &Arc<RwLock<Authority>>) -> HashMap<String, u32> {
@@ -37,13 +730,629 @@ fn list_zones(authority: &Arc<RwLock<Authority>>) -> HashMap<String, u32> {
         .collect()
 }
 
+/// Loads a zone file, returning the origin and serial of the zone and whether it replaced a loaded
+/// zone.
 fn load_zone(
     authority: &Arc<RwLock<Authority>>,
-    path: PathBuf,
-) -> Result<(String, u32), failure::Error> {
-    authority
+    path: &Path,
+) -> Result<(String, u32, bool), failure::Error> {
+    let mut authority = authority
         .write()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"));
+    let zones = authority.zones.len();
+    let (name, serial) = authority.load_zonefile(path)?;
+    Ok((name.to_string(), serial, authority.zones.len() == zones))
+}
+
+fn reload_tls(context: &Context) -> Result<(), Error> {
+    match context.reload_tls() {
+        Some(result) => Ok(result?),
+        None => Err(Error::new(ErrorCode::NotConfigured, "TLS is not configured")),
+    }
+}
+
+fn reopen_query_log(context: &Context) -> Result<(), Error> {
+    match context.reopen_query_log() {
+        Some(result) => Ok(result?),
+        None => Err(Error::new(ErrorCode::NotConfigured, "the query log is not enabled")),
+    }
+}
+
+fn zone_stats(
+    authority: &Arc<RwLock<Authority>>,
+    origin: &str,
+) -> Result<ZoneStats, Error> {
+    let name = parse_name(origin)?;
+    let stats = match authority
+        .read()
         .unwrap_or_else(|_| fatal!("authority is poisoned"))
-        .load_zonefile(path)
-        .map(|(name, serial)| (name.to_string(), serial))
+        .zone_stats(&name)
+    {
+        Some(stats) => stats,
+        None => return Err(not_loaded(&name)),
+    };
+    let mut record_types = stats
+        .record_types
+        .iter()
+        .map(|(&record_type, &count)| (record::type_string(record_type), count))
+        .collect::<Vec<_>>();
+    record_types.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(ZoneStats {
+        queries: stats.queries,
+        nxdomain: stats.nxdomain,
+        nodata: stats.nodata,
+        record_types,
+        top_names: stats
+            .top_names
+            .into_iter()
+            .take(TOP_NAMES)
+            .map(|(name, count)| (name.to_string(), count))
+            .collect(),
+    })
+}
+
+fn get_zone(
+    authority: &Arc<RwLock<Authority>>,
+    origin: &str,
+    name: Option<&String>,
+    qtype: Option<&String>,
+    staged: bool,
+) -> Result<ZoneContents, Error> {
+    let origin = parse_name(origin)?;
+    let suffix = match name {
+        Some(name) => Some(parse_name(name)?),
+        None => None,
+    };
+    let qtype = match qtype {
+        Some(qtype) => Some(parse_qtype(qtype)?),
+        None => None,
+    };
+    let authority = authority
+        .read()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"));
+    let zone = find_zone(&authority, &origin, staged)?;
+    Ok(ZoneContents {
+        origin: zone.origin.to_fqdn(),
+        serial: zone.serial,
+        records: zone_records(zone)
+            .into_iter()
+            .filter(|&(name, record_type, _, _)| match suffix {
+                Some(ref suffix) if !name.ends_with(suffix) => false,
+                _ => qtype.unwrap_or(record_type) == record_type,
+            }).map(resource_record)
+            .collect(),
+    })
+}
+
+/// Returns the loaded or, if `staged` is true, the staged zone for an origin.
+fn find_zone<'a>(authority: &'a Authority, origin: &Name, staged: bool) -> Result<&'a Zone, Error> {
+    let zone = if staged {
+        authority.staged_zone(origin)
+    } else {
+        authority.zones.get(origin)
+    };
+    zone.ok_or_else(|| not_found(origin, staged))
+}
+
+/// Returns the name, type, TTL, and data of each record in a zone, including its SOA record.
+fn zone_records(zone: &Zone) -> Vec<(&Name, u16, u32, String)> {
+    let soa = format!(
+        "{} {} {} {} {} {} {}",
+        zone.soa.mname.to_fqdn(),
+        zone.soa.rname.to_fqdn(),
+        zone.serial,
+        zone.soa.refresh,
+        zone.soa.retry,
+        zone.soa.expire,
+        zone.soa.minimum
+    );
+    let mut records = Some((&zone.origin, 6, zone.soa.ttl, soa))
+        .into_iter()
+        .chain(zone.iter().map(|record| {
+            (
+                record.name(),
+                record.record_type(),
+                record.ttl(),
+                record.rdata().to_string(),
+            )
+        })).collect::<Vec<_>>();
+    // Canonical order (RFC 4034 § 6.1) by name, then by type, but with the SOA record first as in
+    // a zone file
+    records.sort_by(|a, b| {
+        (a.1 != 6)
+            .cmp(&(b.1 != 6))
+            .then_with(|| {
+                a.0.as_slice()
+                    .iter()
+                    .rev()
+                    .map(|label| label.to_ascii_lowercase())
+                    .cmp(b.0.as_slice().iter().rev().map(|label| label.to_ascii_lowercase()))
+            }).then_with(|| a.1.cmp(&b.1))
+            .then_with(|| a.3.cmp(&b.3))
+    });
+    records
+}
+
+fn resource_record((name, record_type, ttl, rdata): (&Name, u16, u32, String)) -> ResourceRecord {
+    ResourceRecord {
+        name: name.to_fqdn(),
+        ttl,
+        record_type: record::type_string(record_type),
+        rdata,
+    }
+}
+
+/// Compares the zone staged for an origin with the loaded zone, if there is one. SOA records are
+/// left out, as they differ by serial.
+fn compare_zone(authority: &Arc<RwLock<Authority>>, origin: &str) -> Result<ZoneDiff, Error> {
+    let origin = parse_name(origin)?;
+    let authority = authority
+        .read()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"));
+    let staged = find_zone(&authority, &origin, true)?;
+    let live = authority.zones.get(&origin);
+    let records = |zone: &Zone| {
+        zone_records(zone)
+            .into_iter()
+            .filter(|&(_, record_type, _, _)| record_type != 6)
+            .map(resource_record)
+            .collect::<Vec<_>>()
+    };
+    let staged_records = records(staged);
+    let live_records = live.map(records).unwrap_or_default();
+    Ok(ZoneDiff {
+        origin: origin.to_fqdn(),
+        live_serial: live.map(|zone| zone.serial),
+        staged_serial: staged.serial,
+        removed: live_records
+            .iter()
+            .filter(|record| !staged_records.contains(record))
+            .cloned()
+            .collect(),
+        added: staged_records
+            .iter()
+            .filter(|record| !live_records.contains(record))
+            .cloned()
+            .collect(),
+    })
+}
+
+fn shadow_report(authority: &Arc<RwLock<Authority>>, origin: &str) -> Result<ShadowReport, Error> {
+    let origin = parse_name(origin)?;
+    let report = match authority
+        .read()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"))
+        .shadow_report(&origin)
+    {
+        Some(report) => report,
+        None => {
+            return Err(Error::new(
+                ErrorCode::NotFound,
+                format!("zone {} is not being shadowed", origin),
+            ))
+        }
+    };
+    let answer = |answer: &shadow::Answer| Answer {
+        rcode: rcode_name(answer.rcode),
+        records: answer
+            .records
+            .iter()
+            .map(|record| {
+                resource_record((
+                    record.name(),
+                    record.record_type(),
+                    record.ttl(),
+                    record.rdata().to_string(),
+                ))
+            }).collect(),
+    };
+    Ok(ShadowReport {
+        origin: origin.to_fqdn(),
+        sample_every: report.sample_every,
+        queries: report.queries,
+        compared: report.compared,
+        mismatched: report.mismatched,
+        mismatches: report
+            .recent
+            .iter()
+            .map(|mismatch| Mismatch {
+                question: Question {
+                    name: mismatch.name.to_fqdn(),
+                    record_type: record::type_string(mismatch.record_type),
+                },
+                live: answer(&mismatch.live),
+                candidate: answer(&mismatch.candidate),
+            }).collect(),
+    })
+}
+
+fn update_zone(
+    context: &Context,
+    origin: &str,
+    remove: &[ResourceRecord],
+    add: &[ResourceRecord],
+    write: bool,
+) -> Result<u32, Error> {
+    let origin = parse_name(origin)?;
+    let remove = remove
+        .iter()
+        .map(parse_record)
+        .collect::<Result<Vec<_>, _>>()?;
+    let add = add.iter().map(parse_record).collect::<Result<Vec<_>, _>>()?;
+    // Queries are answered while the zone file is written; the write lock is only taken to swap
+    // the updated zone in. `handle` holds `zone_updates`, so the zone cannot be replaced before
+    // then.
+    let update = {
+        let authority = context
+            .authority
+            .read()
+            .unwrap_or_else(|_| fatal!("authority is poisoned"));
+        if !authority.zones.contains_key(&origin) {
+            return Err(not_loaded(&origin));
+        }
+        authority.prepare_update(&origin, &remove, &add)?
+    };
+    if write {
+        context
+            .authority
+            .read()
+            .unwrap_or_else(|_| fatal!("authority is poisoned"))
+            .check_update(&update)?;
+        update.write()?;
+    }
+    let serial = context
+        .authority
+        .write()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"))
+        .apply_update(update)?;
+    context.events.send(&Event::ZoneUpdated {
+        origin: origin.to_string(),
+        serial,
+    });
+    Ok(serial)
+}
+
+fn parse_record(record: &ResourceRecord) -> Result<Record, Error> {
+    let record_type = parse_qtype(&record.record_type)?;
+    Ok(Record::new(
+        parse_name(&record.name)?,
+        record.ttl,
+        RData::parse(record_type, &record.rdata).map_err(invalid_argument)?,
+    ))
+}
+
+fn query(
+    authority: &Arc<RwLock<Authority>>,
+    name: &str,
+    qtype: &str,
+    staged: bool,
+) -> Result<QueryResponse, Error> {
+    let name = parse_name(name)?;
+    let qtype = parse_qtype(qtype)?;
+    let query = encode_query(&name, qtype)?;
+    let authority = authority
+        .read()
+        .unwrap_or_else(|_| fatal!("authority is poisoned"));
+    let response = if staged {
+        authority.process_staged_message(query)
+    } else {
+        authority.process_unrecorded_message(query)
+    };
+    let response = response.ok_or_else(|| format_err!("the query was not answered"))?;
+    Ok(describe(&response)?)
+}
+
+fn encode_query(name: &Name, qtype: u16) -> Result<Bytes, failure::Error> {
+    let mut buf = BytesMut::with_capacity(512);
+    // ID, then all flags clear
+    buf.put_u16_be(0);
+    buf.put_u16_be(0);
+    // QDCOUNT, ANCOUNT, NSCOUNT, ARCOUNT
+    buf.put_u16_be(1);
+    buf.put_u16_be(0);
+    buf.put_u32_be(0);
+    name.encode(&mut buf, &mut HashMap::new())
+        .map_err(|_| format_err!("name {} is too long", name))?;
+    buf.reserve(4);
+    buf.put_u16_be(qtype);
+    buf.put_u16_be(1);
+    Ok(buf.freeze())
+}
+
+/// Decodes an encoded response.
+fn describe(message: &Bytes) -> Result<QueryResponse, failure::Error> {
+    let response = DecodedResponse::decode(&mut Cursor::new(message.clone()))?;
+    let flags = [
+        (0x8000, "qr"),
+        (0x0400, "aa"),
+        (0x0200, "tc"),
+        (0x0100, "rd"),
+        (0x0080, "ra"),
+    ].iter()
+    .filter(|&&(bit, _)| response.flags & bit != 0)
+    .map(|&(_, flag)| String::from(flag))
+    .collect();
+    let records = |records: Vec<DecodedRecord>| {
+        records
+            .into_iter()
+            .map(|record| ResourceRecord {
+                name: record.name.to_fqdn(),
+                ttl: record.ttl,
+                record_type: record::type_string(record.record_type),
+                rdata: record.rdata,
+            }).collect()
+    };
+
+    Ok(QueryResponse {
+        id: response.id,
+        rcode: rcode_name(response.rcode()),
+        flags,
+        question: response
+            .questions
+            .iter()
+            .map(|(name, record_type)| Question {
+                name: name.to_fqdn(),
+                record_type: record::type_string(*record_type),
+            }).collect(),
+        answer: records(response.answer),
+        authority: records(response.authority),
+        additional: records(response.additional),
+        size: message.len(),
+        rate_limited: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pepbut::authority::Authority;
+    use pepbut::name::Name;
+    use pepbut::record::{RData, Record};
+    use serde_json;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::str::FromStr;
+    use std::sync::{Arc, RwLock};
+
+    use config::{Config, RateLimitConfig};
+    use ctl::{
+        compare_zone, get_zone, handle_request, query, zone_stats, Access, Caller, ErrorCode,
+        Response, ResponseEnvelope, PROTOCOL_VERSION,
+    };
+    use server::Context;
+
+    fn authority() -> Arc<RwLock<Authority>> {
+        let mut authority = Authority::new();
+        authority
+            .load_zonefile(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../tests/data/example.invalid.zone"
+            )).unwrap();
+        Arc::new(RwLock::new(authority))
+    }
+
+    #[test]
+    fn query_answer() {
+        let authority = authority();
+        let response = query(&authority, "www2.example.invalid.", "a", false).unwrap();
+        assert_eq!(response.rcode, "NOERROR");
+        assert_eq!(response.flags, vec!["qr", "aa"]);
+        assert_eq!(response.question[0].name, "www2.example.invalid.");
+        assert_eq!(response.question[0].record_type, "A");
+        let answer = response
+            .answer
+            .iter()
+            .map(|rr| (&rr.name[..], rr.ttl, &rr.record_type[..], &rr.rdata[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            answer,
+            vec![
+                ("www2.example.invalid.", 300, "CNAME", "www.example.invalid."),
+                ("www.example.invalid.", 300, "A", "192.0.2.1"),
+            ]
+        );
+
+        let response = query(&authority, "example.invalid", "TXT", false).unwrap();
+        assert_eq!(response.answer[0].rdata, "\"v=spf1 -all\"");
+        let response = query(&authority, "_sip._tcp.example.invalid", "SRV", false).unwrap();
+        assert_eq!(response.answer[0].rdata, "0 5 5060 sip.example.invalid.");
+
+        // Queries from the control socket are not counted as client queries
+        let stats = zone_stats(&authority, "example.invalid").unwrap();
+        assert_eq!(stats.queries, 0);
+    }
+
+    #[test]
+    fn get_zone_contents() {
+        let authority = authority();
+        let contents = get_zone(&authority, "example.invalid", None, None, false).unwrap();
+        assert_eq!(contents.origin, "example.invalid.");
+        assert_eq!(contents.serial, 1_234_567_890);
+        let records = contents
+            .records
+            .iter()
+            .map(|rr| (&rr.name[..], &rr.record_type[..], &rr.rdata[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 11);
+        assert_eq!(records[0].1, "SOA");
+        assert_eq!(records[1], ("example.invalid.", "NS", "ns1.example.invalid."));
+        assert_eq!(records[5], ("example.invalid.", "TXT", "\"v=spf1 -all\""));
+        assert_eq!(
+            records[6],
+            ("_sip._tcp.example.invalid.", "SRV", "0 5 5060 sip.example.invalid.")
+        );
+
+        let www = String::from("www.example.invalid");
+        let contents = get_zone(&authority, "example.invalid", Some(&www), None, false).unwrap();
+        assert_eq!(contents.records.len(), 2);
+        let mx = String::from("MX");
+        let contents = get_zone(&authority, "example.invalid", None, Some(&mx), false).unwrap();
+        assert_eq!(contents.records[0].rdata, "10 mx1.mail.invalid.");
+        assert_eq!(contents.records[1].rdata, "20 mx2.mail.invalid.");
+
+        assert!(get_zone(&authority, "example.net", None, None, false).is_err());
+    }
+
+    #[test]
+    fn query_nxdomain() {
+        let authority = authority();
+        let response = query(&authority, "nope.example.invalid", "AAAA", false).unwrap();
+        assert_eq!(response.rcode, "NXDOMAIN");
+        assert!(response.answer.is_empty());
+        assert_eq!(response.additional[0].record_type, "SOA");
+        assert_eq!(
+            response.additional[0].rdata,
+            "ns1.wob.zone. hostmistress.as64241.net. 1234567890 1000 2400 604800 3600"
+        );
+
+        assert!(query(&authority, "example.invalid", "bogus", false).is_err());
+    }
+
+    #[test]
+    fn staged_zone() {
+        let authority = authority();
+        let path = env::temp_dir().join(format!("pepbut-ctl-staged-{}.zone", process::id()));
+        {
+            let authority = authority.read().unwrap();
+            let origin = Name::from_str("example.invalid").unwrap();
+            let mut zone = authority.zones[&origin].clone();
+            zone.push(Record::new(
+                Name::from_str("www3.example.invalid").unwrap(),
+                60,
+                RData::A("192.0.2.3".parse().unwrap()),
+            ));
+            zone.serial += 1;
+            zone.write_to(&mut fs::File::create(&path).unwrap()).unwrap();
+        }
+        assert!(compare_zone(&authority, "example.invalid").is_err());
+        authority.write().unwrap().stage_zonefile(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let diff = compare_zone(&authority, "example.invalid").unwrap();
+        assert_eq!(diff.live_serial, Some(1_234_567_890));
+        assert_eq!(diff.staged_serial, 1_234_567_891);
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].name, "www3.example.invalid.");
+        assert_eq!(diff.added[0].rdata, "192.0.2.3");
+
+        let response = query(&authority, "www3.example.invalid", "A", false).unwrap();
+        assert_eq!(response.rcode, "NXDOMAIN");
+        let response = query(&authority, "www3.example.invalid", "A", true).unwrap();
+        assert_eq!(response.answer.len(), 1);
+        let contents = get_zone(&authority, "example.invalid", None, None, true).unwrap();
+        assert_eq!(contents.serial, 1_234_567_891);
+    }
+
+    #[test]
+    fn query_client() {
+        let config = Config {
+            rate_limit: RateLimitConfig {
+                responses_per_second: 1,
+                burst: 1,
+            },
+            ..Config::default()
+        };
+        let context = Context::new(authority(), &config).unwrap();
+        let caller = Caller {
+            uid: 1000,
+            gid: 1000,
+            access: Access::ReadOnly,
+        };
+        let rate_limited = |client: Option<&str>| {
+            let mut request = json!({
+                "version": 1,
+                "id": 1,
+                "method": "query",
+                "name": "www.example.invalid",
+                "qtype": "A",
+            });
+            if let Some(client) = client {
+                request["client"] = json!(client);
+            }
+            match handle_request(request, &caller, &context).into_result().unwrap() {
+                Response::Query(response) => response.rate_limited,
+                response => panic!("unexpected response {:?}", response),
+            }
+        };
+
+        assert_eq!(rate_limited(None), None);
+        assert_eq!(rate_limited(Some("192.0.2.9")), Some(false));
+        // Asking does not take a token
+        assert_eq!(rate_limited(Some("192.0.2.9")), Some(false));
+        context
+            .rate_limiter
+            .as_ref()
+            .unwrap()
+            .check("192.0.2.9".parse().unwrap());
+        assert_eq!(rate_limited(Some("192.0.2.9")), Some(true));
+        assert_eq!(rate_limited(Some("192.0.2.10")), Some(false));
+    }
+
+    #[test]
+    fn envelope() {
+        let context = Context::new(authority(), &Config::default()).unwrap();
+        let caller = Caller {
+            uid: 1000,
+            gid: 1000,
+            access: Access::ReadOnly,
+        };
+        let request = |value| {
+            let response = handle_request(value, &caller, &context);
+            // Responses must survive the trip through JSON
+            serde_json::from_value::<ResponseEnvelope>(serde_json::to_value(&response).unwrap())
+                .unwrap()
+        };
+
+        let response = request(json!({"version": 1, "id": 3, "method": "list-zones"}));
+        assert_eq!(response.id, Some(3));
+        match response.into_result().unwrap() {
+            Response::Zones(zones) => assert_eq!(zones["example.invalid"], 1_234_567_890),
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        let response = request(json!({"version": 1, "id": 4, "method": "hello"}));
+        match response.into_result().unwrap() {
+            Response::Hello(hello) => {
+                assert_eq!(hello.versions, vec![PROTOCOL_VERSION]);
+                assert_eq!(hello.access, Access::ReadOnly);
+                assert!(hello.capabilities.contains(&String::from("get-zone")));
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+
+        for (value, id, code) in &[
+            (
+                json!({"version": 1, "id": 5, "method": "load-zone", "path": "/dev/null"}),
+                Some(5),
+                ErrorCode::PermissionDenied,
+            ),
+            (
+                json!({"version": 1, "id": 6, "method": "zone-stats", "origin": "example.net"}),
+                Some(6),
+                ErrorCode::NotFound,
+            ),
+            (
+                json!({"version": 1, "id": 7, "method": "query", "name": "a..b", "qtype": "A"}),
+                Some(7),
+                ErrorCode::InvalidArgument,
+            ),
+            (
+                json!({"version": 1, "id": 8, "method": "make-coffee"}),
+                Some(8),
+                ErrorCode::InvalidRequest,
+            ),
+            (
+                json!({"version": 2, "id": 9, "method": "list-zones"}),
+                Some(9),
+                ErrorCode::UnsupportedVersion,
+            ),
+            (json!({"method": "list-zones"}), None, ErrorCode::InvalidRequest),
+        ] {
+            let response = request(value.clone());
+            assert_eq!(response.version, PROTOCOL_VERSION);
+            assert_eq!(response.id, *id);
+            assert_eq!(response.into_result().unwrap_err().code, *code);
+        }
+    }
 }
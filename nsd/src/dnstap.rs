@@ -0,0 +1,480 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! [dnstap](http://dnstap.info) output of queries and responses.
+//!
+//! Messages are protobuf-encoded and framed with
+//! [Frame Streams](https://farsightsec.github.io/fstrm/), and written to a Unix socket or a file by
+//! a separate thread. Queries are never held up by a slow reader: messages are queued, and dropped
+//! if the queue is full.
+
+use bytes::{Buf, BufMut, Bytes};
+use failure::{self, ResultExt};
+use std::fs::File;
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::{DnstapConfig, IdentityConfig, Transport};
+
+/// The Frame Streams content type of dnstap data frames.
+static CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// How long to wait before reconnecting to the socket after a failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long the reader has to accept a connection.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Frame Streams control frame types and fields
+const CONTROL_ACCEPT: u32 = 1;
+const CONTROL_START: u32 = 2;
+const CONTROL_READY: u32 = 4;
+const CONTROL_FIELD_CONTENT_TYPE: u32 = 1;
+
+// dnstap.proto enum values
+const DNSTAP_MESSAGE: u64 = 1;
+const MESSAGE_AUTH_QUERY: u64 = 1;
+const MESSAGE_AUTH_RESPONSE: u64 = 2;
+const SOCKET_FAMILY_INET: u64 = 1;
+const SOCKET_FAMILY_INET6: u64 = 2;
+
+/// A query and its response, waiting to be written.
+#[derive(Debug)]
+struct Event {
+    transport: Transport,
+    client: SocketAddr,
+    query: Bytes,
+    query_time: SystemTime,
+    response: Bytes,
+    response_time: SystemTime,
+}
+
+#[derive(Debug)]
+pub struct Dnstap {
+    sender: SyncSender<Event>,
+}
+
+impl Dnstap {
+    /// Starts the thread that writes dnstap frames. A file is created immediately; a socket is
+    /// connected to by the thread, which keeps trying until it succeeds.
+    pub fn new(config: &DnstapConfig, identity: &IdentityConfig) -> Result<Dnstap, failure::Error> {
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+        let writer = Writer {
+            identity: identity.name().into_bytes(),
+            version: identity.version().into_bytes(),
+            receiver,
+        };
+        match (&config.socket, &config.file) {
+            (Some(path), None) => {
+                let path = path.clone();
+                thread::Builder::new()
+                    .name(String::from("dnstap"))
+                    .spawn(move || writer.run(&path))?;
+            }
+            (None, Some(path)) => {
+                let file = File::create(path).with_context(|e| {
+                    format!("failed to create dnstap file {}: {}", path.display(), e)
+                })?;
+                let path = path.clone();
+                thread::Builder::new()
+                    .name(String::from("dnstap"))
+                    .spawn(move || {
+                        if let Err(err) = writer.write_file(BufWriter::new(file)) {
+                            error!("failed to write to dnstap file {}: {}", path.display(), err);
+                        }
+                    })?;
+            }
+            _ => bail!("exactly one of dnstap.socket and dnstap.file must be set"),
+        }
+        Ok(Dnstap { sender })
+    }
+
+    /// Queues a query and its response to be written. Returns `false` if the queue is full and
+    /// they were dropped.
+    pub fn log(
+        &self,
+        transport: Transport,
+        client: SocketAddr,
+        query: Bytes,
+        query_time: SystemTime,
+        response: Bytes,
+    ) -> bool {
+        match self.sender.try_send(Event {
+            transport,
+            client,
+            query,
+            query_time,
+            response,
+            response_time: SystemTime::now(),
+        }) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+struct Writer {
+    identity: Vec<u8>,
+    version: Vec<u8>,
+    receiver: Receiver<Event>,
+}
+
+impl Writer {
+    /// Writes to the socket, reconnecting after failures, until the server exits. Events queue
+    /// while there is no connection.
+    fn run(&self, path: &Path) {
+        let mut failing = false;
+        loop {
+            match connect(path, HANDSHAKE_TIMEOUT) {
+                Ok(stream) => {
+                    info!("connected to dnstap socket {}", path.display());
+                    failing = false;
+                    match self.write_file(BufWriter::new(stream)) {
+                        Ok(()) => return,
+                        Err(err) => warn!("lost dnstap socket {}: {}", path.display(), err),
+                    }
+                }
+                Err(err) => {
+                    // Only log the first of a run of failures
+                    if !failing {
+                        warn!("failed to connect to dnstap socket {}: {}", path.display(), err);
+                    }
+                    failing = true;
+                }
+            }
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+
+    /// Writes a START frame, then a data frame for each event.
+    fn write_file<W: Write>(&self, mut out: W) -> io::Result<()> {
+        out.write_all(&control_frame(CONTROL_START))?;
+        out.flush()?;
+        loop {
+            // Block for the next event, then write out whatever else is queued before flushing
+            let event = match self.receiver.recv() {
+                Ok(event) => event,
+                Err(_) => return Ok(()),
+            };
+            self.write_event(&mut out, &event)?;
+            while let Ok(event) = self.receiver.try_recv() {
+                self.write_event(&mut out, &event)?;
+            }
+            out.flush()?;
+        }
+    }
+
+    fn write_event<W: Write>(&self, out: &mut W, event: &Event) -> io::Result<()> {
+        for &message_type in &[MESSAGE_AUTH_QUERY, MESSAGE_AUTH_RESPONSE] {
+            let frame = self.encode(event, message_type);
+            let mut len = Vec::with_capacity(4);
+            len.put_u32_be(frame.len() as u32);
+            out.write_all(&len)?;
+            out.write_all(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes a `Dnstap` protobuf message.
+    fn encode(&self, event: &Event, message_type: u64) -> Vec<u8> {
+        let mut message = Vec::new();
+        put_varint_field(&mut message, 1, message_type);
+        let (family, address) = match event.client {
+            SocketAddr::V4(addr) => (SOCKET_FAMILY_INET, addr.ip().octets().to_vec()),
+            SocketAddr::V6(addr) => (SOCKET_FAMILY_INET6, addr.ip().octets().to_vec()),
+        };
+        put_varint_field(&mut message, 2, family);
+        put_varint_field(&mut message, 3, socket_protocol(event.transport));
+        put_bytes_field(&mut message, 4, &address);
+        put_varint_field(&mut message, 6, u64::from(event.client.port()));
+        let (sec, nsec) = timestamp(event.query_time);
+        put_varint_field(&mut message, 8, sec);
+        put_fixed32_field(&mut message, 9, nsec);
+        if message_type == MESSAGE_AUTH_QUERY {
+            put_bytes_field(&mut message, 10, &event.query);
+        } else {
+            let (sec, nsec) = timestamp(event.response_time);
+            put_varint_field(&mut message, 12, sec);
+            put_fixed32_field(&mut message, 13, nsec);
+            put_bytes_field(&mut message, 14, &event.response);
+        }
+
+        let mut dnstap = Vec::with_capacity(message.len() + self.identity.len() + 32);
+        put_bytes_field(&mut dnstap, 1, &self.identity);
+        put_bytes_field(&mut dnstap, 2, &self.version);
+        put_bytes_field(&mut dnstap, 14, &message);
+        put_varint_field(&mut dnstap, 15, DNSTAP_MESSAGE);
+        dnstap
+    }
+}
+
+/// Connects to a Frame Streams reader, with the bidirectional handshake: READY, then ACCEPT. START
+/// is sent by `Writer::write_file`.
+///
+/// The handshake fails if it takes longer than `timeout`, so that a reader that never answers is
+/// retried like one that refused the connection.
+fn connect(path: &Path, timeout: Duration) -> io::Result<UnixStream> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let accept = stream
+        .write_all(&control_frame(CONTROL_READY))
+        .and_then(|()| read_control_frame(&mut stream))
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                io::ErrorKind::TimedOut,
+                "reader did not accept the connection in time",
+            ),
+            _ => err,
+        })?;
+    if accept.len() < 4 || Cursor::new(&accept).get_u32_be() != CONTROL_ACCEPT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "reader did not accept the connection",
+        ));
+    }
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(stream)
+}
+
+/// The dnstap `SocketProtocol` for a transport.
+fn socket_protocol(transport: Transport) -> u64 {
+    match transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Tls => 3,
+        Transport::Https => 4,
+    }
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+/// Encodes a Frame Streams control frame with the dnstap content type.
+fn control_frame(control_type: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(CONTENT_TYPE.len() + 20);
+    // An escape sequence, which a data frame length can never be
+    frame.put_u32_be(0);
+    frame.put_u32_be(CONTENT_TYPE.len() as u32 + 12);
+    frame.put_u32_be(control_type);
+    frame.put_u32_be(CONTROL_FIELD_CONTENT_TYPE);
+    frame.put_u32_be(CONTENT_TYPE.len() as u32);
+    frame.put_slice(CONTENT_TYPE);
+    frame
+}
+
+/// Reads a Frame Streams control frame, returning its contents after the length.
+fn read_control_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    let mut header = Cursor::new(&header);
+    if header.get_u32_be() != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    let len = header.get_u32_be();
+    if len > 512 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "control frame is too long",
+        ));
+    }
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn put_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, value);
+}
+
+fn put_fixed32_field(buf: &mut Vec<u8>, field: u64, value: u32) {
+    put_varint(buf, field << 3 | 5);
+    buf.put_u32_le(value);
+}
+
+fn put_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{Buf, Bytes};
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Cursor, Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::process;
+    use std::time::{Duration, SystemTime};
+
+    use config::{DnstapConfig, IdentityConfig, Transport};
+    use dnstap::{
+        connect, control_frame, read_control_frame, Dnstap, CONTENT_TYPE, CONTROL_ACCEPT,
+        CONTROL_READY, CONTROL_START,
+    };
+
+    /// A protobuf field value: either a varint or fixed32, or length-delimited bytes.
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Int(u64),
+        Bytes(Vec<u8>),
+    }
+
+    fn get_varint(buf: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in 0.. {
+            let byte = buf[0];
+            *buf = &buf[1..];
+            value |= u64::from(byte & 0x7f) << (7 * shift);
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Decodes the fields of a protobuf message.
+    fn decode(mut buf: &[u8]) -> HashMap<u64, Value> {
+        let mut fields = HashMap::new();
+        while !buf.is_empty() {
+            let key = get_varint(&mut buf);
+            let value = match key & 7 {
+                0 => Value::Int(get_varint(&mut buf)),
+                2 => {
+                    let len = get_varint(&mut buf) as usize;
+                    let value = buf[..len].to_vec();
+                    buf = &buf[len..];
+                    Value::Bytes(value)
+                }
+                5 => {
+                    let value = Cursor::new(&buf[..4]).get_u32_le();
+                    buf = &buf[4..];
+                    Value::Int(u64::from(value))
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.insert(key >> 3, value);
+        }
+        fields
+    }
+
+    /// Reads a data frame.
+    fn read_data_frame<R: Read>(reader: &mut R) -> Vec<u8> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len).unwrap();
+        let mut frame = vec![0; Cursor::new(&len).get_u32_be() as usize];
+        reader.read_exact(&mut frame).unwrap();
+        frame
+    }
+
+    #[test]
+    fn control_frames() {
+        let frame = control_frame(CONTROL_READY);
+        let contents = read_control_frame(&mut &frame[..]).unwrap();
+        assert_eq!(Cursor::new(&contents).get_u32_be(), CONTROL_READY);
+        assert_eq!(&contents[12..], CONTENT_TYPE);
+        assert!(read_control_frame(&mut &[0, 0, 0, 1, 0, 0, 0, 0][..]).is_err());
+    }
+
+    #[test]
+    fn silent_reader() {
+        let dir = env::temp_dir().join(format!("pepbut-dnstap-silent-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.sock");
+        // The connection is never accepted, so READY is never answered
+        let _listener = UnixListener::bind(&path).unwrap();
+        let err = connect(&path, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn socket() {
+        let dir = env::temp_dir().join(format!("pepbut-dnstap-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dnstap.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let dnstap = Dnstap::new(
+            &DnstapConfig {
+                socket: Some(path.clone()),
+                file: None,
+                queue_size: 1,
+            },
+            &IdentityConfig {
+                name: Some(String::from("ns1.example.invalid")),
+                version: None,
+            },
+        ).unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let ready = read_control_frame(&mut stream).unwrap();
+        assert_eq!(Cursor::new(&ready).get_u32_be(), CONTROL_READY);
+        stream
+            .write_all(&control_frame(CONTROL_ACCEPT))
+            .unwrap();
+        let start = read_control_frame(&mut stream).unwrap();
+        assert_eq!(Cursor::new(&start).get_u32_be(), CONTROL_START);
+
+        let query = Bytes::from_static(b"query");
+        let response = Bytes::from_static(b"response");
+        let client = "[2001:db8::7]:4444".parse().unwrap();
+        assert!(dnstap.log(
+            Transport::Tls,
+            client,
+            query.clone(),
+            SystemTime::now(),
+            response.clone()
+        ));
+
+        let frame = decode(&read_data_frame(&mut stream));
+        assert_eq!(frame[&1], Value::Bytes(b"ns1.example.invalid".to_vec()));
+        assert_eq!(frame[&15], Value::Int(1));
+        let message = match frame[&14] {
+            Value::Bytes(ref message) => decode(message),
+            _ => panic!("message is not embedded"),
+        };
+        assert_eq!(message[&1], Value::Int(1));
+        assert_eq!(message[&2], Value::Int(2));
+        assert_eq!(message[&3], Value::Int(3));
+        assert_eq!(
+            message[&4],
+            Value::Bytes(vec![0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 7])
+        );
+        assert_eq!(message[&6], Value::Int(4444));
+        assert_eq!(message[&10], Value::Bytes(query.to_vec()));
+
+        let frame = decode(&read_data_frame(&mut stream));
+        let message = match frame[&14] {
+            Value::Bytes(ref message) => decode(message),
+            _ => panic!("message is not embedded"),
+        };
+        assert_eq!(message[&1], Value::Int(2));
+        assert!(message.contains_key(&12));
+        assert_eq!(message[&14], Value::Bytes(response.to_vec()));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Events streamed to control socket subscribers.
+
+use futures::sync::mpsc;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::prelude::{Poll, Stream};
+
+/// How many events are buffered for each subscriber. A subscriber that falls further behind is
+/// disconnected, so that it can tell it has missed events.
+const BUFFER: usize = 256;
+
+/// Something that happened in the server.
+///
+/// Zones cannot be unloaded, only replaced, so there is no event for a zone going away.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "event")]
+#[serde(rename_all = "kebab-case")]
+pub enum Event {
+    /// A zone that was not already loaded has been loaded.
+    ZoneLoaded { origin: String, serial: u32 },
+    /// A loaded zone has been replaced by loading it again.
+    ZoneReloaded { origin: String, serial: u32 },
+    /// Records have been added to or removed from a loaded zone.
+    ZoneUpdated { origin: String, serial: u32 },
+    /// A zone file has been loaded into the staging slot for its origin.
+    ZoneStaged { origin: String, serial: u32 },
+    /// The zone staged for an origin has been thrown away.
+    ZoneDiscarded { origin: String },
+    ZoneLoadFailed { path: PathBuf, error: String },
+    /// The TLS certificate and key have been reloaded, on SIGHUP or through the control socket.
+    TlsReloaded,
+    /// Reloading the TLS certificate and key failed; the previous ones are still used.
+    TlsReloadFailed { error: String },
+    /// The query log has been reopened, on SIGUSR1 or through the control socket.
+    QueryLogReopened,
+    /// Reopening the query log failed; queries are still written to the previous file.
+    QueryLogReopenFailed { error: String },
+    /// The rate limiter started dropping responses to a client. It is sent again for the same
+    /// client only after a response to it has been allowed.
+    RateLimited { client: IpAddr },
+    /// A new process has been started to take over from this one.
+    UpgradeStarted { pid: u32 },
+    /// The new process is ready, and this one will shut down once open connections are closed.
+    Upgraded { pid: u32 },
+    /// The new process failed to start or become ready, and this one carries on.
+    UpgradeFailed { error: String },
+    /// The server is shutting down; no more events will be sent.
+    Shutdown,
+}
+
+/// Sends events to every subscriber.
+#[derive(Debug, Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+    /// Shared with each `Subscription`, to count those that have not yet been dropped.
+    active: Arc<()>,
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events::default()
+    }
+
+    /// Starts a subscription, which receives every event sent from now on.
+    pub fn subscribe(&self) -> Subscription {
+        let (sender, receiver) = mpsc::channel(BUFFER);
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|_| fatal!("event subscribers are poisoned"))
+            .push(sender);
+        Subscription {
+            receiver,
+            _active: self.active.clone(),
+        }
+    }
+
+    pub fn send(&self, event: &Event) {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .unwrap_or_else(|_| fatal!("event subscribers are poisoned"));
+        // Cloning a sender would grow the channel, so each one is taken out and put back
+        *subscribers = subscribers
+            .drain(..)
+            .filter_map(|mut subscriber| match subscriber.try_send(event.clone()) {
+                Ok(()) => Some(subscriber),
+                Err(ref err) if err.is_full() => {
+                    warn!("disconnecting event subscriber that fell behind");
+                    None
+                }
+                // The subscriber has gone away
+                Err(_) => None,
+            }).collect();
+    }
+
+    /// Sends `Event::Shutdown`, then ends every subscription once it has received its events.
+    pub fn shutdown(&self) {
+        self.send(&Event::Shutdown);
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|_| fatal!("event subscribers are poisoned"))
+            .clear();
+    }
+
+    /// Returns how many subscriptions have not yet been dropped.
+    pub fn subscribers(&self) -> usize {
+        Arc::strong_count(&self.active) - 1
+    }
+}
+
+/// A stream of the events sent after `Events::subscribe` was called.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: mpsc::Receiver<Event>,
+    _active: Arc<()>,
+}
+
+impl Stream for Subscription {
+    type Item = Event;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Event>, ()> {
+        self.receiver.poll()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::prelude::Stream;
+
+    use super::{Event, Events, BUFFER};
+
+    #[test]
+    fn subscribe() {
+        let events = Events::new();
+        events.send(&Event::Shutdown);
+        let subscription = events.subscribe();
+        let loaded = Event::ZoneLoaded {
+            origin: String::from("example.invalid"),
+            serial: 1,
+        };
+        events.send(&loaded);
+        assert_eq!(events.subscribers(), 1);
+        events.shutdown();
+        assert_eq!(
+            subscription.wait().collect::<Result<Vec<_>, _>>().unwrap(),
+            vec![loaded, Event::Shutdown]
+        );
+        assert_eq!(events.subscribers(), 0);
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let events = Events::new();
+        let _subscription = events.subscribe();
+        for _ in 0..=BUFFER {
+            events.send(&Event::Shutdown);
+        }
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        events.send(&Event::Shutdown);
+        assert!(events.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over HTTPS ([RFC 8484](https://tools.ietf.org/html/rfc8484)).
+//!
+//! Queries are accepted at `/dns-query`, either base64url-encoded in the `dns` parameter of a GET
+//! request or as the body of a POST request. Responses may be cached for as long as the smallest
+//! TTL of the records in them.
+
+use base64;
+use bytes::Bytes;
+use hyper::header::{HeaderValue, ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{self, Body, Method, Request, Response, StatusCode};
+use pepbut::wire::response_min_ttl;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::prelude::{future, Async, AsyncRead, AsyncWrite, Future, Poll, Stream};
+use tokio::timer::Delay;
+
+use config::Transport;
+use server::Context;
+
+pub type ResponseFuture = Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// The path queries are accepted at.
+pub static PATH: &str = "/dns-query";
+static DNS_MESSAGE: &str = "application/dns-message";
+
+/// The largest DNS message that can be sent over TCP; larger POST bodies are rejected.
+const MAX_MESSAGE_SIZE: usize = 65535;
+/// The smallest possible DNS message, which is just the header.
+const MIN_MESSAGE_SIZE: usize = 12;
+
+/// Answers a DNS over HTTPS request.
+pub fn handle(
+    request: Request<Body>,
+    context: &Arc<Context>,
+    client: SocketAddr,
+) -> ResponseFuture {
+    if request.uri().path() != PATH {
+        return Box::new(future::ok(status(StatusCode::NOT_FOUND)));
+    }
+
+    match *request.method() {
+        Method::GET => {
+            let query = request
+                .uri()
+                .query()
+                .unwrap_or("")
+                .split('&')
+                .filter_map(|pair| {
+                    let mut pair = pair.splitn(2, '=');
+                    match (pair.next(), pair.next()) {
+                        (Some("dns"), Some(value)) => Some(value),
+                        _ => None,
+                    }
+                }).next()
+                .and_then(|value| base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok());
+            Box::new(future::ok(match query {
+                Some(query) => answer(query.into(), context, client),
+                None => status(StatusCode::BAD_REQUEST),
+            }))
+        }
+        Method::POST => {
+            if request.headers().get(CONTENT_TYPE).map(HeaderValue::as_bytes)
+                != Some(DNS_MESSAGE.as_bytes())
+            {
+                return Box::new(future::ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE)));
+            }
+            let context = context.clone();
+            // The body is read to the end even if it is too large, as the connection may be reused
+            Box::new(
+                request
+                    .into_body()
+                    .fold(Some(Vec::new()), |body, chunk| {
+                        Ok::<_, hyper::Error>(body.and_then(|mut body| {
+                            if body.len() + chunk.len() > MAX_MESSAGE_SIZE {
+                                None
+                            } else {
+                                body.extend_from_slice(&chunk);
+                                Some(body)
+                            }
+                        }))
+                    }).map(move |body| match body {
+                        Some(body) => answer(body.into(), &context, client),
+                        None => status(StatusCode::PAYLOAD_TOO_LARGE),
+                    }),
+            )
+        }
+        _ => {
+            let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+            response
+                .headers_mut()
+                .insert(ALLOW, HeaderValue::from_static("GET, POST"));
+            Box::new(future::ok(response))
+        }
+    }
+}
+
+fn answer(query: Bytes, context: &Context, client: SocketAddr) -> Response<Body> {
+    if query.len() < MIN_MESSAGE_SIZE {
+        return status(StatusCode::BAD_REQUEST);
+    }
+    let message = match context.process_message(query, Transport::Https, client) {
+        Some(message) => message,
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+    let max_age = response_min_ttl(&message).unwrap_or(0);
+
+    let mut response = Response::new(Body::empty());
+    {
+        let headers = response.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(message.len()));
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!("max-age={}", max_age)).expect("cannot fail"),
+        );
+    }
+    *response.body_mut() = Body::from(message);
+    response
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Fails reads on a connection once it has neither read nor written anything for a while.
+///
+/// HTTP connections are otherwise kept open for as long as the client likes.
+pub struct IdleTimeout<S> {
+    io: S,
+    timeout: Duration,
+    timer: Delay,
+}
+
+impl<S> IdleTimeout<S> {
+    pub fn new(io: S, timeout: Duration) -> IdleTimeout<S> {
+        IdleTimeout {
+            io,
+            timeout,
+            timer: Delay::new(Instant::now() + timeout),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.timer.reset(Instant::now() + self.timeout);
+    }
+}
+
+impl<S: Read> Read for IdleTimeout<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.read(buf) {
+            Ok(n) => {
+                self.reset();
+                Ok(n)
+            }
+            Err(err) => {
+                if err.kind() == io::ErrorKind::WouldBlock {
+                    if let Ok(Async::Ready(())) = self.timer.poll() {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"));
+                    }
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for IdleTimeout<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.io.write(buf)?;
+        self.reset();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for IdleTimeout<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for IdleTimeout<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.io.shutdown()
+    }
+}
@@ -3,17 +3,31 @@
 #![cfg_attr(feature = "cargo-clippy", warn(clippy_pedantic))]
 #![cfg_attr(feature = "cargo-clippy", allow(use_self, stutter))]
 
+extern crate base64;
 extern crate bytes;
 extern crate cast;
-extern crate erased_serde;
+#[macro_use]
 extern crate failure;
+extern crate futures;
+extern crate humantime;
+extern crate hyper;
+extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate net2;
+extern crate num_cpus;
 extern crate pepbut;
+extern crate rustls;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+extern crate tokio;
 extern crate tokio_codec;
+extern crate tokio_rustls;
+extern crate toml;
+extern crate users;
 
 macro_rules! fatal {
     ($msg:expr) => {{
@@ -23,4 +37,20 @@ macro_rules! fatal {
 }
 
 pub mod codec;
+pub mod config;
 pub mod ctl;
+pub mod dnstap;
+pub mod events;
+pub mod https;
+pub mod metrics;
+pub mod privileges;
+pub mod proxy;
+pub mod querylog;
+pub mod ratelimit;
+pub mod seccomp;
+pub mod server;
+pub mod systemd;
+pub mod tcp;
+pub mod tls;
+pub mod udp;
+pub mod upgrade;
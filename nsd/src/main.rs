@@ -7,14 +7,16 @@ extern crate clap;
 extern crate env_logger;
 #[macro_use]
 extern crate failure;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate pepbut;
 extern crate pepbut_nsd;
-extern crate safeword;
 extern crate tokio;
 extern crate tokio_codec;
 extern crate tokio_jsoncodec;
+extern crate tokio_signal;
+extern crate tokio_threadpool;
 extern crate tokio_uds;
 extern crate users;
 
@@ -22,35 +24,72 @@ use clap::{App, Arg};
 use env_logger::Builder;
 use failure::ResultExt;
 use log::LevelFilter;
-use pepbut::authority::Authority;
-use pepbut_nsd::{codec::DnsCodec, ctl};
-use safeword::{Safeword, Shutdown};
+use pepbut_nsd::config::{
+    Config, ControlConfig, ListenConfig, SeccompAction, Transport, DEFAULT_LISTEN_ADDR,
+    DEFAULT_SOCKET_PATH,
+};
+use pepbut_nsd::ctl;
+use pepbut_nsd::metrics;
+use pepbut_nsd::privileges::{self, Credentials};
+use pepbut_nsd::seccomp::Filter;
+use pepbut_nsd::server::{self, Context, ServerFuture};
+use pepbut_nsd::systemd::{self, Socket};
+use pepbut_nsd::upgrade::{self, Parent};
+use std::env;
+use std::ffi::CString;
 use std::fs;
+use std::io;
 use std::net::SocketAddr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
-use tokio::net::{TcpListener, UdpFramed, UdpSocket};
-use tokio::prelude::{future, Future, Sink, Stream};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::reactor::Handle;
+use tokio::prelude::future::{self, Either};
+use tokio::prelude::{Future, Sink, Stream};
+use tokio::runtime::Runtime;
+use tokio::timer::{Interval, Timeout};
 use tokio_codec::Decoder;
-use tokio_jsoncodec::Codec as JsonCodec;
+use tokio_jsoncodec::{Codec as JsonCodec, Error as JsonError};
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
 use tokio_uds::UnixListener;
 
-static DEFAULT_LISTEN_ADDR: &str = "[::]:53";
-static DEFAULT_SOCKET_PATH: &str = "/run/pepbut/nsd.sock";
+/// How often to check whether event subscribers have disconnected during shutdown.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(10);
+/// How long to wait for event subscribers to disconnect during shutdown.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn main() -> Result<(), failure::Error> {
     // Command line argument parsing
     let matches = App::new("pepbut-nsd")
         .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("CONFIG")
+                .help("TOML configuration file; command line options override its values")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("check_config")
+                .long("check-config")
+                .help("Validate the configuration and zone files, then exit"),
+        ).arg(
             Arg::with_name("listen_addr")
                 .short("l")
                 .long("listen")
                 .value_name("LISTEN_ADDR")
                 .help(&format!(
-                    "ipaddr:port to listen on (default {})",
+                    "ipaddr:port to listen on for UDP and TCP; may be repeated (default {})",
                     DEFAULT_LISTEN_ADDR
-                )).takes_value(true),
+                )).takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
         ).arg(
             Arg::with_name("socket_path")
                 .short("s")
@@ -71,122 +110,607 @@ fn main() -> Result<(), failure::Error> {
                 .multiple(true),
         ).get_matches();
 
-    // Set log level from -v option
+    // Read the configuration file, then apply command line overrides
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    if let Some(addrs) = matches.values_of("listen_addr") {
+        config.listen = addrs
+            .map(|addr_str| {
+                SocketAddr::from_str(addr_str)
+                    .map(ListenConfig::new)
+                    .context(format!("Could not parse LISTEN_ADDR: {}", addr_str))
+            }).collect::<Result<_, _>>()?;
+    }
+    if let Some(path) = matches.value_of("socket_path") {
+        config.control.path = PathBuf::from(path);
+    }
+    if let Some(paths) = matches.values_of("ZONEFILE") {
+        config.zones.files.extend(paths.map(PathBuf::from));
+    }
+    match matches.occurrences_of("verbose") {
+        0 => {}
+        1 => config.log.level = LevelFilter::Debug,
+        2 => config.log.level = LevelFilter::Trace,
+        _ => config.log.level = LevelFilter::max(),
+    }
+    let verbose_all = matches.occurrences_of("verbose") >= 3;
+
+    // Set log level
     {
-        let level = matches.occurrences_of("verbose");
         let mut builder = Builder::new();
-        if level >= 3 {
+        if verbose_all {
             // Enables all log messages across all crates
             builder.filter_level(LevelFilter::Trace)
         } else {
-            let level = match level {
-                0 => LevelFilter::Info,
-                1 => LevelFilter::Debug,
-                _ => LevelFilter::Trace,
-            };
             builder
-                .filter_module("pepbut", level)
-                .filter_module("nsd", level)
+                .filter_module("pepbut", config.log.level)
+                .filter_module("nsd", config.log.level)
         };
         builder.init();
     }
 
-    if users::get_effective_uid() == 0 {
-        error!("pepbut will not run as root!");
-        error!("to listen on a privileged port, run `setcap cap_net_bind_service=+ep` on the nsd binary");
-        ::std::process::exit(1);
-    }
-
-    let addr_str = matches
-        .value_of("listen_addr")
-        .unwrap_or(DEFAULT_LISTEN_ADDR);
-    let addr = SocketAddr::from_str(addr_str)
-        .context(format!("Could not parse LISTEN_ADDR: {}", addr_str))?;
-    let tcp_listener = TcpListener::bind(&addr)
-        .with_context(|e| format!("Failed to bind to TCP socket on {}: {}", addr, e))?;
-    let udp_socket = UdpSocket::bind(&addr)
-        .with_context(|e| format!("Failed to bind to UDP socket on {}: {}", addr, e))?;
-
-    let ctl_socket_path = matches
-        .value_of("socket_path")
-        .unwrap_or(DEFAULT_SOCKET_PATH);
-    let ctl_listener = UnixListener::bind(ctl_socket_path).with_context(|e| {
-        format!(
-            "Failed to bind to Unix socket at {}: {}",
-            ctl_socket_path, e
-        )
-    })?;
+    config.validate()?;
+    if matches.is_present("check_config") {
+        let authority = config.load_authority()?;
+        info!(
+            "configuration OK, {} zones would be loaded",
+            authority.zones.len()
+        );
+        return Ok(());
+    }
+
+    // A process started by an upgrade tells the one it takes over from when it is ready. The
+    // binary is found now, as it may have been replaced by the time we upgrade.
+    let parent = Parent::take().context("Failed to take the socket to the upgrading process")?;
+    let binary = env::current_exe();
+
+    // As root, we only bind sockets before switching to the configured user
+    let credentials = if users::get_effective_uid() == 0 {
+        match Credentials::from_config(&config.privileges)? {
+            Some(credentials) => Some(credentials),
+            None => {
+                error!("pepbut will not run as root without a user to switch to!");
+                error!("to listen on a privileged port, set privileges.user, use systemd socket activation or run `setcap cap_net_bind_service=+ep` on the nsd binary");
+                ::std::process::exit(1);
+            }
+        }
+    } else {
+        if config.privileges.user.is_some() && parent.is_none() {
+            warn!("not running as root, so privileges.user has no effect");
+        }
+        None
+    };
+
+    let authority = Arc::new(RwLock::new(config.load_authority()?));
+    let context = Arc::new(Context::new(authority.clone(), &config)?);
+
+    // Sockets passed by the service manager take the place of the configured ones
+    let mut inherited_dns = Vec::new();
+    let mut inherited_ctl = None;
+    let mut inherited_metrics = None;
+    let inherited = systemd::listen_fds(parent.is_some())
+        .context("Failed to take sockets from the service manager")?;
+    for fd in inherited {
+        match fd.socket {
+            Socket::Unix(listener) => {
+                if inherited_ctl.is_some() {
+                    bail!("more than one Unix control socket was passed by the service manager");
+                }
+                inherited_ctl = Some((listener, fd.name));
+            }
+            Socket::Tcp(listener) if fd.name == "metrics" => inherited_metrics = Some(listener),
+            _ => inherited_dns.push(fd),
+        }
+    }
+
+    // Servers are started again if they stop, so they only end when stopped on shutdown
+    let mut servers: Vec<ServerFuture> = Vec::new();
+    // Signal handlers run until the process exits
+    let mut handlers: Vec<ServerFuture> = Vec::new();
+    // Every listening socket, with the name it is passed to a new process with on upgrade
+    let mut handover: Vec<(RawFd, String)> = Vec::new();
+    if inherited_dns.is_empty() {
+        for listener in &config.listen {
+            let addr = listener.address;
+            for transport in &listener.transports {
+                match transport {
+                    Transport::Udp => {
+                        let udp_sockets = server::bind_udp(&addr, config.udp.sockets())
+                            .with_context(|e| {
+                                format!("Failed to bind to UDP socket on {}: {}", addr, e)
+                            })?;
+                        for udp_socket in udp_sockets {
+                            handover.push((udp_socket.as_raw_fd(), String::from("dns")));
+                            servers.push(server::serve_udp(udp_socket, context.clone())?);
+                        }
+                    }
+                    Transport::Tcp => {
+                        let tcp_listener = server::bind_tcp(&addr).with_context(|e| {
+                            format!("Failed to bind to TCP socket on {}: {}", addr, e)
+                        })?;
+                        handover.push((tcp_listener.as_raw_fd(), String::from("dns")));
+                        servers.push(server::serve_tcp(tcp_listener, *transport, context.clone())?);
+                    }
+                    Transport::Tls => {
+                        let tcp_listener = server::bind_tcp(&addr).with_context(|e| {
+                            format!("Failed to bind to TLS socket on {}: {}", addr, e)
+                        })?;
+                        handover.push((tcp_listener.as_raw_fd(), String::from("tls")));
+                        servers.push(server::serve_tcp(tcp_listener, *transport, context.clone())?);
+                    }
+                    Transport::Https => {
+                        let tcp_listener = server::bind_tcp(&addr).with_context(|e| {
+                            format!("Failed to bind to HTTPS socket on {}: {}", addr, e)
+                        })?;
+                        handover.push((tcp_listener.as_raw_fd(), String::from("https")));
+                        servers.push(server::serve_tcp(tcp_listener, *transport, context.clone())?);
+                    }
+                }
+                match transport {
+                    Transport::Udp => info!(
+                        "listening on {} {} ({} sockets)",
+                        transport,
+                        addr,
+                        config.udp.sockets()
+                    ),
+                    _ => info!("listening on {} {}", transport, addr),
+                }
+            }
+        }
+    } else {
+        for fd in inherited_dns {
+            handover.push((fd.socket.as_raw_fd(), fd.name.clone()));
+            match fd.socket {
+                Socket::Udp(socket) => {
+                    info!("listening on udp {} (socket activated)", socket.local_addr()?);
+                    servers.push(server::serve_udp(socket, context.clone())?);
+                }
+                Socket::Tcp(listener) if fd.name == "tls" || fd.name == "https" => {
+                    if context.tls.is_none() {
+                        bail!(
+                            "[tls] is required for {} sockets passed by the service manager",
+                            fd.name
+                        );
+                    }
+                    info!(
+                        "listening on {} {} (socket activated)",
+                        fd.name,
+                        listener.local_addr()?
+                    );
+                    let transport = if fd.name == "tls" {
+                        Transport::Tls
+                    } else {
+                        Transport::Https
+                    };
+                    servers.push(server::serve_tcp(listener, transport, context.clone())?);
+                }
+                Socket::Tcp(listener) => {
+                    info!("listening on tcp {} (socket activated)", listener.local_addr()?);
+                    servers.push(server::serve_tcp(listener, Transport::Tcp, context.clone())?);
+                }
+                Socket::Unix(_) => unreachable!(),
+            }
+        }
+    }
+
+    match (&config.metrics, inherited_metrics) {
+        (Some(metrics), inherited) => {
+            let listener = match inherited {
+                Some(listener) => listener,
+                None => server::bind_tcp(&metrics.address).with_context(|e| {
+                    format!("Failed to bind to metrics socket on {}: {}", metrics.address, e)
+                })?,
+            };
+            handover.push((listener.as_raw_fd(), String::from("metrics")));
+            let context = context.clone();
+            let name = format!("metrics server on {}", metrics.address);
+            servers.push(server::restarting(name, move || {
+                let listener = TcpListener::from_std(listener.try_clone()?, &Handle::default())?;
+                Ok(metrics::serve(listener, context.clone()))
+            }));
+            info!("serving metrics on http://{}{}", metrics.address, metrics::PATH);
+        }
+        (None, Some(_)) => {
+            warn!("closing the metrics socket passed, as [metrics] is not configured");
+        }
+        (None, None) => {}
+    }
+
+    // The service manager owns an inherited control socket, so we neither set its mode nor remove
+    // it on shutdown. One bound by a process we are upgrading from is ours, though.
+    let ctl_socket_path = config.control.path.clone();
+    let ctl_socket_name = match inherited_ctl {
+        Some((_, ref name)) => name.clone(),
+        None => String::from("control"),
+    };
+    let ctl_socket_owned =
+        inherited_ctl.is_none() || (parent.is_some() && ctl_socket_name == "control");
+    let ctl_listener = match inherited_ctl {
+        Some((listener, _)) => listener,
+        None => {
+            let ctl_listener = net::UnixListener::bind(&ctl_socket_path).with_context(|e| {
+                format!(
+                    "Failed to bind to Unix socket at {}: {}",
+                    ctl_socket_path.display(),
+                    e
+                )
+            })?;
+            if let Some(mode) = config.control.mode {
+                fs::set_permissions(&ctl_socket_path, fs::Permissions::from_mode(mode))
+                    .with_context(|e| {
+                        format!(
+                            "Failed to set mode of Unix socket at {}: {}",
+                            ctl_socket_path.display(),
+                            e
+                        )
+                    })?;
+            }
+            if let Some(ref group) = config.control.group {
+                set_group(&ctl_socket_path, group).with_context(|e| {
+                    format!(
+                        "Failed to set group of Unix socket at {}: {}",
+                        ctl_socket_path.display(),
+                        e
+                    )
+                })?;
+            }
+            ctl_listener
+        }
+    };
+    handover.push((ctl_listener.as_raw_fd(), ctl_socket_name));
+
+    // Every socket is bound, so root is no longer needed. The control socket is handed over to the
+    // user, and its path is adjusted so that it can still be removed on shutdown.
+    let mut ctl_socket_path = ctl_socket_path;
+    let mut ctl_socket_owned = ctl_socket_owned;
+    if let Some(credentials) = credentials {
+        if ctl_socket_owned {
+            chown(&ctl_socket_path, credentials.uid, !0).with_context(|e| {
+                format!(
+                    "Failed to set owner of Unix socket at {}: {}",
+                    ctl_socket_path.display(),
+                    e
+                )
+            })?;
+        }
+        let chroot: Option<&Path> = config.privileges.chroot.as_ref().map(AsRef::as_ref);
+        privileges::drop_privileges(credentials, chroot)?;
+        info!(
+            "switched to uid {} gid {}{}",
+            credentials.uid,
+            credentials.gid,
+            match chroot {
+                Some(dir) => format!(" in chroot {}", dir.display()),
+                None => String::new(),
+            }
+        );
+        if let Some(dir) = chroot {
+            match ctl_socket_path.strip_prefix(dir) {
+                Ok(path) => ctl_socket_path = Path::new("/").join(path),
+                Err(_) => {
+                    warn!(
+                        "control socket {} is outside the chroot, and will not be removed on \
+                         shutdown",
+                        ctl_socket_path.display()
+                    );
+                    ctl_socket_owned = false;
+                }
+            }
+            let outside = authority
+                .write()
+                .map_err(|_| format_err!("authority is poisoned"))?
+                .chroot(dir);
+            for origin in outside {
+                warn!(
+                    "zone {} was loaded from outside the chroot, and updates to it cannot be \
+                     written",
+                    origin
+                );
+            }
+        }
+    }
 
     info!(
-        "pepbut nsd listening on {}, control socket {}, PID {}",
-        addr,
-        ctl_socket_path,
+        "pepbut nsd {} ({}) running with control socket {}, PID {}",
+        config.identity.name(),
+        config.identity.version(),
+        if ctl_socket_owned {
+            ctl_socket_path.display().to_string()
+        } else {
+            String::from("(socket activated)")
+        },
         process::id()
     );
 
-    let authority = Arc::new(RwLock::new(Authority::new()));
-    if let Some(paths) = matches.values_of("ZONEFILE") {
-        let mut authority = authority.write().unwrap();
-        for path in paths {
-            authority
-                .load_zonefile(path)
-                .context(format!("failed to load zone {}", path))?;
-        }
-    }
-
-    let futs: Vec<Box<Future<Item = (), Error = ()> + Send>> = vec![
-        // TCP server
-        Box::new({
-            let authority = authority.clone();
-            tcp_listener
-                .incoming()
-                .for_each(move |tcp| {
-                    let authority = authority.clone();
-                    let (sink, stream) = DnsCodec::tcp().framed(tcp).split();
-                    tokio::spawn(
-                        sink.send_all(
-                            stream.map(move |b| authority.read().unwrap().process_message(b)),
-                        ).map(|_| ())
-                        .map_err(|e| error!("error in TCP server: {:?}", e)),
-                    );
+    // Reload the TLS certificate on SIGHUP
+    if context.tls.is_some() {
+        let context = context.clone();
+        handlers.push(Box::new(
+            Signal::new(SIGHUP)
+                .flatten_stream()
+                .for_each(move |_| {
+                    info!("received SIGHUP, reloading TLS certificate");
+                    if let Some(Err(err)) = context.reload_tls() {
+                        error!("failed to reload TLS certificate: {}", err);
+                    }
                     Ok(())
-                }).map_err(|e| error!("error in TCP server: {:?}", e))
-        }),
-        // UDP server
-        Box::new({
-            let authority = authority.clone();
-            let (sink, stream) = UdpFramed::new(udp_socket, DnsCodec::udp()).split();
-            sink.send_all(
-                stream.map(move |(b, addr)| (authority.read().unwrap().process_message(b), addr)),
-            ).map(|_| ())
-            .map_err(|e| error!("error in UDP server: {:?}", e))
-        }),
-        // Control server
-        Box::new({
-            ctl_listener
-                .incoming()
-                .for_each(move |stream| {
-                    let authority = authority.clone();
-                    let (sink, stream) = JsonCodec::default().framed(stream).split();
-                    tokio::spawn(
-                        sink.send_all(
-                            stream.map(move |request| ctl::handle_request(request, &authority)),
-                        ).map(|_| ())
-                        .map_err(|e| error!("error in control server: {:?}", e)),
-                    );
+                }).map_err(|e| error!("error handling SIGHUP: {:?}", e)),
+        ));
+    }
+
+    // Reopen the query log on SIGUSR1
+    if context.query_log.is_some() {
+        let context = context.clone();
+        handlers.push(Box::new(
+            Signal::new(SIGUSR1)
+                .flatten_stream()
+                .for_each(move |_| {
+                    info!("received SIGUSR1, reopening query log");
+                    if let Some(Err(err)) = context.reopen_query_log() {
+                        error!("failed to reopen query log: {}", err);
+                    }
                     Ok(())
-                }).map_err(|e| error!("error in control server: {:?}", e))
-        }),
-    ];
+                }).map_err(|e| error!("error handling SIGUSR1: {:?}", e)),
+        ));
+    }
 
-    if let Err(shutdown) = Safeword::default().run(future::select_all(futs)) {
-        match shutdown {
-            Shutdown::FutureFinished(_) | Shutdown::FutureErr(_) => bail!("unexpected shutdown!"),
-            Shutdown::NoRuntime(err) | Shutdown::SignalError(err) => Err(failure::Error::from(err)),
-        }
+    // Upgrade on SIGUSR2 or a control request, unless the new process could not start
+    let upgraded: upgrade::Upgraded = if config.privileges.chroot.is_some() {
+        info!("upgrades are disabled, as the binary cannot be found from inside the chroot");
+        Box::new(future::empty())
+    } else if config.seccomp.is_some() {
+        info!("upgrades are disabled, as the new process would inherit the seccomp filter");
+        Box::new(future::empty())
     } else {
-        fs::remove_file(ctl_socket_path)
-            .with_context(|_| format!("failed to remove control socket {}", ctl_socket_path))?;
-        Ok(())
+        match binary {
+            Ok(binary) => upgrade::run(
+                context.upgrade.enable(),
+                binary,
+                env::args_os().skip(1).collect(),
+                handover,
+                context.clone(),
+            ),
+            Err(err) => {
+                warn!("upgrades are disabled, as the binary cannot be found: {}", err);
+                Box::new(future::empty())
+            }
+        }
+    };
+    {
+        let context = context.clone();
+        handlers.push(Box::new(
+            Signal::new(SIGUSR2)
+                .flatten_stream()
+                .for_each(move |_| {
+                    info!("received SIGUSR2, upgrading");
+                    if let Err(err) = context.upgrade.request() {
+                        error!("cannot upgrade: {}", err);
+                    }
+                    Ok(())
+                }).map_err(|e| error!("error handling SIGUSR2: {:?}", e)),
+        ));
+    }
+
+    // Stop on SIGINT or SIGTERM, or once a new process has taken over. The PID of the new process
+    // is returned, if there is one.
+    let shutdown = interrupted()
+        .map(|()| None)
+        .select(upgraded.map(Some).map_err(|()| unreachable!()))
+        .map(|(upgraded, _)| upgraded)
+        .map_err(|(err, _)| err);
+
+    // Control server
+    let control = Arc::new(config.control);
+    servers.push(server::restarting(String::from("control server"), {
+        let context = context.clone();
+        move || {
+            let listener = UnixListener::from_std(ctl_listener.try_clone()?, &Handle::default())?;
+            Ok(control_server(listener, control.clone(), context.clone()))
+        }
+    }));
+
+    // Everything is set up, so from here on only the system calls nsd needs are allowed
+    if let Some(ref seccomp) = config.seccomp {
+        let filter = Filter::new(seccomp.action)?;
+        filter.install().context("failed to install seccomp filter")?;
+        info!(
+            "installed seccomp filter allowing {} system calls, others {}",
+            filter.allowed(),
+            match seccomp.action {
+                SeccompAction::Kill => "kill the process",
+                SeccompAction::Log => "are logged",
+            }
+        );
+    }
+
+    // After an upgrade, the service manager is told that we are the main process now
+    let ready = match parent {
+        Some(_) => format!("MAINPID={}\nREADY=1", process::id()),
+        None => String::from("READY=1"),
+    };
+    if let Err(err) = systemd::notify(&ready) {
+        warn!("failed to notify service manager of readiness: {}", err);
+    }
+    if let Some(parent) = parent {
+        match parent.ready() {
+            Ok(()) => info!("taking over from the upgrading process"),
+            Err(err) => warn!("failed to tell the upgrading process we are ready: {}", err),
+        }
+    }
+
+    let stopping = || {
+        if let Err(err) = systemd::notify("STOPPING=1") {
+            warn!("failed to notify service manager of shutdown: {}", err);
+        }
+    };
+    let mut runtime = Runtime::new()?;
+    for handler in handlers {
+        runtime.spawn(handler);
+    }
+    let running = server::spawn(servers, &runtime.executor());
+    let upgraded = match runtime.block_on(shutdown) {
+        Ok(upgraded) => upgraded,
+        Err(err) => {
+            stopping();
+            return Err(err.into());
+        }
+    };
+    // Stop accepting connections and control requests
+    drop(running);
+    match upgraded {
+        Some(pid) => info!("process {} has taken over, shutting down", pid),
+        None => {
+            info!("shutting down");
+            stopping();
+        }
+    }
+
+    // Event subscribers are told, and given a moment to disconnect
+    context.events.shutdown();
+    let events = context.clone();
+    if !wait_until(&mut runtime, SHUTDOWN_TIMEOUT, move || events.events.subscribers() == 0) {
+        warn!(
+            "closing {} event subscriptions still open",
+            context.events.subscribers()
+        );
+    }
+
+    // Connections still open are closed as soon as they are idle, but may finish answering the
+    // queries they have been sent first
+    context.tcp.drain();
+    let open = context.tcp.open();
+    if open > 0 {
+        info!(
+            "waiting up to {}s for {} TCP connections to finish",
+            context.tcp.drain_timeout.as_secs(),
+            open
+        );
+        let tcp = context.tcp.clone();
+        if !wait_until(&mut runtime, context.tcp.drain_timeout, move || tcp.open() == 0) {
+            warn!("closing {} TCP connections still open", context.tcp.open());
+        }
+    }
+
+    match upgraded {
+        Some(pid) => info!("exiting, process {} has taken over", pid),
+        None => {
+            if ctl_socket_owned {
+                fs::remove_file(&ctl_socket_path).with_context(|_| {
+                    format!(
+                        "failed to remove control socket {}",
+                        ctl_socket_path.display()
+                    )
+                })?;
+            }
+            info!("exiting");
+        }
+    }
+    Ok(())
+}
+
+/// Completes on SIGINT or SIGTERM.
+fn interrupted() -> Box<Future<Item = (), Error = io::Error> + Send> {
+    Box::new(
+        Signal::new(SIGINT)
+            .flatten_stream()
+            .select(Signal::new(SIGTERM).flatten_stream())
+            .into_future()
+            .map(|_| ())
+            .map_err(|(err, _)| err),
+    )
+}
+
+/// Runs until `done` returns true, checking every `SHUTDOWN_POLL`. Returns false if `timeout`
+/// elapses first, or if SIGINT or SIGTERM is received to stop waiting.
+fn wait_until<F>(runtime: &mut Runtime, timeout: Duration, mut done: F) -> bool
+where
+    F: FnMut() -> bool + Send + 'static,
+{
+    let poll = Interval::new(Instant::now(), SHUTDOWN_POLL)
+        .take_while(move |_| Ok(!done()))
+        .for_each(|_| Ok(()));
+    match runtime.block_on(Timeout::new(poll, timeout).select2(interrupted())) {
+        Ok(Either::A(_)) => true,
+        Ok(Either::B(_)) => {
+            info!("received another signal, not waiting");
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Serves control requests on a Unix socket, spawning a task for each connection.
+fn control_server(
+    listener: UnixListener,
+    control: Arc<ControlConfig>,
+    context: Arc<Context>,
+) -> ServerFuture {
+    Box::new(
+        listener
+            .incoming()
+            .for_each(move |stream| {
+                let caller = match stream.peer_cred() {
+                    Ok(cred) => ctl::Caller {
+                        uid: cred.uid,
+                        gid: cred.gid,
+                        access: control.access(cred.uid, cred.gid),
+                    },
+                    Err(err) => {
+                        error!("failed to get control socket peer credentials: {}", err);
+                        return Ok(());
+                    }
+                };
+                let context = context.clone();
+                let (sink, stream) = JsonCodec::default().framed(stream).split();
+                tokio::spawn(
+                    sink.send_all(
+                        stream
+                            .map_err(io::Error::from)
+                            .and_then(move |request| {
+                                // Requests can take locks and write zone files, so they are
+                                // handled where blocking does not hold up other tasks
+                                let context = context.clone();
+                                let mut request = Some(request);
+                                future::poll_fn(move || {
+                                    tokio_threadpool::blocking(|| {
+                                        let request = request.take().expect("polled after ready");
+                                        ctl::handle_stream(request, &caller, &context)
+                                    }).map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+                                })
+                            }).flatten(),
+                    ).map(|_| ())
+                    .map_err(|e| match e {
+                        // An event subscriber that has gone away is only noticed on the next event
+                        JsonError::Io(ref e)
+                            if e.kind() == io::ErrorKind::BrokenPipe
+                                || e.kind() == io::ErrorKind::ConnectionReset =>
+                        {
+                            debug!("control client disconnected: {}", e)
+                        }
+                        e => error!("error in control connection: {:?}", e),
+                    }),
+                );
+                Ok(())
+            }).map_err(|e| error!("error in control server: {:?}", e)),
+    )
+}
+
+/// Gives ownership of a file to a group, by name.
+fn set_group(path: &Path, group: &str) -> Result<(), failure::Error> {
+    let gid = match users::get_group_by_name(group) {
+        Some(group) => group.gid(),
+        None => bail!("no such group {}", group),
+    };
+    // A UID of -1 leaves the owner unchanged
+    chown(path, !0, gid)
+}
+
+fn chown(path: &Path, uid: libc::uid_t, gid: libc::gid_t) -> Result<(), failure::Error> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::chown(path.as_ptr(), uid, gid) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
     }
+    Ok(())
 }
@@ -0,0 +1,549 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Counters and a latency histogram for the DNS servers, served over HTTP in the Prometheus text
+//! exposition format.
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use pepbut::authority::Authority;
+use pepbut::name::Name;
+use pepbut::record;
+use pepbut::wire::{rcode_name, ResponseSummary};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::{Future, Stream};
+
+use config::Transport;
+use server::{Context, ServerFuture};
+use tcp::Incoming;
+
+/// The path metrics are served at.
+pub static PATH: &str = "/metrics";
+static CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Upper bounds of the query processing time histogram buckets, in seconds.
+static DURATION_BUCKETS: &[f64] = &[
+    0.000_01, 0.000_025, 0.000_05, 0.000_1, 0.000_25, 0.000_5, 0.001, 0.002_5, 0.005, 0.01,
+];
+
+/// Query types given their own label value. Anything else is counted as `other`, so that clients
+/// cannot create an unbounded number of series.
+static QUERY_TYPES: &[u16] = &[
+    1, 2, 5, 6, 12, 15, 16, 28, 33, 35, 43, 48, 64, 65, 252, 255, 257,
+];
+
+static TRANSPORTS: &[Transport] = &[
+    Transport::Udp,
+    Transport::Tcp,
+    Transport::Tls,
+    Transport::Https,
+];
+
+/// The number of response codes that fit in the message header.
+const RCODES: usize = 16;
+
+/// Query and response counts for every DNS server. Counters are separate atomics, so a scrape can
+/// see some of them a moment later than others.
+#[derive(Debug)]
+pub struct Metrics {
+    /// Queries answered from each zone. The lock is only taken for writing the first time a zone
+    /// answers a query.
+    zones: RwLock<HashMap<Name, QueryCounts>>,
+    /// Queries for names outside every zone.
+    unzoned: QueryCounts,
+    /// Indexed like `TRANSPORTS`.
+    decode_errors: Vec<AtomicUsize>,
+    /// Indexed like `TRANSPORTS`.
+    truncated: Vec<AtomicUsize>,
+    rate_limited: AtomicUsize,
+    dnstap_dropped: AtomicUsize,
+    query_log_dropped: AtomicUsize,
+    zone_loads: AtomicUsize,
+    zone_load_failures: AtomicUsize,
+    /// Non-cumulative counts for each of `DURATION_BUCKETS`, then the count above the last one.
+    duration_buckets: Vec<AtomicUsize>,
+    /// The total time taken to answer queries, in nanoseconds.
+    duration_sum: AtomicUsize,
+}
+
+/// Counts of answered queries by transport, query type, and response code.
+#[derive(Debug)]
+struct QueryCounts(Vec<AtomicUsize>);
+
+impl QueryCounts {
+    fn new() -> QueryCounts {
+        QueryCounts(counters(TRANSPORTS.len() * (QUERY_TYPES.len() + 1) * RCODES))
+    }
+
+    fn add(&self, transport: Transport, qtype: u16, rcode: u8) {
+        let qtype = QUERY_TYPES
+            .iter()
+            .position(|&t| t == qtype)
+            .unwrap_or(QUERY_TYPES.len());
+        let i = (transport_index(transport) * (QUERY_TYPES.len() + 1) + qtype) * RCODES
+            + usize::from(rcode) % RCODES;
+        self.0[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns each non-zero count with its transport, query type (`None` for `other`), and
+    /// response code.
+    fn counts(&self) -> Vec<(Transport, Option<u16>, u8, usize)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, count)| match count.load(Ordering::Relaxed) {
+                0 => None,
+                count => Some((
+                    TRANSPORTS[i / RCODES / (QUERY_TYPES.len() + 1)],
+                    QUERY_TYPES.get(i / RCODES % (QUERY_TYPES.len() + 1)).cloned(),
+                    (i % RCODES) as u8,
+                    count,
+                )),
+            }).collect()
+    }
+}
+
+fn counters(n: usize) -> Vec<AtomicUsize> {
+    (0..n).map(|_| AtomicUsize::new(0)).collect()
+}
+
+fn transport_index(transport: Transport) -> usize {
+    match transport {
+        Transport::Udp => 0,
+        Transport::Tcp => 1,
+        Transport::Tls => 2,
+        Transport::Https => 3,
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics {
+            zones: RwLock::new(HashMap::new()),
+            unzoned: QueryCounts::new(),
+            decode_errors: counters(TRANSPORTS.len()),
+            truncated: counters(TRANSPORTS.len()),
+            rate_limited: AtomicUsize::new(0),
+            dnstap_dropped: AtomicUsize::new(0),
+            query_log_dropped: AtomicUsize::new(0),
+            zone_loads: AtomicUsize::new(0),
+            zone_load_failures: AtomicUsize::new(0),
+            duration_buckets: counters(DURATION_BUCKETS.len() + 1),
+            duration_sum: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Counts a response, and how long it took to produce. `zone` is the origin of the zone that
+    /// answered it.
+    ///
+    /// Responses without a question section are counted as decode errors, as those are only sent
+    /// for queries that could not be decoded.
+    pub fn record_response(
+        &self,
+        transport: Transport,
+        response: &ResponseSummary,
+        zone: Option<&Name>,
+        elapsed: Duration,
+    ) {
+        match (&response.question, zone) {
+            (Some((_, qtype)), Some(zone)) => {
+                let zones = self
+                    .zones
+                    .read()
+                    .unwrap_or_else(|_| fatal!("metrics are poisoned"));
+                match zones.get(zone) {
+                    Some(counts) => counts.add(transport, *qtype, response.rcode),
+                    None => {
+                        drop(zones);
+                        self.zones
+                            .write()
+                            .unwrap_or_else(|_| fatal!("metrics are poisoned"))
+                            .entry(zone.clone())
+                            .or_insert_with(QueryCounts::new)
+                            .add(transport, *qtype, response.rcode);
+                    }
+                }
+            }
+            (Some((_, qtype)), None) => self.unzoned.add(transport, *qtype, response.rcode),
+            (None, _) => {
+                self.decode_errors[transport_index(transport)].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if response.truncated {
+            self.truncated[transport_index(transport)].fetch_add(1, Ordering::Relaxed);
+        }
+
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.duration_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = elapsed.as_secs() as usize * 1_000_000_000 + elapsed.subsec_nanos() as usize;
+        self.duration_sum.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// Counts a UDP query dropped by the rate limiter.
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a query dropped from dnstap output because the queue was full.
+    pub fn record_dnstap_dropped(&self) {
+        self.dnstap_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a query left out of the query log because the queue was full.
+    pub fn record_query_log_dropped(&self) {
+        self.query_log_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a zone load, whether or not it succeeded.
+    pub fn record_zone_load(&self, success: bool) {
+        if success {
+            self.zone_loads.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.zone_load_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format. The zone and record gauges
+    /// are read from the authority.
+    pub fn render(&self, authority: &Authority) -> String {
+        let mut out = String::new();
+        self.write(&mut out, authority).expect("cannot fail");
+        out
+    }
+
+    fn write(&self, out: &mut String, authority: &Authority) -> fmt::Result {
+        header(
+            out,
+            "queries_total",
+            "counter",
+            "DNS queries answered, by transport, query type, response code, and zone.",
+        )?;
+        let zones = self
+            .zones
+            .read()
+            .unwrap_or_else(|_| fatal!("metrics are poisoned"));
+        let mut queries = Vec::new();
+        for (zone, counts) in zones
+            .iter()
+            .map(|(zone, counts)| (escape(&zone.to_string()), counts))
+            .chain(Some((String::new(), &self.unzoned)))
+        {
+            for (transport, qtype, rcode, count) in counts.counts() {
+                queries.push(format!(
+                    "pepbut_nsd_queries_total{{transport=\"{}\",qtype=\"{}\",rcode=\"{}\",\
+                     zone=\"{}\"}} {}",
+                    transport,
+                    qtype.and_then(record::type_name).unwrap_or("other"),
+                    rcode_name(rcode),
+                    zone,
+                    count
+                ));
+            }
+        }
+        queries.sort();
+        for line in queries {
+            writeln!(out, "{}", line)?;
+        }
+
+        header(
+            out,
+            "decode_errors_total",
+            "counter",
+            "Queries that could not be decoded, by transport.",
+        )?;
+        write_by_transport(out, "decode_errors_total", &self.decode_errors)?;
+
+        header(
+            out,
+            "truncated_responses_total",
+            "counter",
+            "Responses sent with the TC bit set, by transport.",
+        )?;
+        write_by_transport(out, "truncated_responses_total", &self.truncated)?;
+
+        header(
+            out,
+            "rate_limited_total",
+            "counter",
+            "UDP queries dropped by the rate limiter.",
+        )?;
+        writeln!(out, "pepbut_nsd_rate_limited_total {}", load(&self.rate_limited))?;
+
+        header(
+            out,
+            "dnstap_dropped_total",
+            "counter",
+            "Queries left out of dnstap output because the queue was full.",
+        )?;
+        writeln!(out, "pepbut_nsd_dnstap_dropped_total {}", load(&self.dnstap_dropped))?;
+
+        header(
+            out,
+            "query_log_dropped_total",
+            "counter",
+            "Sampled queries left out of the query log because the queue was full.",
+        )?;
+        writeln!(
+            out,
+            "pepbut_nsd_query_log_dropped_total {}",
+            load(&self.query_log_dropped)
+        )?;
+
+        header(
+            out,
+            "zone_loads_total",
+            "counter",
+            "Zone loads, by whether they succeeded.",
+        )?;
+        writeln!(
+            out,
+            "pepbut_nsd_zone_loads_total{{result=\"success\"}} {}",
+            load(&self.zone_loads)
+        )?;
+        writeln!(
+            out,
+            "pepbut_nsd_zone_loads_total{{result=\"failure\"}} {}",
+            load(&self.zone_load_failures)
+        )?;
+
+        header(
+            out,
+            "query_duration_seconds",
+            "histogram",
+            "Time taken to answer queries, once decoded from the transport.",
+        )?;
+        let mut cumulative = 0;
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            cumulative += load(&self.duration_buckets[i]);
+            writeln!(
+                out,
+                "pepbut_nsd_query_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            )?;
+        }
+        cumulative += load(&self.duration_buckets[DURATION_BUCKETS.len()]);
+        writeln!(
+            out,
+            "pepbut_nsd_query_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            cumulative
+        )?;
+        writeln!(
+            out,
+            "pepbut_nsd_query_duration_seconds_sum {}",
+            load(&self.duration_sum) as f64 / 1e9
+        )?;
+        writeln!(out, "pepbut_nsd_query_duration_seconds_count {}", cumulative)?;
+
+        header(out, "zones", "gauge", "Zones currently loaded.")?;
+        writeln!(out, "pepbut_nsd_zones {}", authority.zones.len())?;
+        header(out, "records", "gauge", "Records in all loaded zones.")?;
+        writeln!(
+            out,
+            "pepbut_nsd_records {}",
+            authority.zones.values().map(|zone| zone.len()).sum::<usize>()
+        )
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(out, "# HELP pepbut_nsd_{} {}", name, help)?;
+    writeln!(out, "# TYPE pepbut_nsd_{} {}", name, kind)
+}
+
+fn write_by_transport(out: &mut String, name: &str, counts: &[AtomicUsize]) -> fmt::Result {
+    for (transport, count) in TRANSPORTS.iter().zip(counts) {
+        let count = load(count);
+        if count > 0 {
+            writeln!(
+                out,
+                "pepbut_nsd_{}{{transport=\"{}\"}} {}",
+                name, transport, count
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn load(count: &AtomicUsize) -> u64 {
+    count.load(Ordering::Relaxed) as u64
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves metrics over HTTP, spawning a task for each connection.
+pub fn serve(listener: TcpListener, context: Arc<Context>) -> ServerFuture {
+    Box::new(
+        Incoming::new(listener)
+            .for_each(move |(tcp, peer)| {
+                let context = context.clone();
+                tokio::spawn(
+                    Http::new()
+                        .http1_only(true)
+                        .serve_connection(
+                            tcp,
+                            service_fn(move |request| {
+                                Ok::<_, hyper::Error>(handle(&request, &context))
+                            }),
+                        ).map_err(move |e| {
+                            info!("error in metrics connection from {}: {}", peer, e)
+                        }),
+                );
+                Ok(())
+            }).map_err(|e| error!("error in metrics server: {:?}", e)),
+    )
+}
+
+fn handle(request: &Request<Body>, context: &Context) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    if request.uri().path() != PATH {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    } else if *request.method() != Method::GET {
+        *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_TEXT));
+        *response.body_mut() = Body::from(
+            context.metrics.render(
+                &context
+                    .authority
+                    .read()
+                    .unwrap_or_else(|_| fatal!("authority is poisoned")),
+            ),
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pepbut::authority::{Authority, Channel};
+    use pepbut::name::Name;
+    use pepbut::record::{RData, Record};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    use config::Transport;
+    use metrics::{escape, Metrics};
+
+    #[test]
+    fn render() {
+        let mut authority = Authority::new();
+        authority
+            .load_zonefile(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/../tests/data/example.invalid.zone"
+            )).unwrap();
+        let metrics = Metrics::new();
+        let query = Bytes::from_static(&[
+            0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77,
+            0x77, 0x77, 0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x07, 0x69, 0x6e, 0x76,
+            0x61, 0x6c, 0x69, 0x64, 0x00, 0x00, 0x01, 0x00, 0x01,
+        ]);
+        for &elapsed in &[Duration::from_micros(30), Duration::from_millis(2)] {
+            let answered = authority.answer(query.clone(), Channel::Datagram).unwrap();
+            metrics.record_response(
+                Transport::Udp,
+                &answered.summary,
+                answered.zone.map(|zone| &zone.origin),
+                elapsed,
+            );
+        }
+        let garbage = authority
+            .answer(
+                Bytes::from_static(&[
+                    0x12, 0x34, 0x80, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ]),
+                Channel::Stream(100),
+            ).unwrap();
+        assert!(garbage.zone.is_none());
+        metrics.record_response(
+            Transport::Tcp,
+            &garbage.summary,
+            None,
+            Duration::from_secs(1),
+        );
+        // A name outside every zone is refused
+        let mut outside = query.to_vec();
+        outside[20] = b'X';
+        let refused = authority.answer(Bytes::from(outside), Channel::Datagram).unwrap();
+        metrics.record_response(
+            Transport::Tls,
+            &refused.summary,
+            refused.zone.map(|zone| &zone.origin),
+            Duration::from_micros(30),
+        );
+        // Too many answers to fit in a UDP response without EDNS
+        let www = Name::from_str("www.example.invalid").unwrap();
+        let records = (0..40)
+            .map(|i| Record::new(www.clone(), 60, RData::A([198, 51, 100, i].into())))
+            .collect::<Vec<_>>();
+        let origin = Name::from_str("example.invalid").unwrap();
+        authority.update_zone(&origin, &[], &records, false).unwrap();
+        let truncated = authority.answer(query.clone(), Channel::Datagram).unwrap();
+        metrics.record_response(
+            Transport::Udp,
+            &truncated.summary,
+            truncated.zone.map(|zone| &zone.origin),
+            Duration::from_micros(30),
+        );
+        metrics.record_rate_limited();
+        metrics.record_zone_load(true);
+
+        let rendered = metrics.render(&authority);
+        for line in &[
+            "pepbut_nsd_queries_total{transport=\"udp\",qtype=\"A\",rcode=\"NOERROR\",\
+             zone=\"example.invalid\"} 3",
+            "pepbut_nsd_queries_total{transport=\"tls\",qtype=\"A\",rcode=\"REFUSED\",zone=\"\"} 1",
+            "pepbut_nsd_decode_errors_total{transport=\"tcp\"} 1",
+            "pepbut_nsd_truncated_responses_total{transport=\"udp\"} 1",
+            "pepbut_nsd_rate_limited_total 1",
+            "pepbut_nsd_zone_loads_total{result=\"success\"} 1",
+            "pepbut_nsd_zone_loads_total{result=\"failure\"} 0",
+            "pepbut_nsd_query_duration_seconds_bucket{le=\"0.00001\"} 0",
+            "pepbut_nsd_query_duration_seconds_bucket{le=\"0.00005\"} 3",
+            "pepbut_nsd_query_duration_seconds_bucket{le=\"0.0025\"} 4",
+            "pepbut_nsd_query_duration_seconds_bucket{le=\"+Inf\"} 5",
+            "pepbut_nsd_query_duration_seconds_sum 1.00209",
+            "pepbut_nsd_query_duration_seconds_count 5",
+            "pepbut_nsd_zones 1",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == *line),
+                "{:?} not in:\n{}",
+                line,
+                rendered
+            );
+        }
+        assert!(!rendered.contains("truncated_responses_total{transport=\"tcp\""));
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Dropping root privileges once every socket has been bound.
+
+use failure::{self, ResultExt};
+use libc;
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use users;
+
+use config::PrivilegesConfig;
+
+/// The user and group to switch to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl Credentials {
+    /// Looks up the configured user and group. Returns `None` if no user is configured.
+    pub fn from_config(config: &PrivilegesConfig) -> Result<Option<Credentials>, failure::Error> {
+        let user = match config.user {
+            Some(ref name) => match users::get_user_by_name(name) {
+                Some(user) => user,
+                None => bail!("no such user {}", name),
+            },
+            None => return Ok(None),
+        };
+        if user.uid() == 0 {
+            bail!("privileges.user must not be root");
+        }
+        let gid = match config.group {
+            Some(ref name) => match users::get_group_by_name(name) {
+                Some(group) => group.gid(),
+                None => bail!("no such group {}", name),
+            },
+            None => user.primary_group_id(),
+        };
+        if gid == 0 {
+            bail!("privileges.group must not be root");
+        }
+        Ok(Some(Credentials {
+            uid: user.uid(),
+            gid,
+        }))
+    }
+}
+
+/// Switches to the given user and group, dropping every supplementary group, after changing the
+/// root directory to `chroot` if it is set. Fails if root privileges can be regained afterwards.
+pub fn drop_privileges(
+    credentials: Credentials,
+    chroot: Option<&Path>,
+) -> Result<(), failure::Error> {
+    if let Some(dir) = chroot {
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        check(unsafe { libc::chroot(path.as_ptr()) })
+            .with_context(|e| format!("failed to chroot to {}: {}", dir.display(), e))?;
+        env::set_current_dir("/").context("failed to change directory after chroot")?;
+    }
+    // The group must be changed while we are still allowed to
+    check(unsafe { libc::setgroups(1, &credentials.gid) })
+        .context("failed to drop supplementary groups")?;
+    check(unsafe { libc::setgid(credentials.gid) }).context("failed to set group ID")?;
+    check(unsafe { libc::setuid(credentials.uid) }).context("failed to set user ID")?;
+
+    let (uid, euid, gid, egid) =
+        unsafe { (libc::getuid(), libc::geteuid(), libc::getgid(), libc::getegid()) };
+    if (uid, euid) != (credentials.uid, credentials.uid)
+        || (gid, egid) != (credentials.gid, credentials.gid)
+    {
+        bail!(
+            "privileges were not dropped: uid {}, euid {}, gid {}, egid {}",
+            uid,
+            euid,
+            gid,
+            egid
+        );
+    }
+    if unsafe { libc::setuid(0) } == 0 || unsafe { libc::setgid(0) } == 0 {
+        bail!("root privileges can be regained after dropping them");
+    }
+    Ok(())
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::PrivilegesConfig;
+    use privileges::Credentials;
+
+    #[test]
+    fn credentials() {
+        let config = |user: &str| PrivilegesConfig {
+            user: Some(String::from(user)),
+            group: None,
+            chroot: None,
+        };
+        assert_eq!(
+            Credentials::from_config(&PrivilegesConfig::default()).unwrap(),
+            None
+        );
+        assert!(Credentials::from_config(&config("root")).is_err());
+        assert!(Credentials::from_config(&config("no-such-user-for-pepbut")).is_err());
+    }
+}
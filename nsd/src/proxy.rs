@@ -0,0 +1,375 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The [PROXY protocol](https://www.haproxy.org/download/1.8/doc/proxy-protocol.txt), versions 1
+//! and 2, which load balancers use to pass on the address of the client they are proxying for.
+//!
+//! Only connections from trusted proxies are expected to begin with a PROXY header; connections
+//! from trusted proxies without one are closed.
+
+use serde::de::{self, Deserialize, Deserializer};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::{self, FromStr};
+use tokio::prelude::{Async, AsyncRead, Future, Poll};
+
+/// The signature that begins a version 2 header.
+static V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+/// The longest version 2 header we accept. Headers may carry TLVs we ignore, but nothing we
+/// expect to see is anywhere near this long.
+const V2_MAX_LEN: usize = 4096;
+
+/// An IP network in CIDR notation, such as `192.0.2.0/24`. A bare address is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns true if the address is in this network. IPv4-mapped IPv6 addresses, as seen by
+    /// sockets listening on `[::]`, are treated as IPv4 addresses.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.to_ipv4() {
+                Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(v4),
+                _ => *addr,
+            },
+            IpAddr::V4(_) => *addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(&net.octets(), &addr.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], addr: &[u8], prefix_len: u8) -> bool {
+    let bytes = usize::from(prefix_len / 8);
+    let bits = prefix_len % 8;
+    net[..bytes] == addr[..bytes]
+        && (bits == 0 || (net[bytes] ^ addr[bytes]) & (0xff << (8 - bits)) == 0)
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let invalid = || format!("invalid CIDR network: {}", s);
+        let mut parts = s.splitn(2, '/');
+        let addr = IpAddr::from_str(parts.next().unwrap_or("")).map_err(|_| invalid())?;
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match parts.next() {
+            Some(len) => u8::from_str(len).map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Cidr, D::Error> {
+        Cidr::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// What a PROXY header says about the connection.
+#[derive(Debug, PartialEq)]
+enum Header {
+    /// The connection is proxied for a client at this address.
+    Proxied(SocketAddr),
+    /// The connection is from the proxy itself (such as a health check), or the proxy does not
+    /// know the client's address.
+    Local,
+}
+
+/// How far along parsing a header is.
+#[derive(Debug, PartialEq)]
+enum Progress {
+    /// At least this many bytes of the header are needed to continue.
+    Need(usize),
+    Done(Header),
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY header: {}", msg),
+    )
+}
+
+/// Parses as much of a header as has been read. `buf` never holds more than the header, so that
+/// no data following it is consumed.
+fn parse(buf: &[u8]) -> io::Result<Progress> {
+    if buf.len() < 6 {
+        return Ok(Progress::Need(6));
+    }
+    if &buf[..6] == b"PROXY " {
+        if !buf.ends_with(b"\r\n") {
+            return if buf.len() >= V1_MAX_LEN {
+                Err(invalid("version 1 header too long"))
+            } else {
+                Ok(Progress::Need(buf.len() + 1))
+            };
+        }
+        return parse_v1(&buf[6..buf.len() - 2]).map(Progress::Done);
+    }
+    if buf[..6] != V2_SIGNATURE[..6] {
+        return Err(invalid("missing signature"));
+    }
+    if buf.len() < 16 {
+        return Ok(Progress::Need(16));
+    }
+    if buf[..12] != *V2_SIGNATURE || buf[12] >> 4 != 2 {
+        return Err(invalid("missing signature"));
+    }
+    let len = 16 + ((usize::from(buf[14]) << 8) | usize::from(buf[15]));
+    if len > V2_MAX_LEN {
+        return Err(invalid("version 2 header too long"));
+    }
+    if buf.len() < len {
+        return Ok(Progress::Need(len));
+    }
+    parse_v2(buf[12] & 0xf, buf[13], &buf[16..]).map(Progress::Done)
+}
+
+/// Parses the fields of a version 1 header, such as `TCP4 192.0.2.1 192.0.2.2 56324 53`.
+fn parse_v1(line: &[u8]) -> io::Result<Header> {
+    let line = str::from_utf8(line).map_err(|_| invalid("not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[0] {
+        "UNKNOWN" => Ok(Header::Local),
+        "TCP4" | "TCP6" if fields.len() == 5 => {
+            let ip = IpAddr::from_str(fields[1]).map_err(|_| invalid("bad source address"))?;
+            let port = u16::from_str(fields[3]).map_err(|_| invalid("bad source port"))?;
+            match (fields[0], ip) {
+                ("TCP4", IpAddr::V4(_)) | ("TCP6", IpAddr::V6(_)) => {
+                    Ok(Header::Proxied(SocketAddr::new(ip, port)))
+                }
+                _ => Err(invalid("address does not match protocol")),
+            }
+        }
+        _ => Err(invalid("unsupported protocol")),
+    }
+}
+
+/// Parses the addresses of a version 2 header.
+fn parse_v2(command: u8, family: u8, addrs: &[u8]) -> io::Result<Header> {
+    match command {
+        // LOCAL
+        0x0 => return Ok(Header::Local),
+        // PROXY
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+    let port = |b: &[u8]| (u16::from(b[0]) << 8) | u16::from(b[1]);
+    match family {
+        // TCP over IPv4: source address, destination address, source port, destination port
+        0x11 if addrs.len() >= 12 => {
+            let mut ip = [0; 4];
+            ip.copy_from_slice(&addrs[..4]);
+            Ok(Header::Proxied(SocketAddr::new(
+                Ipv4Addr::from(ip).into(),
+                port(&addrs[8..10]),
+            )))
+        }
+        // TCP over IPv6
+        0x21 if addrs.len() >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            Ok(Header::Proxied(SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                port(&addrs[32..34]),
+            )))
+        }
+        0x11 | 0x21 => Err(invalid("address block too short")),
+        // Unspecified, UDP, or Unix sockets, which tell us nothing useful
+        _ => Ok(Header::Local),
+    }
+}
+
+/// A future that reads a PROXY header from a connection, resolving to the connection and the
+/// address of the client it was proxied for.
+pub struct ReadHeader<S> {
+    io: Option<S>,
+    peer: SocketAddr,
+    buf: Vec<u8>,
+}
+
+impl<S: AsyncRead> ReadHeader<S> {
+    /// `peer` is the address of the proxy, which is used if the header does not give a client
+    /// address.
+    pub fn new(io: S, peer: SocketAddr) -> ReadHeader<S> {
+        ReadHeader {
+            io: Some(io),
+            peer,
+            buf: Vec::with_capacity(16),
+        }
+    }
+}
+
+impl<S: AsyncRead> Future for ReadHeader<S> {
+    type Item = (S, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(S, SocketAddr), io::Error> {
+        loop {
+            let need = match parse(&self.buf)? {
+                Progress::Need(need) => need,
+                Progress::Done(header) => {
+                    let addr = match header {
+                        Header::Proxied(addr) => addr,
+                        Header::Local => self.peer,
+                    };
+                    let io = self.io.take().expect("ReadHeader polled after completion");
+                    return Ok(Async::Ready((io, addr)));
+                }
+            };
+            let start = self.buf.len();
+            self.buf.resize(need, 0);
+            let result = self
+                .io
+                .as_mut()
+                .expect("ReadHeader polled after completion")
+                .poll_read(&mut self.buf[start..]);
+            match result {
+                Ok(Async::Ready(0)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed before PROXY header",
+                    ))
+                }
+                Ok(Async::Ready(n)) => self.buf.truncate(start + n),
+                Ok(Async::NotReady) => {
+                    self.buf.truncate(start);
+                    return Ok(Async::NotReady);
+                }
+                Err(err) => {
+                    self.buf.truncate(start);
+                    return Err(err);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use tokio::prelude::Future;
+
+    use super::{parse, Cidr, Header, Progress, ReadHeader};
+
+    #[test]
+    fn cidr() {
+        let net = Cidr::from_str("192.0.2.0/25").unwrap();
+        assert!(net.contains(&"192.0.2.127".parse().unwrap()));
+        assert!(!net.contains(&"192.0.2.128".parse().unwrap()));
+        assert!(net.contains(&"::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db8::1".parse().unwrap()));
+        let net = Cidr::from_str("2001:db8::1").unwrap();
+        assert!(net.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!net.contains(&"2001:db8::2".parse().unwrap()));
+        assert!(Cidr::from_str("0.0.0.0/0")
+            .unwrap()
+            .contains(&"198.51.100.1".parse().unwrap()));
+        assert!(Cidr::from_str("192.0.2.0/33").is_err());
+        assert!(Cidr::from_str("localhost/8").is_err());
+    }
+
+    fn done(buf: &[u8]) -> Header {
+        match parse(buf).unwrap() {
+            Progress::Done(header) => header,
+            progress => panic!("unexpected {:?}", progress),
+        }
+    }
+
+    #[test]
+    fn parse_v1() {
+        assert_eq!(
+            done(b"PROXY TCP4 192.0.2.1 192.0.2.53 56324 53\r\n"),
+            Header::Proxied("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(
+            done(b"PROXY TCP6 2001:db8::1 2001:db8::53 56324 853\r\n"),
+            Header::Proxied("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(done(b"PROXY UNKNOWN\r\n"), Header::Local);
+        assert_eq!(parse(b"PROX").unwrap(), Progress::Need(6));
+        assert_eq!(parse(b"PROXY TCP4 ").unwrap(), Progress::Need(12));
+        assert!(parse(b"PROXY TCP4 2001:db8::1 192.0.2.53 56324 53\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 192.0.2.53 56324 53\r\n").is_err());
+        let mut long = b"PROXY ".to_vec();
+        long.resize(107, b'x');
+        assert!(parse(&long).is_err());
+        assert!(parse(b"\x00\x25\x86\x2a\x01\x20").is_err());
+    }
+
+    #[test]
+    fn parse_v2() {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        assert_eq!(parse(&header[..12]).unwrap(), Progress::Need(16));
+        assert_eq!(parse(&header).unwrap(), Progress::Need(28));
+        header.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 53, 0xdc, 0x04, 0x00, 0x35]);
+        assert_eq!(
+            done(&header),
+            Header::Proxied("192.0.2.1:56324".parse().unwrap())
+        );
+
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        header.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        header.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x53]);
+        header.extend_from_slice(&[0xdc, 0x04, 0x03, 0x55]);
+        assert_eq!(
+            done(&header),
+            Header::Proxied("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        assert_eq!(done(b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00"), Header::Local);
+        assert!(parse(b"\r\n\r\n\0\r\nQUIT\n\x11\x11\x00\x0c").is_err());
+        assert!(parse(b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x04\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn read_header() {
+        let proxy: SocketAddr = "192.0.2.10:1234".parse().unwrap();
+        let mut data = b"PROXY TCP4 192.0.2.1 192.0.2.53 56324 53\r\n".to_vec();
+        let len = data.len();
+        data.extend_from_slice(b"\x00\x25\x86\x2a");
+        let (io, addr) = ReadHeader::new(Cursor::new(data), proxy).wait().unwrap();
+        assert_eq!(addr, "192.0.2.1:56324".parse().unwrap());
+        // Nothing after the header has been read
+        assert_eq!(io.position(), len as u64);
+
+        let (_, addr) = ReadHeader::new(Cursor::new(b"PROXY UNKNOWN\r\n".to_vec()), proxy)
+            .wait()
+            .unwrap();
+        assert_eq!(addr, proxy);
+        assert!(
+            ReadHeader::new(Cursor::new(b"PROXY TCP4".to_vec()), proxy)
+                .wait()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,292 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The query log, which records answered queries as newline-delimited JSON.
+
+use failure::{self, ResultExt};
+use humantime;
+use pepbut::record;
+use pepbut::wire::{rcode_name, ResponseSummary};
+use serde_json;
+use std::fs::OpenOptions;
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use config::{QueryLogConfig, Transport};
+
+/// A query log entry.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Entry {
+    timestamp: String,
+    client: SocketAddr,
+    transport: Transport,
+    /// The name and type queried for, if the query could be decoded.
+    qname: Option<String>,
+    qtype: Option<String>,
+    rcode: String,
+    answer: u16,
+    authority: u16,
+    additional: u16,
+    /// The size of the response in bytes.
+    size: usize,
+    /// Time taken to answer the query, in microseconds.
+    duration: u64,
+}
+
+/// An answered query, waiting to be written.
+struct Query {
+    time: SystemTime,
+    client: SocketAddr,
+    transport: Transport,
+    response: ResponseSummary,
+    size: usize,
+    elapsed: Duration,
+}
+
+impl Query {
+    fn to_line(&self) -> Vec<u8> {
+        let (qname, qtype) = match self.response.question {
+            Some((ref name, qtype)) => (
+                Some(name.to_string()),
+                Some(record::type_string(qtype)),
+            ),
+            None => (None, None),
+        };
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_nanos(self.time).to_string(),
+            client: self.client,
+            transport: self.transport,
+            qname,
+            qtype,
+            rcode: rcode_name(self.response.rcode),
+            answer: self.response.counts[0],
+            authority: self.response.counts[1],
+            additional: self.response.counts[2],
+            size: self.size,
+            duration: self.elapsed.as_secs() * 1_000_000 + u64::from(self.elapsed.subsec_micros()),
+        };
+        let mut line = serde_json::to_vec(&entry).expect("cannot fail");
+        line.push(b'\n');
+        line
+    }
+}
+
+enum Message {
+    Query(Query),
+    /// Wakes the writer, so that it switches to a newly opened log file even if no queries are
+    /// being logged.
+    Reopen,
+}
+
+/// A newly opened log file, waiting for the writer to switch to it.
+type Reopened = Arc<Mutex<Option<Box<Write + Send>>>>;
+
+/// The query log. Entries are written by a separate thread, so that queries are never held up by
+/// a slow disk: they are queued, and dropped if the queue is full.
+pub struct QueryLog {
+    /// The file to log to, or `None` for standard output.
+    path: Option<PathBuf>,
+    sample: usize,
+    seen: AtomicUsize,
+    sender: SyncSender<Message>,
+    reopened: Reopened,
+}
+
+impl QueryLog {
+    /// Opens the log file and starts the thread that writes to it.
+    pub fn new(config: &QueryLogConfig) -> Result<QueryLog, failure::Error> {
+        let path = if config.path.as_os_str() == "-" {
+            None
+        } else {
+            Some(config.path.clone())
+        };
+        let output = open(&path)?;
+        let (sender, receiver) = mpsc::sync_channel(config.queue_size);
+        let reopened = Reopened::default();
+        let writer_reopened = reopened.clone();
+        thread::Builder::new()
+            .name(String::from("query-log"))
+            .spawn(move || write(output, &receiver, &writer_reopened))?;
+        Ok(QueryLog {
+            path,
+            sample: config.sample as usize,
+            seen: AtomicUsize::new(0),
+            sender,
+            reopened,
+        })
+    }
+
+    /// Reopens the log file, so that a rotated log is no longer written to. Standard output is
+    /// left alone.
+    ///
+    /// The writer switches to the new file before it writes its next entry, so queries already
+    /// queued may be written to either file. This never waits for the writer.
+    pub fn reopen(&self) -> Result<(), failure::Error> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        let output = open(&self.path)?;
+        *self
+            .reopened
+            .lock()
+            .unwrap_or_else(|_| fatal!("query log is poisoned")) = Some(output);
+        match self.sender.try_send(Message::Reopen) {
+            // A full queue means the writer is busy, and will find the new file soon enough
+            Ok(()) | Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => bail!("the query log writer has stopped"),
+        }
+        info!("reopened query log {}", self.path.as_ref().expect("cannot fail").display());
+        Ok(())
+    }
+
+    /// Queues an answered query to be logged, if it is sampled. Returns `false` if the queue is
+    /// full and it was dropped.
+    pub fn record(
+        &self,
+        client: SocketAddr,
+        transport: Transport,
+        response: &ResponseSummary,
+        size: usize,
+        elapsed: Duration,
+    ) -> bool {
+        if self.seen.fetch_add(1, Ordering::Relaxed) % self.sample != 0 {
+            return true;
+        }
+        match self.sender.try_send(Message::Query(Query {
+            time: SystemTime::now(),
+            client,
+            transport,
+            response: response.clone(),
+            size,
+            elapsed,
+        })) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => false,
+        }
+    }
+}
+
+/// Writes queued queries until the query log is dropped. Only the first of a run of write failures
+/// is logged.
+fn write(mut output: Box<Write + Send>, receiver: &Receiver<Message>, reopened: &Reopened) {
+    let mut failing = false;
+    for message in receiver {
+        let mut result = Ok(());
+        let reopened = reopened
+            .lock()
+            .unwrap_or_else(|_| fatal!("query log is poisoned"))
+            .take();
+        if let Some(reopened) = reopened {
+            result = output.flush();
+            output = reopened;
+        }
+        if let Message::Query(query) = message {
+            result = result.and(output.write_all(&query.to_line()));
+        }
+        match result {
+            Ok(()) => failing = false,
+            Err(err) => {
+                if !failing {
+                    warn!("failed to write to query log: {}", err);
+                }
+                failing = true;
+            }
+        }
+    }
+}
+
+fn open(path: &Option<PathBuf>) -> Result<Box<Write + Send>, failure::Error> {
+    Ok(match path {
+        Some(path) => Box::new(LineWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|e| {
+                    format!("failed to open query log {}: {}", path.display(), e)
+                })?,
+        )),
+        None => Box::new(io::stdout()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use pepbut::name::Name;
+    use pepbut::wire::ResponseSummary;
+    use serde_json::{self, Value};
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::path::Path;
+    use std::str::FromStr;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use config::{QueryLogConfig, Transport};
+    use querylog::QueryLog;
+
+    #[test]
+    fn sample_and_reopen() {
+        let dir = env::temp_dir().join(format!("pepbut-querylog-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let log = QueryLog::new(&QueryLogConfig {
+            path: path.clone(),
+            sample: 2,
+            queue_size: 16,
+        }).unwrap();
+        let response = ResponseSummary {
+            id: 0x1234,
+            rcode: 3,
+            truncated: false,
+            question: Some((Name::from_str("nope.example.invalid").unwrap(), 28)),
+            counts: [0, 0, 1],
+        };
+        let client = "192.0.2.7:4444".parse().unwrap();
+        // Entries are written by another thread, so wait for them
+        let read_lines = |path: &Path, count: usize| {
+            let start = Instant::now();
+            loop {
+                let lines = fs::read_to_string(path).unwrap();
+                if lines.lines().count() >= count || start.elapsed() > Duration::from_secs(5) {
+                    return lines;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        for _ in 0..3 {
+            assert!(log.record(client, Transport::Udp, &response, 80, Duration::from_micros(42)));
+        }
+        read_lines(&path, 2);
+
+        let rotated = dir.join("queries.log.1");
+        fs::rename(&path, &rotated).unwrap();
+        log.reopen().unwrap();
+        log.record(client, Transport::Tcp, &response, 80, Duration::from_micros(42));
+        log.record(client, Transport::Tcp, &response, 80, Duration::from_micros(42));
+
+        let lines = read_lines(&rotated, 2);
+        let lines = lines.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let entry: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(entry["client"], "192.0.2.7:4444");
+        assert_eq!(entry["transport"], "udp");
+        assert_eq!(entry["qname"], "nope.example.invalid");
+        assert_eq!(entry["qtype"], "AAAA");
+        assert_eq!(entry["rcode"], "NXDOMAIN");
+        assert_eq!(entry["additional"], 1);
+        assert_eq!(entry["size"], 80);
+        assert_eq!(entry["duration"], 42);
+        assert_eq!(read_lines(&path, 1).lines().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
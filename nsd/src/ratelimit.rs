@@ -0,0 +1,237 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use config::RateLimitConfig;
+
+/// The most clients tracked at once.
+const MAX_CLIENTS: usize = 65536;
+/// The number of independently locked tables clients are spread over. Each client's table is
+/// chosen by a randomly keyed hash, so a flood from chosen addresses cannot fill just one.
+const SHARDS: usize = 64;
+/// How often a full shard may be searched for buckets that have refilled completely.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket rate limiter keyed on client address.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Debug)]
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    /// When the shard was last searched for buckets to forget.
+    cleaned: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// Whether the last response to the client was dropped.
+    limited: bool,
+}
+
+/// Whether a response should be sent, as decided by `RateLimiter::check`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Drop,
+    /// Drop the response, which is the first dropped since a response to the client was allowed.
+    StartDropping,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter. Returns `None` if rate limiting is disabled.
+    pub fn new(config: &RateLimitConfig) -> Option<RateLimiter> {
+        if config.responses_per_second == 0 {
+            None
+        } else {
+            Some(RateLimiter {
+                rate: f64::from(config.responses_per_second),
+                burst: f64::from(config.burst),
+                hasher: RandomState::new(),
+                shards: (0..SHARDS)
+                    .map(|_| {
+                        Mutex::new(Shard {
+                            buckets: HashMap::new(),
+                            cleaned: None,
+                        })
+                    }).collect(),
+            })
+        }
+    }
+
+    /// Takes a token for the client, deciding whether the response should be dropped.
+    pub fn check(&self, addr: IpAddr) -> Verdict {
+        self.check_at(addr, Instant::now())
+    }
+
+    /// Returns whether a response to the client would be dropped now, without taking a token.
+    pub fn would_drop(&self, addr: IpAddr) -> bool {
+        self.would_drop_at(addr, Instant::now())
+    }
+
+    fn shard(&self, addr: IpAddr) -> MutexGuard<'_, Shard> {
+        let mut hasher = self.hasher.build_hasher();
+        addr.hash(&mut hasher);
+        self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap_or_else(|_| fatal!("rate limiter is poisoned"))
+    }
+
+    fn would_drop_at(&self, addr: IpAddr, now: Instant) -> bool {
+        let tokens = match self.shard(addr).buckets.get(&addr) {
+            Some(bucket) => bucket.tokens_at(now, self.rate, self.burst),
+            None => self.burst,
+        };
+        tokens < 1.0
+    }
+
+    fn check_at(&self, addr: IpAddr, now: Instant) -> Verdict {
+        let mut shard = self.shard(addr);
+        if shard.buckets.len() >= MAX_CLIENTS / SHARDS && !shard.buckets.contains_key(&addr) {
+            shard.make_room(now, self.rate, self.burst);
+        }
+        let burst = self.burst;
+        let bucket = shard.buckets.entry(addr).or_insert_with(|| Bucket {
+            tokens: burst,
+            last: now,
+            limited: false,
+        });
+        bucket.refill(now, self.rate, self.burst);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = false;
+            Verdict::Allow
+        } else if bucket.limited {
+            Verdict::Drop
+        } else {
+            bucket.limited = true;
+            Verdict::StartDropping
+        }
+    }
+}
+
+impl Shard {
+    /// Removes at least one bucket from a full shard. Buckets that have refilled completely are
+    /// forgotten at most once every `CLEANUP_INTERVAL`; if that frees nothing, such as during a
+    /// flood from spoofed addresses, the client with the most tokens is forgotten instead, since
+    /// it loses the least by starting over with a full bucket. Clients being dropped are only
+    /// forgotten once no others are left, so that churn cannot lift their limit.
+    fn make_room(&mut self, now: Instant, rate: f64, burst: f64) {
+        if self
+            .cleaned
+            .map_or(true, |cleaned| now.duration_since(cleaned) >= CLEANUP_INTERVAL)
+        {
+            self.cleaned = Some(now);
+            self.buckets
+                .retain(|_, bucket| bucket.refill(now, rate, burst) < burst);
+        }
+        if self.buckets.len() >= MAX_CLIENTS / SHARDS {
+            let mut evict: Option<(IpAddr, bool, f64)> = None;
+            for (&addr, bucket) in &self.buckets {
+                let tokens = bucket.tokens_at(now, rate, burst);
+                let better = match evict {
+                    Some((_, limited, most)) => {
+                        (limited && !bucket.limited)
+                            || (limited == bucket.limited && tokens > most)
+                    }
+                    None => true,
+                };
+                if better {
+                    evict = Some((addr, bucket.limited, tokens));
+                }
+            }
+            if let Some((addr, _, _)) = evict {
+                self.buckets.remove(&addr);
+            }
+        }
+    }
+}
+
+impl Bucket {
+    /// Returns the tokens the bucket would have at `now`.
+    fn tokens_at(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        (self.tokens + elapsed * rate).min(burst)
+    }
+
+    fn refill(&mut self, now: Instant, rate: f64, burst: f64) -> f64 {
+        self.tokens = self.tokens_at(now, rate, burst);
+        self.last = now;
+        self.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimiter, Verdict, MAX_CLIENTS, SHARDS};
+    use config::RateLimitConfig;
+
+    #[test]
+    fn rate_limit() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            responses_per_second: 10,
+            burst: 2,
+        }).unwrap();
+        let a: IpAddr = [192, 0, 2, 1].into();
+        let b: IpAddr = [192, 0, 2, 2].into();
+        let now = Instant::now();
+        assert_eq!(limiter.check_at(a, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(a, now), Verdict::Allow);
+        assert!(limiter.would_drop_at(a, now));
+        assert_eq!(limiter.check_at(a, now), Verdict::StartDropping);
+        assert_eq!(limiter.check_at(a, now), Verdict::Drop);
+        assert!(!limiter.would_drop_at(b, now));
+        assert_eq!(limiter.check_at(b, now), Verdict::Allow);
+        let later = now + Duration::from_millis(100);
+        assert!(!limiter.would_drop_at(a, later));
+        assert_eq!(limiter.check_at(a, later), Verdict::Allow);
+        assert_eq!(limiter.check_at(a, later), Verdict::StartDropping);
+    }
+
+    #[test]
+    fn bounded() {
+        let limiter = RateLimiter::new(&RateLimitConfig {
+            responses_per_second: 1,
+            burst: 1,
+        }).unwrap();
+        let now = Instant::now();
+        let limited: IpAddr = [192, 0, 2, 1].into();
+        assert_eq!(limiter.check_at(limited, now), Verdict::Allow);
+        assert_eq!(limiter.check_at(limited, now), Verdict::StartDropping);
+        for i in 0..MAX_CLIENTS as u32 * 2 {
+            let addr: IpAddr = [10, (i >> 16) as u8, (i >> 8) as u8, i as u8].into();
+            assert_eq!(limiter.check_at(addr, now), Verdict::Allow);
+        }
+        // Other clients coming and going do not make the server forget a client it is dropping
+        assert_eq!(limiter.check_at(limited, now), Verdict::Drop);
+        let later = now + Duration::from_secs(2);
+        for i in 0..MAX_CLIENTS as u32 {
+            let addr: IpAddr = [172, 16 + (i >> 16) as u8, (i >> 8) as u8, i as u8].into();
+            limiter.check_at(addr, later);
+        }
+        for shard in &limiter.shards {
+            assert!(shard.lock().unwrap().buckets.len() <= MAX_CLIENTS / SHARDS);
+        }
+    }
+
+    #[test]
+    fn disabled() {
+        assert!(RateLimiter::new(&RateLimitConfig::default()).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! A seccomp filter allowing only the system calls nsd makes once it is running.
+//!
+//! The filter is a classic BPF program: it checks the architecture the system call was made for,
+//! then compares the system call number against each allowed one in turn. Anything else gets the
+//! configured action.
+
+use cast;
+use failure;
+use libc;
+use std::io;
+
+use config::SeccompAction;
+
+// From linux/bpf_common.h
+const BPF_LD: u16 = 0x00;
+const BPF_W: u16 = 0x00;
+const BPF_ABS: u16 = 0x20;
+const BPF_JMP: u16 = 0x05;
+const BPF_JEQ: u16 = 0x10;
+const BPF_JGE: u16 = 0x30;
+const BPF_K: u16 = 0x00;
+const BPF_RET: u16 = 0x06;
+
+// From linux/seccomp.h
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// Offsets of the fields of `struct seccomp_data`.
+const OFFSET_NR: u32 = 0;
+const OFFSET_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// System calls for x32 binaries have the same architecture as x86_64, but have this bit set.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+/// System calls made by the tokio runtime and its threads, by sockets, by reading and writing
+/// zone files, and by the standard library along the way.
+const ALLOWED: &[libc::c_long] = &[
+    // Memory
+    libc::SYS_brk,
+    libc::SYS_madvise,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    // Threads and synchronization
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getrandom,
+    libc::SYS_prctl,
+    libc::SYS_rseq,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_set_robust_list,
+    libc::SYS_tgkill,
+    // Signals
+    libc::SYS_restart_syscall,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    // Time
+    libc::SYS_clock_getres,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_nanosleep,
+    // Event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    // File descriptors
+    libc::SYS_close,
+    libc::SYS_dup3,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    // Sockets
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_connect,
+    libc::SYS_getpeername,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_listen,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    // Files
+    libc::SYS_fdatasync,
+    libc::SYS_fstat,
+    libc::SYS_fsync,
+    libc::SYS_getdents64,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_statx,
+    libc::SYS_unlinkat,
+    // Identity, for access checks and the server's name
+    libc::SYS_getegid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getuid,
+    libc::SYS_uname,
+];
+
+/// System calls that newer architectures only have the `*at` or `p*` variants of.
+#[cfg(target_arch = "x86_64")]
+const ALLOWED_LEGACY: &[libc::c_long] = &[
+    libc::SYS_accept,
+    libc::SYS_epoll_wait,
+    libc::SYS_lstat,
+    libc::SYS_open,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_rename,
+    libc::SYS_stat,
+    libc::SYS_unlink,
+];
+#[cfg(not(target_arch = "x86_64"))]
+const ALLOWED_LEGACY: &[libc::c_long] = &[];
+
+/// `struct sock_filter`, a single BPF instruction.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Instruction {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// `struct sock_fprog`.
+#[repr(C)]
+struct Program {
+    len: libc::c_ushort,
+    filter: *const Instruction,
+}
+
+fn statement(code: u16, k: u32) -> Instruction {
+    Instruction {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Instruction {
+    Instruction { code, jt, jf, k }
+}
+
+/// A compiled seccomp filter.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    program: Vec<Instruction>,
+}
+
+impl Filter {
+    /// Builds a filter that allows the system calls nsd needs, and applies `action` to any other.
+    /// System calls made for a different architecture always kill the process.
+    pub fn new(action: SeccompAction) -> Result<Filter, failure::Error> {
+        let arch = match AUDIT_ARCH {
+            Some(arch) => arch,
+            None => bail!("seccomp filtering is not supported on this architecture"),
+        };
+        let disallowed = match action {
+            SeccompAction::Kill => SECCOMP_RET_KILL_PROCESS,
+            SeccompAction::Log => SECCOMP_RET_LOG,
+        };
+
+        let mut program = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_ARCH),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+            statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD | BPF_W | BPF_ABS, OFFSET_NR),
+        ];
+        if let Some(bit) = X32_SYSCALL_BIT {
+            program.push(jump(BPF_JMP | BPF_JGE | BPF_K, bit, 0, 1));
+            program.push(statement(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS));
+        }
+        // Each match jumps over the rest of the comparisons and the disallowed action
+        let allowed = ALLOWED.iter().chain(ALLOWED_LEGACY).collect::<Vec<_>>();
+        for (i, nr) in allowed.iter().enumerate() {
+            let remaining = cast::u8(allowed.len() - i)?;
+            program.push(jump(BPF_JMP | BPF_JEQ | BPF_K, cast::u32(**nr)?, remaining, 0));
+        }
+        program.push(statement(BPF_RET | BPF_K, disallowed));
+        program.push(statement(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        Ok(Filter { program })
+    }
+
+    /// Installs the filter for every thread in the process. It cannot be removed afterwards.
+    ///
+    /// This only allocates on failure, so that it can be called in a child process after forking.
+    pub fn install(&self) -> Result<(), failure::Error> {
+        let program = Program {
+            len: cast::u16(self.program.len())?,
+            filter: self.program.as_ptr(),
+        };
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            bail!("failed to set no_new_privs: {}", io::Error::last_os_error());
+        }
+        match unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &program as *const Program,
+            )
+        } {
+            0 => Ok(()),
+            // The ID of a thread that could not be synchronized
+            tid if tid > 0 => bail!("thread {} could not be given the filter", tid),
+            _ => Err(io::Error::last_os_error().into()),
+        }
+    }
+
+    /// Returns how many system calls are allowed.
+    pub fn allowed(&self) -> usize {
+        ALLOWED.len() + ALLOWED_LEGACY.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, ALLOWED, ALLOWED_LEGACY, BPF_RET};
+    use config::SeccompAction;
+
+    #[test]
+    fn program() {
+        let filter = Filter::new(SeccompAction::Kill).unwrap();
+        let allowed = ALLOWED.len() + ALLOWED_LEGACY.len();
+        assert!(filter.program.len() > allowed + 2);
+        // Every comparison jumps to the final instruction, which allows the system call
+        let last = filter.program.len() - 1;
+        for (i, instruction) in filter.program.iter().enumerate().rev().skip(2).take(allowed) {
+            assert_eq!(i + 1 + instruction.jt as usize, last);
+        }
+        assert_eq!(filter.program[last].code, BPF_RET);
+    }
+}
@@ -0,0 +1,474 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS servers for each transport.
+
+use bytes::Bytes;
+use failure;
+use futures::sync::oneshot::{self, SpawnHandle};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use net2::unix::UnixUdpBuilderExt;
+use net2::{TcpBuilder, UdpBuilder};
+use pepbut::authority::{Answered, Authority, Channel};
+use pepbut::wire::{encode_err, ResponseSummary};
+use rustls::Session;
+use std::io;
+use std::net::{self, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use tokio;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::prelude::future::{self, Loop};
+use tokio::prelude::{Future, Stream};
+use tokio::reactor::Handle;
+use tokio::runtime::TaskExecutor;
+use tokio::timer::{Delay, Timeout};
+
+use config::{Config, ProxyProtocolConfig, Transport};
+use dnstap::Dnstap;
+use events::{Event, Events};
+use https::{self, IdleTimeout};
+use metrics::Metrics;
+use proxy::ReadHeader;
+use querylog::QueryLog;
+use ratelimit::{RateLimiter, Verdict};
+use tcp::{Connection, ConnectionGuard, Incoming, TcpLimits};
+use tls::Tls;
+use udp;
+use upgrade::Trigger;
+
+pub type ServerFuture = Box<Future<Item = (), Error = ()> + Send>;
+
+/// How long to wait before starting a server again after it stops.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// State shared by the servers for every transport and the control socket.
+pub struct Context {
+    pub authority: Arc<RwLock<Authority>>,
+    pub rate_limiter: Option<RateLimiter>,
+    pub tcp: Arc<TcpLimits>,
+    pub tls: Option<Tls>,
+    pub proxy_protocol: ProxyProtocolConfig,
+    pub metrics: Metrics,
+    pub query_log: Option<QueryLog>,
+    pub dnstap: Option<Dnstap>,
+    pub events: Events,
+    pub upgrade: Trigger,
+    /// Held while the control socket loads, stages, activates, discards, or updates a zone, so that
+    /// an update is not applied or written over a zone that was replaced after it was prepared.
+    pub zone_updates: Mutex<()>,
+}
+
+impl Context {
+    pub fn new(
+        authority: Arc<RwLock<Authority>>,
+        config: &Config,
+    ) -> Result<Context, failure::Error> {
+        // Zones loaded on startup count as loads; a failure there stops the server instead
+        let metrics = Metrics::new();
+        let zones = authority
+            .read()
+            .unwrap_or_else(|_| fatal!("authority is poisoned"))
+            .zones
+            .len();
+        for _ in 0..zones {
+            metrics.record_zone_load(true);
+        }
+        Ok(Context {
+            authority,
+            rate_limiter: RateLimiter::new(&config.rate_limit),
+            tcp: Arc::new(TcpLimits::new(&config.tcp)),
+            tls: match config.tls {
+                Some(ref tls) => Some(Tls::new(tls)?),
+                None => None,
+            },
+            proxy_protocol: config.proxy_protocol.clone(),
+            metrics,
+            query_log: match config.query_log {
+                Some(ref query_log) => Some(QueryLog::new(query_log)?),
+                None => None,
+            },
+            dnstap: match config.dnstap {
+                Some(ref dnstap) => Some(Dnstap::new(dnstap, &config.identity)?),
+                None => None,
+            },
+            events: Events::new(),
+            upgrade: Trigger::default(),
+            zone_updates: Mutex::new(()),
+        })
+    }
+
+    /// Reloads the TLS certificate and key, and tells event subscribers whether it worked.
+    /// Returns `None` if TLS is not configured.
+    pub fn reload_tls(&self) -> Option<Result<(), failure::Error>> {
+        let result = self.tls.as_ref()?.reload();
+        self.events.send(&match result {
+            Ok(()) => Event::TlsReloaded,
+            Err(ref err) => Event::TlsReloadFailed {
+                error: err.to_string(),
+            },
+        });
+        Some(result)
+    }
+
+    /// Reopens the query log, and tells event subscribers whether it worked. Returns `None` if the
+    /// query log is not enabled.
+    pub fn reopen_query_log(&self) -> Option<Result<(), failure::Error>> {
+        let result = self.query_log.as_ref()?.reopen();
+        self.events.send(&match result {
+            Ok(()) => Event::QueryLogReopened,
+            Err(ref err) => Event::QueryLogReopenFailed {
+                error: err.to_string(),
+            },
+        });
+        Some(result)
+    }
+
+    /// Answers a query received over a connectionless transport. Returns `None` if the message
+    /// is too short to respond to, in which case it is dropped.
+    ///
+    /// Responses are truncated to the size the client can receive over UDP; DNS over HTTPS has no
+    /// such limit.
+    pub fn process_message(
+        &self,
+        b: Bytes,
+        transport: Transport,
+        client: SocketAddr,
+    ) -> Option<Bytes> {
+        let channel = match transport {
+            Transport::Udp => Channel::Datagram,
+            _ => Channel::Unlimited,
+        };
+        self.process(b, transport, client, channel)
+    }
+
+    /// Answers a query received over a connection-oriented transport, like `process_message`.
+    pub fn process_tcp_message(
+        &self,
+        b: Bytes,
+        transport: Transport,
+        client: SocketAddr,
+    ) -> Option<Bytes> {
+        self.process(b, transport, client, Channel::Stream(self.tcp.keepalive()))
+    }
+
+    fn process(
+        &self,
+        b: Bytes,
+        transport: Transport,
+        client: SocketAddr,
+        channel: Channel,
+    ) -> Option<Bytes> {
+        let start = Instant::now();
+        let query_time = SystemTime::now();
+        let query = if self.dnstap.is_some() {
+            Some(b.clone())
+        } else {
+            None
+        };
+        let authority = self
+            .authority
+            .read()
+            .unwrap_or_else(|_| fatal!("authority is poisoned"));
+        // A bug answering one query must not stop the server it arrived on. Read guards are not
+        // poisoned by a panic, so the authority stays usable.
+        let id = if b.len() >= 2 {
+            Some(u16::from(b[0]) << 8 | u16::from(b[1]))
+        } else {
+            None
+        };
+        let answered = panic::catch_unwind(AssertUnwindSafe(|| authority.answer(b, channel)));
+        let answered = match answered {
+            Ok(answered) => answered?,
+            Err(_) => {
+                error!("panicked answering a {} query from {}", transport, client);
+                let response = encode_err(id?, 2);
+                Answered {
+                    summary: ResponseSummary::from_header(&response, None),
+                    response,
+                    zone: None,
+                }
+            }
+        };
+        let elapsed = start.elapsed();
+        let response = answered.response;
+        let summary = answered.summary;
+        self.metrics.record_response(
+            transport,
+            &summary,
+            answered.zone.map(|zone| &zone.origin),
+            elapsed,
+        );
+        if let Some(ref query_log) = self.query_log {
+            if !query_log.record(client, transport, &summary, response.len(), elapsed) {
+                self.metrics.record_query_log_dropped();
+            }
+        }
+        if let (Some(dnstap), Some(query)) = (&self.dnstap, query) {
+            if !dnstap.log(transport, client, query, query_time, response.clone()) {
+                self.metrics.record_dnstap_dropped();
+            }
+        }
+        Some(response)
+    }
+}
+
+/// Accepts connections on a TCP listener, spawning a task for each. Once any PROXY protocol header
+/// has been read and the connection limits have been checked, `serve` is called with the address
+/// of the client.
+fn accept(
+    listener: TcpListener,
+    context: Arc<Context>,
+    transport: Transport,
+    serve: fn(TcpStream, SocketAddr, ConnectionGuard, Arc<Context>) -> ServerFuture,
+) -> ServerFuture {
+    Box::new(
+        Incoming::new(listener)
+            .for_each(move |(tcp, peer)| {
+                let client: Box<Future<Item = (TcpStream, SocketAddr), Error = ()> + Send> =
+                    if context.proxy_protocol.is_trusted(&peer.ip()) {
+                        Box::new(
+                            Timeout::new(ReadHeader::new(tcp, peer), context.tcp.read_timeout)
+                                .map_err(move |e| match e.into_inner() {
+                                    Some(e) => warn!(
+                                        "closing {} connection from proxy {}: {}",
+                                        transport, peer, e
+                                    ),
+                                    None => warn!(
+                                        "closing {} connection from proxy {}: PROXY header timeout",
+                                        transport, peer
+                                    ),
+                                }).map(move |(tcp, client)| {
+                                    debug!(
+                                        "{} connection from {} via proxy {}",
+                                        transport, client, peer
+                                    );
+                                    (tcp, client)
+                                }),
+                        )
+                    } else {
+                        Box::new(future::ok((tcp, peer)))
+                    };
+                let context = context.clone();
+                tokio::spawn(client.and_then(move |(tcp, client)| {
+                    match TcpLimits::acquire(&context.tcp, &client) {
+                        Some(guard) => serve(tcp, client, guard, context),
+                        None => Box::new(future::ok(())),
+                    }
+                }));
+                Ok(())
+            }).map_err(move |e| error!("error in {} server: {:?}", transport, e)),
+    )
+}
+
+/// Serves DNS over TCP, spawning a task for each connection.
+pub fn tcp(listener: TcpListener, context: Arc<Context>) -> ServerFuture {
+    accept(listener, context, Transport::Tcp, |tcp, peer, guard, context| {
+        Box::new(
+            Connection::new(tcp, peer, context.tcp.clone(), guard, move |b| {
+                context.process_tcp_message(b, Transport::Tcp, peer)
+            }).map_err(move |e| error!("error in TCP connection from {}: {}", peer, e)),
+        )
+    })
+}
+
+/// Serves DNS over TLS, spawning a task for each connection.
+///
+/// The handshake must complete within the TCP read timeout.
+pub fn tls(listener: TcpListener, context: Arc<Context>) -> ServerFuture {
+    accept(listener, context, Transport::Tls, |tcp, peer, guard, context| {
+        let acceptor = match context.tls {
+            Some(ref tls) => tls.acceptor(),
+            None => fatal!("TLS listener started without a TLS configuration"),
+        };
+        Box::new(
+            Timeout::new(acceptor.accept(tcp), context.tcp.read_timeout)
+                .map_err(move |e| match e.into_inner() {
+                    Some(e) => info!("TLS handshake with {} failed: {}", peer, e),
+                    None => info!("TLS handshake with {} timed out", peer),
+                }).and_then(move |stream| {
+                    Connection::new(stream, peer, context.tcp.clone(), guard, move |b| {
+                        context.process_tcp_message(b, Transport::Tls, peer)
+                    }).map_err(move |e| error!("error in TLS connection from {}: {}", peer, e))
+                }),
+        )
+    })
+}
+
+/// Serves DNS over HTTPS, spawning a task for each connection. HTTP/2 is used if the client
+/// negotiates it with ALPN, and HTTP/1.1 otherwise.
+///
+/// The handshake must complete within the TCP read timeout, and connections are closed after the
+/// TCP idle timeout, or once requests in progress are answered when connections are drained.
+pub fn https(listener: TcpListener, context: Arc<Context>) -> ServerFuture {
+    accept(listener, context, Transport::Https, |tcp, peer, guard, context| {
+        let acceptor = match context.tls {
+            Some(ref tls) => tls.https_acceptor(),
+            None => fatal!("HTTPS listener started without a TLS configuration"),
+        };
+        let tcp = IdleTimeout::new(tcp, context.tcp.idle_timeout);
+        Box::new(
+            Timeout::new(acceptor.accept(tcp), context.tcp.read_timeout)
+                .map_err(move |e| match e.into_inner() {
+                    Some(e) => info!("TLS handshake with {} failed: {}", peer, e),
+                    None => info!("TLS handshake with {} timed out", peer),
+                }).and_then(move |stream| {
+                    let h2 = stream.get_ref().1.get_alpn_protocol() == Some(&b"h2"[..]);
+                    let mut draining = context.tcp.draining();
+                    let mut connection = Http::new().http2_only(h2).serve_connection(
+                        stream,
+                        service_fn(move |request| https::handle(request, &context, peer)),
+                    );
+                    let mut closing = false;
+                    future::poll_fn(move || {
+                        // Requests in progress are answered before the connection is closed
+                        if !closing && draining.poll() {
+                            connection.graceful_shutdown();
+                            closing = true;
+                        }
+                        connection.poll()
+                    }).then(move |result| {
+                            drop(guard);
+                            result.map_err(|e| {
+                                info!("error in HTTPS connection from {}: {}", peer, e)
+                            })
+                        })
+                }),
+        )
+    })
+}
+
+/// Binds `count` UDP sockets to the same address with `SO_REUSEPORT`, so that the kernel spreads
+/// incoming queries across them.
+///
+/// If the port is 0, the port the first socket is given is used for the rest.
+pub fn bind_udp(addr: &SocketAddr, count: usize) -> io::Result<Vec<net::UdpSocket>> {
+    let mut addr = *addr;
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let builder = match addr {
+            SocketAddr::V4(_) => UdpBuilder::new_v4()?,
+            SocketAddr::V6(_) => UdpBuilder::new_v6()?,
+        };
+        builder.reuse_port(true)?;
+        let socket = builder.bind(addr)?;
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+/// Binds a TCP listener with `SO_REUSEADDR`, so that nsd can be restarted while connections to
+/// the old process are still in `TIME_WAIT`.
+pub fn bind_tcp(addr: &SocketAddr) -> io::Result<net::TcpListener> {
+    let builder = match addr {
+        SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+        SocketAddr::V6(_) => TcpBuilder::new_v6()?,
+    };
+    builder.reuse_address(true)?;
+    builder.bind(addr)?;
+    builder.listen(1024)
+}
+
+/// Runs the server returned by `start`, and starts it again whenever it stops or panics, so that
+/// an error on a listening socket does not stop nsd. If `start` fails, it is tried again after the
+/// same delay.
+pub fn restarting<F>(name: String, start: F) -> ServerFuture
+where
+    F: FnMut() -> io::Result<ServerFuture> + Send + 'static,
+{
+    Box::new(future::loop_fn(start, move |mut start| {
+        let server = match start() {
+            Ok(server) => server,
+            Err(err) => {
+                error!("failed to start {}: {}", name, err);
+                Box::new(future::ok(()))
+            }
+        };
+        let name = name.clone();
+        AssertUnwindSafe(server).catch_unwind().then(move |result| {
+            match result {
+                Ok(_) => warn!("{} stopped, starting it again in {:?}", name, RESTART_DELAY),
+                Err(_) => error!("{} panicked, starting it again in {:?}", name, RESTART_DELAY),
+            }
+            Delay::new(Instant::now() + RESTART_DELAY).then(|_| Ok(Loop::Continue(start)))
+        })
+    }))
+}
+
+/// Servers running on a runtime's worker threads, so that queries arriving on different sockets
+/// are answered in parallel. Every server is stopped when this is dropped.
+#[derive(Debug)]
+#[must_use]
+pub struct Running {
+    _handles: Vec<SpawnHandle<(), ()>>,
+}
+
+/// Spawns each server as its own task.
+pub fn spawn(servers: Vec<ServerFuture>, executor: &TaskExecutor) -> Running {
+    Running {
+        _handles: servers
+            .into_iter()
+            .map(|server| oneshot::spawn(server, executor))
+            .collect(),
+    }
+}
+
+/// Serves DNS over UDP on `socket`, starting the server again if it stops.
+pub fn serve_udp(socket: net::UdpSocket, context: Arc<Context>) -> io::Result<ServerFuture> {
+    let name = format!("udp server on {}", socket.local_addr()?);
+    Ok(restarting(name, move || {
+        let socket = UdpSocket::from_std(socket.try_clone()?, &Handle::default())?;
+        Ok(udp(socket, context.clone()))
+    }))
+}
+
+/// Serves DNS over TCP, TLS, or HTTPS on `listener`, starting the server again if it stops.
+pub fn serve_tcp(
+    listener: net::TcpListener,
+    transport: Transport,
+    context: Arc<Context>,
+) -> io::Result<ServerFuture> {
+    let serve = match transport {
+        Transport::Tcp => tcp,
+        Transport::Tls => tls,
+        Transport::Https => https,
+        Transport::Udp => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "UDP cannot be served on a TCP listener",
+            ))
+        }
+    };
+    let name = format!("{} server on {}", transport, listener.local_addr()?);
+    Ok(restarting(name, move || {
+        let listener = TcpListener::from_std(listener.try_clone()?, &Handle::default())?;
+        Ok(serve(listener, context.clone()))
+    }))
+}
+
+/// Serves DNS over UDP.
+pub fn udp(socket: UdpSocket, context: Arc<Context>) -> ServerFuture {
+    Box::new(
+        udp::Server::new(socket, move |b, client| {
+            if let Some(ref rate_limiter) = context.rate_limiter {
+                match rate_limiter.check(client.ip()) {
+                    Verdict::Allow => {}
+                    Verdict::Drop => {
+                        context.metrics.record_rate_limited();
+                        return None;
+                    }
+                    Verdict::StartDropping => {
+                        context.metrics.record_rate_limited();
+                        context.events.send(&Event::RateLimited {
+                            client: client.ip(),
+                        });
+                        return None;
+                    }
+                }
+            }
+            context.process_message(b, Transport::Udp, client)
+        }).map_err(|e| error!("error in UDP server: {}", e)),
+    )
+}
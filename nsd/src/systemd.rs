@@ -0,0 +1,300 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! systemd socket activation and readiness notification.
+//!
+//! These implement the protocols described in [sd_listen_fds(3)][listen] and [sd_notify(3)][notify]
+//! without linking to libsystemd.
+//!
+//! When sockets are passed, they replace the configured listeners: UDP and TCP sockets serve DNS,
+//! and a Unix stream socket serves as the control socket. TCP sockets serve DNS over TLS if they
+//! are named `tls`, DNS over HTTPS if they are named `https`, or metrics if they are named
+//! `metrics`; each needs a separate socket unit to be named. For example:
+//!
+//! ```ini
+//! # pepbut-nsd.socket
+//! [Socket]
+//! ListenDatagram=53
+//! ListenStream=53
+//! ListenStream=/run/pepbut/nsd.sock
+//!
+//! # pepbut-nsd-tls.socket
+//! [Socket]
+//! ListenStream=853
+//! FileDescriptorName=tls
+//! Service=pepbut-nsd.service
+//!
+//! # pepbut-nsd.service
+//! [Service]
+//! Type=notify
+//! Sockets=pepbut-nsd.socket pepbut-nsd-tls.socket
+//! ExecStart=/usr/bin/pepbut-nsd --config /etc/pepbut/nsd.toml
+//! User=pepbut
+//! ```
+//!
+//! [listen]: https://www.freedesktop.org/software/systemd/man/sd_listen_fds.html
+//! [notify]: https://www.freedesktop.org/software/systemd/man/sd_notify.html
+
+use libc;
+use std::env;
+use std::ffi::OsStr;
+use std::io;
+use std::mem;
+use std::net::{TcpListener, UdpSocket};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::process;
+
+/// The first file descriptor passed by the service manager.
+pub(crate) const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A socket inherited from the service manager.
+#[derive(Debug)]
+pub enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Socket::Udp(socket) => socket.as_raw_fd(),
+            Socket::Tcp(listener) => listener.as_raw_fd(),
+            Socket::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// A socket inherited from the service manager, with the name given to it by
+/// `FileDescriptorName=` (or `unknown` if unnamed).
+#[derive(Debug)]
+pub struct ListenFd {
+    pub name: String,
+    pub socket: Socket,
+}
+
+/// Takes the sockets passed by the service manager, if any.
+///
+/// `LISTEN_PID` must name this process, unless it was started by an upgrade, in which case the
+/// process being upgraded from passes its sockets without knowing the new PID in advance.
+///
+/// The `LISTEN_*` environment variables are removed so that they are not inherited by child
+/// processes. Calling this more than once returns no sockets after the first call.
+pub fn listen_fds(upgrading: bool) -> io::Result<Vec<ListenFd>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    parse_listen_fds(
+        pid.as_deref(),
+        fds.as_deref(),
+        names.as_deref(),
+        process::id(),
+        upgrading,
+    )?.into_iter()
+    .map(|(fd, name)| {
+        set_cloexec(fd)?;
+        Ok(ListenFd {
+            name,
+            socket: unsafe { socket_from_fd(fd)? },
+        })
+    }).collect()
+}
+
+/// Interprets the `LISTEN_*` environment variables, returning the passed file descriptors and
+/// their names.
+fn parse_listen_fds(
+    pid: Option<&str>,
+    fds: Option<&str>,
+    names: Option<&str>,
+    our_pid: u32,
+    upgrading: bool,
+) -> io::Result<Vec<(RawFd, String)>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidInput, msg.to_owned());
+
+    let fds = match fds {
+        Some(fds) => fds,
+        None => return Ok(Vec::new()),
+    };
+    let pid = match pid {
+        Some(pid) => Some(pid.parse::<u32>().map_err(|_| invalid("invalid LISTEN_PID"))?),
+        None => None,
+    };
+    match pid {
+        // The variables were meant for another process (probably our parent).
+        Some(pid) if pid != our_pid => return Ok(Vec::new()),
+        // Without a PID, the variables may have been inherited from an unrelated process.
+        None if !upgrading => return Ok(Vec::new()),
+        _ => {}
+    }
+    let count: RawFd = fds.parse().map_err(|_| invalid("invalid LISTEN_FDS"))?;
+    if count < 0 {
+        return Err(invalid("invalid LISTEN_FDS"));
+    }
+    let names: Vec<&str> = names.map_or_else(Vec::new, |names| names.split(':').collect());
+    Ok((0..count)
+        .map(|i| {
+            let name = names.get(i as usize).cloned().unwrap_or("unknown");
+            (SD_LISTEN_FDS_START + i, name.to_owned())
+        }).collect())
+}
+
+pub(crate) fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Determines what kind of socket a file descriptor is and takes ownership of it.
+///
+/// Only listening TCP sockets, UDP sockets, and listening Unix stream sockets are accepted.
+unsafe fn socket_from_fd(fd: RawFd) -> io::Result<Socket> {
+    let mut ty: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    if libc::getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_TYPE,
+        &mut ty as *mut _ as *mut libc::c_void,
+        &mut len,
+    ) < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addr: libc::sockaddr_storage = mem::zeroed();
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    match (libc::c_int::from(addr.ss_family), ty) {
+        (libc::AF_INET, libc::SOCK_DGRAM) | (libc::AF_INET6, libc::SOCK_DGRAM) => {
+            Ok(Socket::Udp(UdpSocket::from_raw_fd(fd)))
+        }
+        (libc::AF_INET, libc::SOCK_STREAM) | (libc::AF_INET6, libc::SOCK_STREAM) => {
+            Ok(Socket::Tcp(TcpListener::from_raw_fd(fd)))
+        }
+        (libc::AF_UNIX, libc::SOCK_STREAM) => Ok(Socket::Unix(UnixListener::from_raw_fd(fd))),
+        (family, ty) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "unsupported socket passed on fd {} (family {}, type {})",
+                fd, family, ty
+            ),
+        )),
+    }
+}
+
+/// Sends a state change to the service manager, such as `READY=1`.
+///
+/// Returns `Ok(false)` if `NOTIFY_SOCKET` is not set.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => notify_socket(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Sends a state change to a notification socket. Paths starting with `@` are in the abstract
+/// namespace.
+pub fn notify_socket(path: &OsStr, state: &str) -> io::Result<()> {
+    let path = path.as_bytes();
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if path.is_empty() || path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid NOTIFY_SOCKET",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let socket = UnixDatagram::unbound()?;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const _ as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::net::UnixDatagram;
+    use std::process;
+
+    use super::{notify_socket, parse_listen_fds};
+
+    #[test]
+    fn listen_fds() {
+        assert_eq!(
+            parse_listen_fds(Some("1234"), Some("3"), Some("dns:dns:control"), 1234, false)
+                .unwrap(),
+            vec![
+                (3, "dns".to_owned()),
+                (4, "dns".to_owned()),
+                (5, "control".to_owned()),
+            ]
+        );
+        // Only a process started by an upgrade accepts sockets without a PID
+        assert_eq!(
+            parse_listen_fds(None, Some("1"), None, 1234, true).unwrap(),
+            vec![(3, "unknown".to_owned())]
+        );
+        assert!(
+            parse_listen_fds(None, Some("1"), None, 1234, false)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            parse_listen_fds(Some("4321"), Some("2"), None, 1234, false)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            parse_listen_fds(None, None, None, 1234, false)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(parse_listen_fds(None, Some("two"), None, 1234, true).is_err());
+        assert!(parse_listen_fds(Some("1234"), Some("-1"), None, 1234, false).is_err());
+    }
+
+    #[test]
+    fn notify() {
+        let path = env::temp_dir().join(format!("pepbut-nsd-notify-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_socket(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        fs::remove_file(&path).unwrap();
+    }
+}
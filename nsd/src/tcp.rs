@@ -0,0 +1,448 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! TCP connection handling with the limits recommended by
+//! [RFC 7766 § 6.2](https://tools.ietf.org/html/rfc7766#section-6.2).
+
+use bytes::{Bytes, BytesMut};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::{Async, AsyncRead, AsyncWrite, Future, Poll, Stream};
+use tokio::timer::Delay;
+use tokio_codec::{Decoder, Encoder};
+
+use codec::DnsCodec;
+use config::TcpConfig;
+
+/// How long to stop accepting connections after running out of file descriptors or memory.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Connection limits and timeouts shared by every TCP listener.
+#[derive(Debug)]
+pub struct TcpLimits {
+    pub idle_timeout: Duration,
+    pub read_timeout: Duration,
+    pub max_pipelined: usize,
+    pub drain_timeout: Duration,
+    max_connections: usize,
+    max_connections_per_ip: usize,
+    open: Mutex<OpenConnections>,
+    draining: Draining,
+    drain: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+#[derive(Debug, Default)]
+struct OpenConnections {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl TcpLimits {
+    pub fn new(config: &TcpConfig) -> TcpLimits {
+        let (drain, draining) = oneshot::channel();
+        TcpLimits {
+            idle_timeout: config.idle_timeout(),
+            read_timeout: config.read_timeout(),
+            max_pipelined: config.max_pipelined,
+            drain_timeout: config.drain_timeout(),
+            max_connections: config.max_connections,
+            max_connections_per_ip: config.max_connections_per_ip,
+            open: Mutex::new(OpenConnections::default()),
+            draining: Draining(draining.shared()),
+            drain: Mutex::new(Some(drain)),
+        }
+    }
+
+    /// Returns the idle timeout in units of 100 milliseconds, as advertised with the
+    /// `edns-tcp-keepalive` option.
+    pub fn keepalive(&self) -> u16 {
+        let tenths = self.idle_timeout.as_secs() * 10
+            + u64::from(self.idle_timeout.subsec_millis() / 100);
+        if tenths > u64::from(u16::MAX) {
+            u16::MAX
+        } else {
+            tenths as u16
+        }
+    }
+
+    /// Returns how many connections are open.
+    pub fn open(&self) -> usize {
+        self.open
+            .lock()
+            .unwrap_or_else(|_| fatal!("TCP connection limits are poisoned"))
+            .total
+    }
+
+    /// Tells every open connection to close once it has finished with the queries it has been
+    /// sent, as the server is stopping.
+    pub fn drain(&self) {
+        let drain = self
+            .drain
+            .lock()
+            .unwrap_or_else(|_| fatal!("TCP connection limits are poisoned"))
+            .take();
+        if let Some(drain) = drain {
+            let _ = drain.send(());
+        }
+    }
+
+    /// Returns a signal that connections are being drained.
+    pub fn draining(&self) -> Draining {
+        self.draining.clone()
+    }
+
+    /// Reserves a connection slot for a client, or logs and returns `None` if a limit has been
+    /// reached. The slot is released when the returned guard is dropped.
+    pub fn acquire(limits: &Arc<TcpLimits>, peer: &SocketAddr) -> Option<ConnectionGuard> {
+        let ip = peer.ip();
+        let mut open = limits
+            .open
+            .lock()
+            .unwrap_or_else(|_| fatal!("TCP connection limits are poisoned"));
+        if open.total >= limits.max_connections {
+            warn!(
+                "rejecting TCP connection from {}: {} connections open",
+                peer, open.total
+            );
+            return None;
+        }
+        if open.by_ip.get(&ip).cloned().unwrap_or(0) >= limits.max_connections_per_ip {
+            warn!(
+                "rejecting TCP connection from {}: {} connections open from {}",
+                peer, limits.max_connections_per_ip, ip
+            );
+            return None;
+        }
+        open.total += 1;
+        *open.by_ip.entry(ip).or_insert(0) += 1;
+        Some(ConnectionGuard {
+            limits: limits.clone(),
+            ip,
+        })
+    }
+}
+
+/// A reserved connection slot. See [`TcpLimits::acquire`].
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    limits: Arc<TcpLimits>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.limits.open.lock() {
+            open.total -= 1;
+            let remove = match open.by_ip.get_mut(&self.ip) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if remove {
+                open.by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// The connections accepted on a listener, with the address of each client.
+///
+/// Errors accepting a connection never end the stream. Those caused by the client, such as the
+/// connection being reset before it was accepted, are skipped. Others, such as running out of
+/// file descriptors, pause accepting for a moment, as retrying straight away would fail again.
+pub struct Incoming {
+    listener: TcpListener,
+    paused: Option<Delay>,
+}
+
+impl Incoming {
+    pub fn new(listener: TcpListener) -> Incoming {
+        Incoming {
+            listener,
+            paused: None,
+        }
+    }
+}
+
+impl Stream for Incoming {
+    type Item = (TcpStream, SocketAddr);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<(TcpStream, SocketAddr)>, io::Error> {
+        loop {
+            if let Some(ref mut paused) = self.paused {
+                if let Ok(Async::NotReady) = paused.poll() {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.paused = None;
+            match self.listener.poll_accept() {
+                Ok(Async::Ready(accepted)) => return Ok(Async::Ready(Some(accepted))),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::ConnectionAborted
+                        || err.kind() == io::ErrorKind::ConnectionReset
+                        || err.kind() == io::ErrorKind::ConnectionRefused =>
+                {
+                    debug!("failed to accept TCP connection: {}", err)
+                }
+                Err(err) => {
+                    warn!(
+                        "failed to accept TCP connection, pausing for {:?}: {}",
+                        ACCEPT_ERROR_DELAY, err
+                    );
+                    self.paused = Some(Delay::new(Instant::now() + ACCEPT_ERROR_DELAY));
+                }
+            }
+        }
+    }
+}
+
+/// Tells a connection when `TcpLimits::drain` has been called.
+#[derive(Debug, Clone)]
+pub struct Draining(Shared<oneshot::Receiver<()>>);
+
+impl Draining {
+    /// Returns whether connections are being drained. If not, the current task is notified once
+    /// they are.
+    pub fn poll(&mut self) -> bool {
+        match self.0.poll() {
+            Ok(Async::NotReady) => false,
+            // The sender is only dropped along with the limits, so this cannot fail in practice
+            Ok(Async::Ready(_)) | Err(_) => true,
+        }
+    }
+}
+
+/// A DNS-over-TCP connection, answering queries with `handler` until the client closes the
+/// connection or a timeout elapses. Once connections are being drained, it is closed as soon as
+/// it is idle.
+///
+/// Queries are answered in the order they are received. Responses are buffered until they can be
+/// written; once `max_pipelined` responses are buffered, no more queries are read.
+pub struct Connection<S, F> {
+    io: S,
+    peer: SocketAddr,
+    handler: F,
+    limits: Arc<TcpLimits>,
+    _guard: ConnectionGuard,
+    draining: Draining,
+    codec: DnsCodec,
+    read_buf: BytesMut,
+    write_buf: BytesMut,
+    /// Responses in `write_buf` that have not been completely written.
+    pending: usize,
+    /// Whether responses have been written since the socket was last flushed.
+    flush_pending: bool,
+    eof: bool,
+    /// When the connection last became idle (opened, or all responses were written).
+    idle_since: Instant,
+    /// When the first part of the query being read arrived.
+    read_since: Option<Instant>,
+    timer: Delay,
+}
+
+impl<S, F> Connection<S, F>
+where
+    S: AsyncRead + AsyncWrite,
+    F: FnMut(Bytes) -> Option<Bytes>,
+{
+    pub fn new(
+        io: S,
+        peer: SocketAddr,
+        limits: Arc<TcpLimits>,
+        guard: ConnectionGuard,
+        handler: F,
+    ) -> Connection<S, F> {
+        let now = Instant::now();
+        Connection {
+            io,
+            peer,
+            handler,
+            timer: Delay::new(now + limits.idle_timeout),
+            draining: limits.draining(),
+            limits,
+            _guard: guard,
+            codec: DnsCodec::tcp(),
+            read_buf: BytesMut::new(),
+            write_buf: BytesMut::new(),
+            pending: 0,
+            flush_pending: false,
+            eof: false,
+            idle_since: now,
+            read_since: None,
+        }
+    }
+
+    /// Writes as much of the buffered responses as the socket accepts, then flushes it.
+    ///
+    /// The idle timeout advertised to clients starts once the last response has been flushed.
+    fn poll_write(&mut self) -> io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.io.poll_write(&self.write_buf)? {
+                Async::Ready(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write response",
+                    ))
+                }
+                Async::Ready(n) => {
+                    self.write_buf.advance(n);
+                    self.flush_pending = true;
+                }
+                Async::NotReady => return Ok(()),
+            }
+        }
+        self.pending = 0;
+        if self.flush_pending {
+            // A TLS stream may still hold part of a response until it is flushed
+            if let Async::NotReady = self.io.poll_flush()? {
+                return Ok(());
+            }
+            self.flush_pending = false;
+            self.idle_since = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn deadline(&self) -> Instant {
+        match self.read_since {
+            Some(since) => since + self.limits.read_timeout,
+            None => self.idle_since + self.limits.idle_timeout,
+        }
+    }
+}
+
+impl<S, F> Future for Connection<S, F>
+where
+    S: AsyncRead + AsyncWrite,
+    F: FnMut(Bytes) -> Option<Bytes>,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            self.poll_write()?;
+            if self.eof && self.write_buf.is_empty() && !self.flush_pending {
+                return self.io.shutdown();
+            }
+
+            if self.pending >= self.limits.max_pipelined {
+                debug!(
+                    "pausing reads from {}: {} responses waiting to be written",
+                    self.peer, self.pending
+                );
+                break;
+            }
+            if let Some(query) = self.codec.decode(&mut self.read_buf)? {
+                // A message too short to respond to gets no response
+                if let Some(response) = (self.handler)(query) {
+                    self.codec.encode(response, &mut self.write_buf)?;
+                    self.pending += 1;
+                }
+                // The read timeout still applies to any part of the next query already read
+                if self.read_buf.is_empty() && !self.codec.in_message() {
+                    self.read_since = None;
+                }
+                continue;
+            }
+            if self.eof {
+                // Any partial query left in the buffer will never be completed.
+                break;
+            }
+
+            self.read_buf.reserve(1024);
+            match AsyncRead::read_buf(&mut self.io, &mut self.read_buf)? {
+                Async::Ready(0) => self.eof = true,
+                Async::Ready(_) => {
+                    if self.read_since.is_none() {
+                        self.read_since = Some(Instant::now());
+                    }
+                }
+                Async::NotReady => break,
+            }
+            if self.read_buf.is_empty() && !self.codec.in_message() {
+                self.read_since = None;
+            }
+        }
+
+        let idle = self.write_buf.is_empty()
+            && !self.flush_pending
+            && self.read_buf.is_empty()
+            && !self.codec.in_message();
+        if idle && self.draining.poll() {
+            debug!("closing idle TCP connection from {}: shutting down", self.peer);
+            return Ok(Async::Ready(()));
+        }
+
+        let deadline = self.deadline();
+        if self.timer.deadline() != deadline {
+            self.timer.reset(deadline);
+        }
+        match self.timer.poll() {
+            Ok(Async::Ready(())) => {
+                info!(
+                    "closing TCP connection from {}: {} timeout",
+                    self.peer,
+                    if self.read_since.is_some() {
+                        "read"
+                    } else {
+                        "idle"
+                    }
+                );
+                Ok(Async::Ready(()))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                error!("timer error on TCP connection from {}: {}", self.peer, err);
+                Ok(Async::Ready(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::TcpLimits;
+    use config::TcpConfig;
+
+    #[test]
+    fn connection_limits() {
+        let limits = Arc::new(TcpLimits::new(&TcpConfig {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..TcpConfig::default()
+        }));
+        let a = "192.0.2.1:1234".parse().unwrap();
+        let b = "192.0.2.2:1234".parse().unwrap();
+        let a1 = TcpLimits::acquire(&limits, &a).unwrap();
+        let _a2 = TcpLimits::acquire(&limits, &a).unwrap();
+        assert!(TcpLimits::acquire(&limits, &a).is_none());
+        let _b1 = TcpLimits::acquire(&limits, &b).unwrap();
+        assert!(TcpLimits::acquire(&limits, &b).is_none());
+        drop(a1);
+        assert!(TcpLimits::acquire(&limits, &b).is_some());
+    }
+
+    #[test]
+    fn keepalive() {
+        let limits = |idle_timeout| {
+            TcpLimits::new(&TcpConfig {
+                idle_timeout,
+                ..TcpConfig::default()
+            })
+        };
+        assert_eq!(limits(10).keepalive(), 100);
+        assert_eq!(limits(6553).keepalive(), 65530);
+    }
+}
@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over TLS ([RFC 7858](https://tools.ietf.org/html/rfc7858)).
+//!
+//! Connections are framed the same as DNS over TCP and share its limits and timeouts. The same
+//! certificate is used for DNS over HTTPS. The certificate and key are read again on reload, so
+//! that renewed certificates can be picked up without a restart; connections already established
+//! keep using the old certificate.
+
+use failure::{self, ResultExt};
+use rustls::internal::pemfile;
+use rustls::{NoClientAuth, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+use config::TlsConfig;
+
+/// The ALPN protocol identifier for DNS over TLS.
+static ALPN_DOT: &[u8] = b"dot";
+/// The ALPN protocol identifiers for DNS over HTTPS, most preferred first.
+static ALPN_HTTPS: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+/// The TLS server configurations, which can be reloaded from disk while the server runs.
+pub struct Tls {
+    config: TlsConfig,
+    server_configs: RwLock<ServerConfigs>,
+}
+
+/// Server configurations sharing a certificate, differing in the protocols they negotiate.
+struct ServerConfigs {
+    dot: Arc<ServerConfig>,
+    https: Arc<ServerConfig>,
+}
+
+impl ServerConfigs {
+    fn new(config: &TlsConfig) -> Result<ServerConfigs, failure::Error> {
+        let mut dot = server_config(config)?;
+        let mut https = dot.clone();
+        dot.set_protocols(&[ALPN_DOT.to_vec()]);
+        https.set_protocols(&ALPN_HTTPS.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+        Ok(ServerConfigs {
+            dot: Arc::new(dot),
+            https: Arc::new(https),
+        })
+    }
+}
+
+impl Tls {
+    pub fn new(config: &TlsConfig) -> Result<Tls, failure::Error> {
+        Ok(Tls {
+            server_configs: RwLock::new(ServerConfigs::new(config)?),
+            config: config.clone(),
+        })
+    }
+
+    /// Reads the certificate and key again. If either cannot be loaded, the current ones remain
+    /// in use.
+    pub fn reload(&self) -> Result<(), failure::Error> {
+        let server_configs = ServerConfigs::new(&self.config)?;
+        *self
+            .server_configs
+            .write()
+            .unwrap_or_else(|_| fatal!("TLS configuration is poisoned")) = server_configs;
+        info!(
+            "loaded TLS certificate from {}",
+            self.config.certificate.display()
+        );
+        Ok(())
+    }
+
+    /// Returns an acceptor for DNS over TLS using the current certificate and key.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.server_configs
+                .read()
+                .unwrap_or_else(|_| fatal!("TLS configuration is poisoned"))
+                .dot
+                .clone(),
+        )
+    }
+
+    /// Returns an acceptor for DNS over HTTPS using the current certificate and key.
+    pub fn https_acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(
+            self.server_configs
+                .read()
+                .unwrap_or_else(|_| fatal!("TLS configuration is poisoned"))
+                .https
+                .clone(),
+        )
+    }
+}
+
+/// Builds a rustls server configuration from the configured certificate and key files, without
+/// any ALPN protocols set.
+pub fn server_config(config: &TlsConfig) -> Result<ServerConfig, failure::Error> {
+    let certs = pemfile::certs(&mut open(&config.certificate)?)
+        .map_err(|()| format_err!("failed to parse {}", config.certificate.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", config.certificate.display());
+    }
+
+    // Try PKCS #8 first, then fall back to PKCS #1 RSA keys
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(&config.key)?)
+        .map_err(|()| format_err!("failed to parse {}", config.key.display()))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(&config.key)?)
+            .map_err(|()| format_err!("failed to parse {}", config.key.display()))?;
+    }
+    let key = match keys.into_iter().next() {
+        Some(key) => key,
+        None => bail!("no private key found in {}", config.key.display()),
+    };
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(certs, key)
+        .with_context(|e| {
+            format!(
+                "failed to use certificate {} with key {}: {}",
+                config.certificate.display(),
+                config.key.display(),
+                e
+            )
+        })?;
+    Ok(server_config)
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, failure::Error> {
+    Ok(BufReader::new(File::open(path).with_context(|e| {
+        format!("failed to open {}: {}", path.display(), e)
+    })?))
+}
@@ -0,0 +1,88 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Answering queries on a UDP socket.
+
+use bytes::Bytes;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+use tokio::prelude::{Async, Future, Poll};
+
+/// The largest message that fits in a UDP datagram.
+const MAX_DATAGRAM: usize = 65535;
+
+/// A future that answers each datagram received on a socket with `handler`, which returns the
+/// response to send back, or `None` to drop the query.
+///
+/// Errors that belong to a single datagram never stop the server. A response that cannot be sent,
+/// say because the client's address is invalid, is dropped, as is an error the kernel reports for
+/// an earlier datagram. The future only resolves with an error if the socket itself fails.
+pub struct Server<F> {
+    socket: UdpSocket,
+    handler: F,
+    recv_buf: Vec<u8>,
+    /// A response that could not be sent yet because the socket's send buffer was full.
+    pending: Option<(Bytes, SocketAddr)>,
+}
+
+impl<F> Server<F>
+where
+    F: FnMut(Bytes, SocketAddr) -> Option<Bytes>,
+{
+    pub fn new(socket: UdpSocket, handler: F) -> Server<F> {
+        Server {
+            socket,
+            handler,
+            recv_buf: vec![0; MAX_DATAGRAM],
+            pending: None,
+        }
+    }
+
+    /// Sends the pending response, if any. Returns false if the socket is not ready to send it.
+    fn poll_send(&mut self) -> bool {
+        if let Some((response, client)) = self.pending.take() {
+            match self.socket.poll_send_to(&response, &client) {
+                Ok(Async::Ready(_)) => {}
+                Ok(Async::NotReady) => {
+                    self.pending = Some((response, client));
+                    return false;
+                }
+                Err(err) => debug!("failed to send UDP response to {}: {}", client, err),
+            }
+        }
+        true
+    }
+}
+
+impl<F> Future for Server<F>
+where
+    F: FnMut(Bytes, SocketAddr) -> Option<Bytes>,
+{
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            if !self.poll_send() {
+                return Ok(Async::NotReady);
+            }
+            let (len, client) = match self.socket.poll_recv_from(&mut self.recv_buf) {
+                Ok(Async::Ready(received)) => received,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(ref err)
+                    if err.kind() == io::ErrorKind::ConnectionRefused
+                        || err.kind() == io::ErrorKind::ConnectionReset
+                        || err.kind() == io::ErrorKind::Interrupted =>
+                {
+                    debug!("failed to receive UDP query: {}", err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            let query = Bytes::from(&self.recv_buf[..len]);
+            if let Some(response) = (self.handler)(query, client) {
+                self.pending = Some((response, client));
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Replacing the running server with a new process without closing its sockets.
+//!
+//! On `SIGUSR2` or an `upgrade` control request, nsd starts the binary it was started from again,
+//! with the same arguments. Every listening socket, including the control socket, is passed to the
+//! new process with the socket activation protocol described in [`systemd`], so queries are still
+//! answered while it starts. The new process is also passed one end of a socket pair, named by
+//! `PEPBUT_NSD_UPGRADE_FD`, and writes `READY=1` to it once its zones are loaded. The old process
+//! then stops accepting connections, waits for those still open to close, and exits. If the new
+//! process exits or is not ready in time, it is killed and the old process carries on.
+//!
+//! Listen addresses cannot be changed by an upgrade, as the sockets are passed as they are. The new
+//! process runs as the user the old one switched to, so upgrades are not possible with
+//! `privileges.chroot`, inside which the binary cannot be found, or with a seccomp filter, which
+//! the new process would inherit. Under systemd, the service needs `NotifyAccess=all` so that the
+//! new process can tell systemd that it is now the main process.
+//!
+//! [`systemd`]: ../systemd/index.html
+
+use cast;
+use failure::{self, ResultExt};
+use futures::sync::mpsc;
+use libc;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{self as tokio_io, ReadToEnd};
+use tokio::net::UnixStream;
+use tokio::prelude::future::{self, Either};
+use tokio::prelude::{Async, Future, Poll, Stream};
+use tokio::reactor::Handle;
+use tokio::timer::Delay;
+
+use events::Event;
+use server::Context;
+use systemd::{self, SD_LISTEN_FDS_START};
+
+/// The environment variable naming the file descriptor the new process reports readiness on.
+const UPGRADE_FD: &str = "PEPBUT_NSD_UPGRADE_FD";
+
+const READY: &[u8] = b"READY=1";
+
+/// How long the new process has to load its zones and become ready.
+const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Completes with the PID of the new process once one has taken over.
+pub type Upgraded = Box<Future<Item = u32, Error = ()> + Send>;
+
+/// Passes upgrade requests from the control socket and signal handlers to `run`.
+#[derive(Debug, Default)]
+pub struct Trigger {
+    sender: Mutex<Option<mpsc::UnboundedSender<()>>>,
+    in_progress: AtomicBool,
+}
+
+impl Trigger {
+    /// Allows upgrades to be requested, returning the requests to pass to `run`.
+    pub fn enable(&self) -> mpsc::UnboundedReceiver<()> {
+        let (sender, receiver) = mpsc::unbounded();
+        *self
+            .sender
+            .lock()
+            .unwrap_or_else(|_| fatal!("upgrade trigger is poisoned")) = Some(sender);
+        receiver
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender
+            .lock()
+            .unwrap_or_else(|_| fatal!("upgrade trigger is poisoned"))
+            .is_some()
+    }
+
+    /// Requests an upgrade. Fails if upgrades are not enabled or one is already in progress.
+    pub fn request(&self) -> Result<(), failure::Error> {
+        let sender = self
+            .sender
+            .lock()
+            .unwrap_or_else(|_| fatal!("upgrade trigger is poisoned"));
+        let sender = match *sender {
+            Some(ref sender) => sender,
+            None => bail!("upgrades are not enabled"),
+        };
+        if self.in_progress.swap(true, Ordering::SeqCst) {
+            bail!("an upgrade is already in progress");
+        }
+        sender
+            .unbounded_send(())
+            .map_err(|_| format_err!("the server is shutting down"))
+    }
+}
+
+/// Handles upgrade requests one at a time, starting `binary` with `args` and passing it `sockets`
+/// with their names.
+pub fn run(
+    requests: mpsc::UnboundedReceiver<()>,
+    binary: PathBuf,
+    args: Vec<OsString>,
+    sockets: Vec<(RawFd, String)>,
+    context: Arc<Context>,
+) -> Upgraded {
+    Box::new(
+        requests
+            .and_then(move |()| {
+                let context = context.clone();
+                info!("upgrading, starting {}", binary.display());
+                future::result(Upgrade::spawn(&binary, &args, &sockets))
+                    .and_then({
+                        let context = context.clone();
+                        move |upgrade| {
+                            info!("started process {}, waiting for it to be ready", upgrade.pid());
+                            context
+                                .events
+                                .send(&Event::UpgradeStarted { pid: upgrade.pid() });
+                            upgrade
+                        }
+                    }).then(move |result| match result {
+                        Ok(pid) => {
+                            info!("process {} is ready to take over", pid);
+                            context.events.send(&Event::Upgraded { pid });
+                            Ok(Some(pid))
+                        }
+                        Err(err) => {
+                            error!("upgrade failed: {}", err);
+                            context.events.send(&Event::UpgradeFailed {
+                                error: err.to_string(),
+                            });
+                            context.upgrade.in_progress.store(false, Ordering::SeqCst);
+                            Ok(None)
+                        }
+                    })
+            }).filter_map(|pid| pid)
+            .into_future()
+            .map_err(|_| ())
+            .and_then(|(pid, _)| match pid {
+                Some(pid) => Either::A(future::ok(pid)),
+                // Requests are never closed while the server is running
+                None => Either::B(future::empty()),
+            }),
+    )
+}
+
+/// A new process, started by an upgrade, that is not ready yet. The future completes with its PID
+/// once it is ready, or kills it and fails if it exits or does not become ready in time.
+pub struct Upgrade {
+    child: Child,
+    ready: ReadToEnd<UnixStream>,
+    deadline: Delay,
+}
+
+impl Upgrade {
+    /// Starts the new process, passing it `sockets` with their names.
+    pub fn spawn(
+        binary: &Path,
+        args: &[OsString],
+        sockets: &[(RawFd, String)],
+    ) -> Result<Upgrade, failure::Error> {
+        let (ours, theirs) = net::UnixStream::pair()?;
+        let mut fds = sockets.iter().map(|&(fd, _)| fd).collect::<Vec<_>>();
+        fds.push(theirs.as_raw_fd());
+        let end = SD_LISTEN_FDS_START + cast::i32(fds.len())?;
+        let names = sockets
+            .iter()
+            .map(|(_, name)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(":");
+
+        let mut command = Command::new(binary);
+        command
+            .args(args)
+            .env_remove("LISTEN_PID")
+            .env("LISTEN_FDS", sockets.len().to_string())
+            .env("LISTEN_FDNAMES", names)
+            .env(UPGRADE_FD, (end - 1).to_string());
+        unsafe {
+            command.pre_exec(move || pass_fds(&mut fds, end));
+        }
+        let child = command
+            .spawn()
+            .with_context(|e| format!("failed to start {}: {}", binary.display(), e))?;
+        // The child has its own copy, so the connection closes when it exits
+        drop(theirs);
+
+        Ok(Upgrade {
+            child,
+            ready: tokio_io::read_to_end(
+                UnixStream::from_std(ours, &Handle::default())?,
+                Vec::new(),
+            ),
+            deadline: Delay::new(Instant::now() + READY_TIMEOUT),
+        })
+    }
+
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Kills and reaps the new process, returning why it was killed.
+    fn abort(&mut self, reason: &str) -> failure::Error {
+        let _ = self.child.kill();
+        match self.child.wait() {
+            Ok(status) => format_err!("{}, process {} {}", reason, self.child.id(), status),
+            Err(_) => format_err!("{}", reason),
+        }
+    }
+}
+
+impl Future for Upgrade {
+    type Item = u32;
+    type Error = failure::Error;
+
+    fn poll(&mut self) -> Poll<u32, failure::Error> {
+        match self.ready.poll() {
+            Ok(Async::Ready((_, ref message))) if message.as_slice() == READY => {
+                return Ok(Async::Ready(self.child.id()))
+            }
+            Ok(Async::Ready(_)) => {
+                return Err(self.abort("the new process exited before it was ready"))
+            }
+            Ok(Async::NotReady) => {}
+            Err(err) => {
+                return Err(self.abort(&format!("failed to read from the new process: {}", err)))
+            }
+        }
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Ok(Async::Ready(())) => Err(self.abort("the new process was not ready in time")),
+            Err(err) => Err(self.abort(&err.to_string())),
+        }
+    }
+}
+
+/// Moves `fds` to consecutive file descriptors from `SD_LISTEN_FDS_START` up to `end`, in a child
+/// process about to exec. Each is first copied to `end` or above, so that none is overwritten
+/// before it is moved; the copies are closed by the exec.
+///
+/// This runs between fork and exec, so it must not allocate.
+fn pass_fds(fds: &mut [RawFd], end: RawFd) -> io::Result<()> {
+    for fd in fds.iter_mut() {
+        *fd = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, end) };
+        if *fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    for (target, fd) in (SD_LISTEN_FDS_START..end).zip(fds.iter()) {
+        if unsafe { libc::dup2(*fd, target) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The process being upgraded from, when this process was started by an upgrade.
+#[derive(Debug)]
+pub struct Parent {
+    socket: net::UnixStream,
+}
+
+impl Parent {
+    /// Takes the socket to the process being upgraded from, if there is one.
+    ///
+    /// `PEPBUT_NSD_UPGRADE_FD` is removed so that it is not inherited by child processes.
+    pub fn take() -> io::Result<Option<Parent>> {
+        let fd = match env::var(UPGRADE_FD) {
+            Ok(fd) => fd,
+            Err(_) => return Ok(None),
+        };
+        env::remove_var(UPGRADE_FD);
+        let fd = fd.parse::<RawFd>().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "invalid PEPBUT_NSD_UPGRADE_FD")
+        })?;
+        systemd::set_cloexec(fd)?;
+        Ok(Some(Parent {
+            socket: unsafe { net::UnixStream::from_raw_fd(fd) },
+        }))
+    }
+
+    /// Tells the process being upgraded from that this one is ready to take over.
+    pub fn ready(mut self) -> io::Result<()> {
+        self.socket.write_all(READY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::prelude::Stream;
+
+    use super::Trigger;
+
+    #[test]
+    fn trigger() {
+        let trigger = Trigger::default();
+        assert!(trigger.request().is_err());
+        let requests = trigger.enable();
+        trigger.request().unwrap();
+        assert!(trigger.request().is_err());
+        assert_eq!(requests.wait().next(), Some(Ok(())));
+    }
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Fixtures shared by the integration tests and benchmarks. Each includes this module, and uses
+//! only part of it.
+
+#![allow(dead_code)]
+
+use libc;
+use pepbut::authority::Authority;
+use pepbut_nsd::config::Config;
+use pepbut_nsd::server::Context;
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// A query for www.example.invalid. IN A.
+pub static QUERY: &[u8] = &[
+    0x86, 0x2a, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x77, 0x77, 0x77,
+    0x07, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x07, 0x69, 0x6e, 0x76, 0x61, 0x6c, 0x69, 0x64,
+    0x00, 0x00, 0x01, 0x00, 0x01,
+];
+
+/// `QUERY` with the length prefix used over TCP and TLS.
+pub fn tcp_query() -> Vec<u8> {
+    let mut query = vec![0, QUERY.len() as u8];
+    query.extend_from_slice(QUERY);
+    query
+}
+
+pub fn data(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/data")
+        .join(name)
+}
+
+/// Builds a server context for `config`, with example.invalid loaded.
+pub fn context(config: &Config) -> Arc<Context> {
+    let mut authority = Authority::new();
+    authority
+        .load_zonefile(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../tests/data/example.invalid.zone"
+        )).unwrap();
+    Arc::new(Context::new(Arc::new(RwLock::new(authority)), config).unwrap())
+}
+
+pub fn set_buffer_sizes<S: AsRawFd>(socket: &S, size: libc::c_int) {
+    for &option in &[libc::SO_SNDBUF, libc::SO_RCVBUF] {
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                option,
+                &size as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+    }
+}
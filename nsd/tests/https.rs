@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over HTTPS over loopback, with a self-signed certificate from `tests/data`.
+
+extern crate base64;
+extern crate bytes;
+extern crate h2;
+extern crate http;
+extern crate libc;
+extern crate pepbut;
+extern crate pepbut_nsd;
+extern crate rustls;
+extern crate tokio;
+extern crate tokio_rustls;
+extern crate webpki;
+
+mod common;
+
+use bytes::Bytes;
+use common::{context, data, QUERY};
+use http::Request;
+use pepbut_nsd::config::{Config, TlsConfig};
+use pepbut_nsd::server;
+use rustls::internal::pemfile;
+use rustls::{ClientConfig, ClientSession};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::prelude::{Future, Stream};
+use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
+use webpki::DNSNameRef;
+
+/// `QUERY` with an ID of 0, as RFC 8484 recommends.
+fn doh_query() -> Vec<u8> {
+    let mut query = QUERY.to_vec();
+    query[0] = 0;
+    query[1] = 0;
+    query
+}
+
+fn start_server() -> (Runtime, SocketAddr) {
+    let context = context(&Config {
+        tls: Some(TlsConfig {
+            certificate: data("tls-a.crt"),
+            key: data("tls-a.key"),
+        }),
+        ..Config::default()
+    });
+    let mut runtime = Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(server::https(listener, context));
+    (runtime, addr)
+}
+
+fn client_config(protocol: &[u8]) -> Arc<ClientConfig> {
+    let mut config = ClientConfig::new();
+    for cert in pemfile::certs(&mut BufReader::new(File::open(data("tls-a.crt")).unwrap())).unwrap()
+    {
+        config.root_store.add(&cert).unwrap();
+    }
+    config.set_protocols(&[protocol.to_vec()]);
+    Arc::new(config)
+}
+
+/// Sends an HTTP/1.1 request, returning the status, headers, and body of the response.
+fn http1(addr: SocketAddr, request: &[u8], body: &[u8]) -> (u16, String, Vec<u8>) {
+    let mut session = ClientSession::new(
+        &client_config(b"http/1.1"),
+        DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+    );
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut stream = rustls::Stream::new(&mut session, &mut socket);
+    stream.write_all(request).unwrap();
+    stream.write_all(b"Host: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    stream.write_all(body).unwrap();
+
+    let mut response = Vec::new();
+    // The server closes the connection without a close_notify
+    let _ = stream.read_to_end(&mut response);
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .expect("incomplete response");
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let status = head[9..12].parse().unwrap();
+    (status, head.to_lowercase(), response[split + 4..].to_vec())
+}
+
+#[test]
+fn https_http1() {
+    let (runtime, addr) = start_server();
+    let query = doh_query();
+    let encoded = base64::encode_config(&query, base64::URL_SAFE_NO_PAD);
+
+    let (status, head, get_body) = http1(
+        addr,
+        format!("GET /dns-query?dns={} HTTP/1.1\r\n", encoded).as_bytes(),
+        b"",
+    );
+    assert_eq!(status, 200);
+    assert!(head.contains("content-type: application/dns-message"));
+    assert!(head.contains("cache-control: max-age=300"));
+    // ID, QR + AA, NOERROR, one answer
+    assert_eq!(&get_body[..4], &[0x00, 0x00, 0x84, 0x00]);
+    assert_eq!(&get_body[6..8], &[0x00, 0x01]);
+
+    let (status, _, post_body) = http1(
+        addr,
+        format!(
+            "POST /dns-query HTTP/1.1\r\nContent-Type: application/dns-message\r\n\
+             Content-Length: {}\r\n",
+            query.len()
+        ).as_bytes(),
+        &query,
+    );
+    assert_eq!(status, 200);
+    assert_eq!(post_body, get_body);
+
+    let (status, _, _) = http1(addr, b"GET /dns-query?dns=!!! HTTP/1.1\r\n", b"");
+    assert_eq!(status, 400);
+    let (status, _, _) = http1(addr, b"GET /dns-query?dns=AAAA HTTP/1.1\r\n", b"");
+    assert_eq!(status, 400);
+    let (status, _, _) = http1(addr, b"GET /resolve HTTP/1.1\r\n", b"");
+    assert_eq!(status, 404);
+    let (status, head, _) = http1(addr, b"PUT /dns-query HTTP/1.1\r\n", b"");
+    assert_eq!(status, 405);
+    assert!(head.contains("allow: get, post"));
+    let (status, _, _) = http1(
+        addr,
+        b"POST /dns-query HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n",
+        b"hi",
+    );
+    assert_eq!(status, 415);
+
+    runtime.shutdown_now().wait().unwrap();
+}
+
+#[test]
+fn https_http2() {
+    let (mut runtime, addr) = start_server();
+    let connector = TlsConnector::from(client_config(b"h2"));
+    let request = Request::get(format!(
+        "https://localhost/dns-query?dns={}",
+        base64::encode_config(&doh_query(), base64::URL_SAFE_NO_PAD)
+    )).body(())
+    .unwrap();
+
+    let (status, cache_control, body) = runtime
+        .block_on(
+            tokio::net::TcpStream::connect(&addr)
+                .and_then(move |tcp| {
+                    connector.connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), tcp)
+                }).map_err(|e| panic!("{}", e))
+                .and_then(h2::client::handshake)
+                .and_then(move |(client, connection)| {
+                    tokio::spawn(connection.map_err(|e| panic!("{}", e)));
+                    let (response, _) = client.clone().send_request(request, true).unwrap();
+                    response
+                }).and_then(|response| {
+                    let status = response.status().as_u16();
+                    let cache_control = response.headers()["cache-control"].clone();
+                    response
+                        .into_body()
+                        .concat2()
+                        .map(move |body: Bytes| (status, cache_control, body))
+                }),
+        ).unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(cache_control, "max-age=300");
+    assert_eq!(&body[..4], &[0x00, 0x00, 0x84, 0x00]);
+
+    runtime.shutdown_now().wait().unwrap();
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! The seccomp filter, installed in forked children so that the test harness is unaffected.
+
+extern crate libc;
+extern crate pepbut_nsd;
+
+use pepbut_nsd::config::SeccompAction;
+use pepbut_nsd::seccomp::Filter;
+
+/// Forks a child that installs the filter, then calls `f` and exits successfully. Returns the
+/// child's wait status.
+fn filtered<F: FnOnce()>(action: SeccompAction, f: F) -> libc::c_int {
+    // Built before forking, as the child must not allocate while other threads may hold locks
+    let filter = Filter::new(action).unwrap();
+    match unsafe { libc::fork() } {
+        -1 => panic!("fork failed"),
+        0 => unsafe {
+            if filter.install().is_err() {
+                libc::_exit(2);
+            }
+            f();
+            libc::_exit(0);
+        },
+        pid => {
+            let mut status = 0;
+            assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+            status
+        }
+    }
+}
+
+/// Changing user is never needed once nsd is running.
+fn forbidden() {
+    unsafe {
+        libc::syscall(libc::SYS_setuid, libc::getuid());
+    }
+}
+
+#[test]
+fn allowed() {
+    let status = filtered(SeccompAction::Kill, || unsafe {
+        libc::getpid();
+    });
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+}
+
+#[test]
+fn kill() {
+    let status = filtered(SeccompAction::Kill, forbidden);
+    assert!(libc::WIFSIGNALED(status));
+    assert_eq!(libc::WTERMSIG(status), libc::SIGSYS);
+}
+
+#[test]
+fn log() {
+    let status = filtered(SeccompAction::Log, forbidden);
+    assert!(libc::WIFEXITED(status));
+    assert_eq!(libc::WEXITSTATUS(status), 0);
+}
@@ -0,0 +1,165 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over TCP over loopback.
+
+extern crate libc;
+extern crate net2;
+extern crate pepbut;
+extern crate pepbut_nsd;
+extern crate tokio;
+
+mod common;
+
+use common::{context, set_buffer_sizes, tcp_query, QUERY};
+use net2::TcpBuilder;
+use pepbut_nsd::config::{Config, Transport};
+use pepbut_nsd::server::{self, Context};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+fn start_server(config: &Config) -> (Runtime, SocketAddr, Arc<Context>) {
+    let context = context(config);
+    let mut runtime = Runtime::new().unwrap();
+    let listener = server::bind_tcp(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    // Accepted sockets inherit the listener's buffer sizes
+    set_buffer_sizes(&listener, 4096);
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(server::serve_tcp(listener, Transport::Tcp, context.clone()).unwrap());
+    (runtime, addr, context)
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let socket = TcpStream::connect(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(15)))
+        .unwrap();
+    socket
+}
+
+/// Asserts that the server closes the connection within `min` to `max` from now.
+fn assert_closed(socket: &mut TcpStream, min: Duration, max: Duration) {
+    let start = Instant::now();
+    assert_eq!(socket.read(&mut [0; 1]).unwrap(), 0);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= min, "closed after {:?}", elapsed);
+    assert!(elapsed < max, "closed after {:?}", elapsed);
+}
+
+/// Reads one length-prefixed response.
+fn read_response(socket: &mut TcpStream) -> Vec<u8> {
+    let mut len = [0; 2];
+    socket.read_exact(&mut len).unwrap();
+    let mut response = vec![0; (usize::from(len[0]) << 8) | usize::from(len[1])];
+    socket.read_exact(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn drain() {
+    let (_runtime, addr, context) = start_server(&Config::default());
+    let mut socket = TcpStream::connect(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    socket.write_all(&tcp_query()).unwrap();
+    let response = read_response(&mut socket);
+    assert_eq!(&response[..2], &QUERY[..2]);
+
+    // The connection is idle, so it is closed long before the idle timeout
+    let start = Instant::now();
+    context.tcp.drain();
+    assert_eq!(socket.read(&mut [0; 1]).unwrap(), 0);
+    assert!(start.elapsed() < context.tcp.idle_timeout);
+    while context.tcp.open() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn idle_timeout() {
+    let mut config = Config::default();
+    config.tcp.idle_timeout = 1;
+    let (_runtime, addr, _context) = start_server(&config);
+    let mut socket = connect(addr);
+    socket.write_all(&tcp_query()).unwrap();
+    read_response(&mut socket);
+    assert_closed(&mut socket, Duration::from_millis(500), Duration::from_secs(5));
+}
+
+#[test]
+fn read_timeout() {
+    let mut config = Config::default();
+    config.tcp.idle_timeout = 10;
+    config.tcp.read_timeout = 1;
+    let (_runtime, addr, _context) = start_server(&config);
+    let mut socket = connect(addr);
+    socket.write_all(&tcp_query()[..10]).unwrap();
+    assert_closed(&mut socket, Duration::from_millis(500), Duration::from_secs(5));
+}
+
+#[test]
+fn read_timeout_after_query() {
+    let mut config = Config::default();
+    config.tcp.idle_timeout = 10;
+    config.tcp.read_timeout = 1;
+    let (_runtime, addr, _context) = start_server(&config);
+    let mut socket = connect(addr);
+    // The start of a second query arrives along with the first, and is never finished
+    let mut queries = tcp_query();
+    queries.extend_from_slice(&tcp_query()[..10]);
+    socket.write_all(&queries).unwrap();
+    let response = read_response(&mut socket);
+    assert_eq!(&response[..2], &QUERY[..2]);
+    assert_closed(&mut socket, Duration::from_millis(500), Duration::from_secs(5));
+}
+
+#[test]
+fn max_pipelined() {
+    const QUERIES: usize = 5000;
+
+    let mut config = Config::default();
+    config.tcp.max_pipelined = 4;
+    let (_runtime, addr, _context) = start_server(&config);
+    let builder = TcpBuilder::new_v4().unwrap();
+    set_buffer_sizes(&builder, 4096);
+    let mut socket = builder.connect(addr).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(15)))
+        .unwrap();
+
+    let queries = tcp_query().repeat(QUERIES);
+    let mut written = 0;
+    socket.set_nonblocking(true).unwrap();
+    let start = Instant::now();
+    // Without reading any responses, the server stops reading queries once its send buffer
+    // fills, so the client's writes eventually block
+    loop {
+        match socket.write(&queries[written..]) {
+            Ok(n) => written += n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                // Give the server time to read anything still in its receive buffer
+                if start.elapsed() > Duration::from_secs(1) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            Err(err) => panic!("{}", err),
+        }
+        assert!(written < queries.len(), "the server read every query");
+    }
+
+    // Reading responses lets the server read the rest of the queries
+    socket.set_nonblocking(false).unwrap();
+    let mut writer = socket.try_clone().unwrap();
+    let writer = thread::spawn(move || writer.write_all(&queries[written..]).unwrap());
+    for _ in 0..QUERIES {
+        let response = read_response(&mut socket);
+        assert_eq!(&response[..2], &QUERY[..2]);
+    }
+    writer.join().unwrap();
+}
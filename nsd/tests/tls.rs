@@ -0,0 +1,205 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over TLS over loopback, with self-signed certificates from `tests/data`.
+
+extern crate libc;
+extern crate net2;
+extern crate pepbut;
+extern crate pepbut_nsd;
+extern crate rustls;
+extern crate tokio;
+extern crate webpki;
+
+mod common;
+
+use common::{context, data, set_buffer_sizes, tcp_query, QUERY};
+use net2::TcpBuilder;
+use pepbut_nsd::config::{Config, TlsConfig};
+use pepbut_nsd::events::Event;
+use pepbut_nsd::server::{self, Context};
+use rustls::internal::pemfile;
+use rustls::{ClientConfig, ClientSession, Session};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::prelude::{Future, Stream};
+use tokio::runtime::Runtime;
+use webpki::DNSNameRef;
+
+fn start_server(tls: TlsConfig) -> (Runtime, SocketAddr, Arc<Context>) {
+    let context = context(&Config {
+        tls: Some(tls),
+        ..Config::default()
+    });
+    let mut runtime = Runtime::new().unwrap();
+    let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+    // Accepted sockets inherit the listener's buffer sizes
+    set_buffer_sizes(&listener, 4096);
+    let addr = listener.local_addr().unwrap();
+    runtime.spawn(server::tls(listener, context.clone()));
+    (runtime, addr, context)
+}
+
+/// Completes a TLS handshake with the server on `socket`, trusting only the given certificate.
+fn handshake(
+    socket: &mut TcpStream,
+    trusted: &Path,
+) -> Result<ClientSession, rustls::TLSError> {
+    let mut config = ClientConfig::new();
+    for cert in pemfile::certs(&mut BufReader::new(File::open(trusted).unwrap())).unwrap() {
+        config.root_store.add(&cert).unwrap();
+    }
+    config.set_protocols(&[b"dot".to_vec()]);
+    let mut session = ClientSession::new(
+        &Arc::new(config),
+        DNSNameRef::try_from_ascii_str("localhost").unwrap(),
+    );
+
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    while session.is_handshaking() {
+        session.complete_io(socket).map_err(|err| {
+            match err.into_inner().map(|err| err.downcast::<rustls::TLSError>()) {
+                Some(Ok(err)) => *err,
+                _ => rustls::TLSError::HandshakeNotComplete,
+            }
+        })?;
+    }
+    assert_eq!(session.get_alpn_protocol(), Some(&b"dot"[..]));
+    Ok(session)
+}
+
+/// Sends a query to the server, trusting only the given certificate, and returns the response.
+fn query(addr: SocketAddr, trusted: &Path) -> Result<Vec<u8>, rustls::TLSError> {
+    let mut socket = TcpStream::connect(addr).unwrap();
+    let mut session = handshake(&mut socket, trusted)?;
+
+    let mut stream = rustls::Stream::new(&mut session, &mut socket);
+    stream.write_all(&tcp_query()).unwrap();
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).unwrap();
+    let mut response = vec![0; (usize::from(len[0]) << 8) | usize::from(len[1])];
+    stream.read_exact(&mut response).unwrap();
+    Ok(response)
+}
+
+#[test]
+fn tls_query_and_reload() {
+    let dir = env::temp_dir().join(format!("pepbut-nsd-tls-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let tls = TlsConfig {
+        certificate: dir.join("tls.crt"),
+        key: dir.join("tls.key"),
+    };
+    fs::copy(data("tls-a.crt"), &tls.certificate).unwrap();
+    fs::copy(data("tls-a.key"), &tls.key).unwrap();
+
+    let (runtime, addr, context) = start_server(tls.clone());
+    let events = context.events.subscribe();
+
+    let response = query(addr, &data("tls-a.crt")).unwrap();
+    // ID, QR + AA, NOERROR, one answer
+    assert_eq!(&response[..4], &[0x86, 0x2a, 0x84, 0x00]);
+    assert_eq!(&response[6..8], &[0x00, 0x01]);
+    assert!(query(addr, &data("tls-b.crt")).is_err());
+
+    // A failed reload keeps the old certificate
+    fs::write(&tls.key, "").unwrap();
+    assert!(context.reload_tls().unwrap().is_err());
+    assert!(query(addr, &data("tls-a.crt")).is_ok());
+
+    fs::copy(data("tls-b.crt"), &tls.certificate).unwrap();
+    fs::copy(data("tls-b.key"), &tls.key).unwrap();
+    context.reload_tls().unwrap().unwrap();
+    assert!(query(addr, &data("tls-b.crt")).is_ok());
+    assert!(query(addr, &data("tls-a.crt")).is_err());
+
+    context.events.shutdown();
+    let events = events.wait().collect::<Result<Vec<_>, _>>().unwrap();
+    match events[..] {
+        [Event::TlsReloadFailed { .. }, Event::TlsReloaded, Event::Shutdown] => {}
+        _ => panic!("unexpected events: {:?}", events),
+    }
+
+    runtime.shutdown_now().wait().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn small_buffers() {
+    const QUERIES: usize = 2000;
+
+    let (_runtime, addr, _context) = start_server(TlsConfig {
+        certificate: data("tls-a.crt"),
+        key: data("tls-a.key"),
+    });
+    let builder = TcpBuilder::new_v4().unwrap();
+    set_buffer_sizes(&builder, 4096);
+    let mut socket = builder.connect(addr).unwrap();
+    let mut session = handshake(&mut socket, &data("tls-a.crt")).unwrap();
+
+    // Every query is sent before any response is read, followed by the end of the connection.
+    // The responses only fit in the socket buffers a few at a time, so the server has to wait
+    // for each to be flushed before it closes the connection.
+    session.write_all(&tcp_query().repeat(QUERIES)).unwrap();
+    session.send_close_notify();
+    socket.set_nonblocking(true).unwrap();
+    let mut shut_down = false;
+    let mut responses = Vec::new();
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(15), "timed out");
+        let mut blocked = true;
+        if session.wants_write() {
+            match session.write_tls(&mut socket) {
+                Ok(_) => blocked = false,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => panic!("{}", err),
+            }
+        } else if !shut_down {
+            socket.shutdown(Shutdown::Write).unwrap();
+            shut_down = true;
+        }
+        match session.read_tls(&mut socket) {
+            Ok(0) => break,
+            Ok(_) => {
+                session.process_new_packets().unwrap();
+                blocked = false;
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => panic!("{}", err),
+        }
+        let mut buf = [0; 4096];
+        match session.read(&mut buf) {
+            Ok(n) => responses.extend_from_slice(&buf[..n]),
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionAborted => {}
+            Err(err) => panic!("{}", err),
+        }
+        if blocked {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    let mut count = 0;
+    let mut rest = &responses[..];
+    while !rest.is_empty() {
+        let len = (usize::from(rest[0]) << 8) | usize::from(rest[1]);
+        assert_eq!(&rest[2..4], &QUERY[..2]);
+        rest = &rest[2 + len..];
+        count += 1;
+    }
+    assert_eq!(count, QUERIES);
+    // The server ended the session with a close_notify alert, rather than just closing the socket
+    assert_eq!(
+        session.read(&mut [0; 1]).unwrap_err().kind(),
+        io::ErrorKind::ConnectionAborted
+    );
+}
@@ -0,0 +1,65 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! DNS over UDP over loopback.
+
+extern crate bytes;
+extern crate pepbut_nsd;
+extern crate tokio;
+
+use bytes::Bytes;
+use pepbut_nsd::server::{self, ServerFuture};
+use pepbut_nsd::udp;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::prelude::future::{self, Future};
+use tokio::reactor::Handle;
+use tokio::runtime::Runtime;
+
+#[test]
+fn send_error() {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let socket = tokio::net::UdpSocket::from_std(socket, &Handle::default()).unwrap();
+    // A response too large for a datagram cannot be sent
+    let server = udp::Server::new(socket, |query: Bytes, _| {
+        if query[0] == 0 {
+            Some(Bytes::from(vec![0; 70000]))
+        } else {
+            Some(query)
+        }
+    });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server.map_err(|e| panic!("UDP server failed: {}", e)));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    client.send_to(&[0], addr).unwrap();
+    client.send_to(&[1], addr).unwrap();
+    let mut buf = [0; 16];
+    let (len, _) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &[1]);
+}
+
+#[test]
+fn restart_after_panic() {
+    let starts = Arc::new(AtomicUsize::new(0));
+    let server = server::restarting("test server".to_owned(), {
+        let starts = starts.clone();
+        move || {
+            let server: ServerFuture = if starts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::new(future::lazy(|| -> Result<(), ()> { panic!("test panic") }))
+            } else {
+                Box::new(future::empty())
+            };
+            Ok(server)
+        }
+    });
+    let mut runtime = Runtime::new().unwrap();
+    runtime.spawn(server);
+    std::thread::sleep(Duration::from_secs(2));
+    assert_eq!(starts.load(Ordering::SeqCst), 2);
+}
@@ -3,35 +3,151 @@
 use bytes::{Buf, Bytes, BytesMut};
 use failure;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{Cursor, Read, Seek};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use name::Name;
-use record::RData;
-use wire::{encode_err, ProtocolDecode, ProtocolEncode, QueryMessage};
-use zone::{LookupResult, Zone};
+use record::{RData, Record, RecordTrait};
+use shadow::{Shadow, ShadowReport};
+use stats::{Outcome, ZoneCounters, ZoneStats};
+use wire::{encode_err, Edns, ProtocolDecode, ProtocolEncode, QueryMessage, ResponseSummary};
+use zone::{LookupResult, SOAParams, Zone};
 
 #[derive(Debug, Default)]
 pub struct Authority {
     pub zones: HashMap<Name, Zone>,
+    /// SOA values given to zones as they are loaded.
+    pub soa: Arc<SOAParams>,
+    /// Query statistics for each zone origin. These are kept when a zone is reloaded.
+    stats: HashMap<Name, ZoneCounters>,
+    /// The file each zone was loaded from.
+    sources: HashMap<Name, Source>,
+    /// Zones that have been loaded, but do not answer queries until they are activated.
+    staged: HashMap<Name, Staged>,
+    /// The number of times the loaded zone for each origin has been replaced, by loading,
+    /// activating, or updating it.
+    generations: HashMap<Name, u64>,
+}
+
+/// A response from `Authority::answer`.
+#[derive(Debug)]
+pub struct Answered<'a> {
+    pub response: Bytes,
+    /// The header and question of the response. Error responses without a question are sent for
+    /// queries that could not be decoded.
+    pub summary: ResponseSummary,
+    /// The zone the query was answered from.
+    pub zone: Option<&'a Zone>,
+}
+
+impl<'a> Answered<'a> {
+    fn error(response: Bytes) -> Answered<'a> {
+        Answered {
+            summary: ResponseSummary::from_header(&response, None),
+            response,
+            zone: None,
+        }
+    }
+}
+
+/// An updated copy of a loaded zone, from `Authority::prepare_update`.
+#[derive(Debug)]
+pub struct ZoneUpdate {
+    zone: Zone,
+    /// The generation of the zone the update was made to.
+    generation: u64,
+    /// The file the zone was loaded from.
+    source: Option<Source>,
+}
+
+impl ZoneUpdate {
+    /// Writes the updated zone to the file it was loaded from.
+    pub fn write(&self) -> Result<(), failure::Error> {
+        match self.source {
+            Some(Source::Path(ref path)) => write_zonefile(&self.zone, path),
+            Some(Source::OutsideRoot(ref path)) => bail!(
+                "zone {} was loaded from {}, which is outside the chroot",
+                self.zone.origin,
+                path.display()
+            ),
+            None => bail!("zone {} was not loaded from a file", self.zone.origin),
+        }
+    }
+}
+
+/// The file a zone was loaded from.
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Path(PathBuf),
+    /// A file that cannot be reached since the process changed its root directory.
+    OutsideRoot(PathBuf),
+}
+
+/// How a query arrived, which decides how large its response can be.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Channel {
+    /// UDP. Responses larger than the client can receive are truncated.
+    Datagram,
+    /// A connection-oriented transport such as TCP or TLS, with the connection's idle timeout in
+    /// units of 100 milliseconds. The timeout is sent to clients that include the
+    /// `edns-tcp-keepalive` option ([RFC 7828](https://tools.ietf.org/html/rfc7828)) in their
+    /// queries.
+    Stream(u16),
+    /// A transport without a message size limit or keepalive negotiation, such as DNS over HTTPS.
+    Unlimited,
+}
+
+/// Which zones answer a query, and whether it counts towards query statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Answer {
+    Live,
+    Unrecorded,
+    Staged,
+}
+
+#[derive(Debug)]
+struct Staged {
+    zone: Zone,
+    path: PathBuf,
+    /// Comparison with the loaded zone, if it has been started.
+    shadow: Option<Shadow>,
 }
 
 impl Authority {
     pub fn new() -> Authority {
+        Authority::with_soa(SOAParams::default())
+    }
+
+    /// Creates an authority that uses the given SOA values for the zones it loads.
+    pub fn with_soa(soa: SOAParams) -> Authority {
         Authority {
             zones: HashMap::new(),
+            soa: Arc::new(soa),
+            stats: HashMap::new(),
+            sources: HashMap::new(),
+            staged: HashMap::new(),
+            generations: HashMap::new(),
         }
     }
 
+    /// Reads a zone, giving it the authority's SOA values.
+    fn read_zone(&self, reader: &mut (impl Read + Seek)) -> Result<Zone, failure::Error> {
+        let mut zone = Zone::read_from(reader)?;
+        zone.soa = self.soa.clone();
+        Ok(zone)
+    }
+
     /// Loads a zone into the authority from a reader. Returns a tuple of the origin and serial.
     fn load_zone(
         &mut self,
         reader: &mut (impl Read + Seek),
     ) -> Result<(Name, u32), failure::Error> {
-        let zone = Zone::read_from(reader)?;
+        let zone = self.read_zone(reader)?;
         let ret = (zone.origin.clone(), zone.serial);
-        self.zones.insert(ret.0.clone(), zone);
+        self.stats.entry(ret.0.clone()).or_default();
+        self.replace_zone(zone);
         Ok(ret)
     }
 
@@ -41,12 +157,223 @@ impl Authority {
         path: P,
     ) -> Result<(Name, u32), failure::Error> {
         info!("loading zone from {}", path.as_ref().display());
-        self.load_zone(&mut File::open(path)?)
+        let (origin, serial) = self.load_zone(&mut File::open(&path)?)?;
+        self.sources
+            .insert(origin.clone(), Source::Path(path.as_ref().to_path_buf()));
+        Ok((origin, serial))
+    }
+
+    /// Adjusts the paths zones were loaded from after the process has changed its root directory
+    /// to `root`, so that updates can still be written to them. Returns the origins of zones whose
+    /// files are outside `root`; updates to those zones cannot be written.
+    pub fn chroot(&mut self, root: &Path) -> Vec<Name> {
+        let mut outside = Vec::new();
+        for (origin, source) in &mut self.sources {
+            let rebased = match *source {
+                Source::Path(ref path) => match path.strip_prefix(root) {
+                    Ok(rest) => Source::Path(Path::new("/").join(rest)),
+                    Err(_) => {
+                        outside.push(origin.clone());
+                        Source::OutsideRoot(path.clone())
+                    }
+                },
+                Source::OutsideRoot(_) => continue,
+            };
+            *source = rebased;
+        }
+        outside
+    }
+
+    /// Loads a zone file into the staging slot for its origin, replacing any zone already staged
+    /// there. Returns a tuple of the origin and serial.
+    ///
+    /// A staged zone does not answer queries, except those passed to `process_staged_message`,
+    /// until it is activated.
+    pub fn stage_zonefile<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Result<(Name, u32), failure::Error> {
+        info!("staging zone from {}", path.as_ref().display());
+        let zone = self.read_zone(&mut File::open(&path)?)?;
+        let ret = (zone.origin.clone(), zone.serial);
+        self.staged.insert(
+            ret.0.clone(),
+            Staged {
+                zone,
+                path: path.as_ref().to_path_buf(),
+                shadow: None,
+            },
+        );
+        Ok(ret)
+    }
+
+    /// Returns the zone staged for an origin.
+    pub fn staged_zone(&self, origin: &Name) -> Option<&Zone> {
+        self.staged.get(origin).map(|staged| &staged.zone)
+    }
+
+    /// Replaces the loaded zone for an origin with the zone staged for it, as if the staged zone
+    /// file had been loaded. Returns the serial, or `None` if no zone is staged for the origin.
+    pub fn activate_zone(&mut self, origin: &Name) -> Option<u32> {
+        let staged = self.staged.remove(origin)?;
+        let serial = staged.zone.serial;
+        info!("activating staged zone {} with serial {}", origin, serial);
+        self.stats.entry(origin.clone()).or_default();
+        self.replace_zone(staged.zone);
+        self.sources
+            .insert(origin.clone(), Source::Path(staged.path));
+        Some(serial)
+    }
+
+    /// Discards the zone staged for an origin. Returns `false` if no zone is staged for it.
+    pub fn discard_zone(&mut self, origin: &Name) -> bool {
+        self.staged.remove(origin).is_some()
+    }
+
+    /// Starts comparing the answers to one in `sample_every` queries for the loaded zone with the
+    /// answers from the zone staged for the same origin, or stops if `sample_every` is 0. Earlier
+    /// results are discarded. Returns `false` if no zone is staged for the origin.
+    ///
+    /// The answers sent to clients always come from the loaded zone.
+    pub fn shadow_zone(&mut self, origin: &Name, sample_every: u32) -> bool {
+        match self.staged.get_mut(origin) {
+            Some(staged) => {
+                staged.shadow = if sample_every == 0 {
+                    None
+                } else {
+                    Some(Shadow::new(sample_every))
+                };
+                true
+            }
+            None => false,
+        }
     }
 
-    fn find_zone(&self, name: &Name) -> Option<&Zone> {
+    /// Returns the results of comparing the zone staged for an origin with the loaded zone, if
+    /// comparison has been started with `shadow_zone`.
+    pub fn shadow_report(&self, origin: &Name) -> Option<ShadowReport> {
+        self.staged
+            .get(origin)
+            .and_then(|staged| staged.shadow.as_ref().map(Shadow::report))
+    }
+
+    /// Removes and adds records in a loaded zone, and increments its serial. Returns the new
+    /// serial.
+    ///
+    /// Records to remove are matched by name, type, and data; their TTL is ignored. If any record
+    /// to remove does not exist, or any record to add is outside the zone or already exists, the
+    /// zone is left unchanged.
+    ///
+    /// If `write` is true, the updated zone is also written to the file it was loaded from. The
+    /// zone is left unchanged if this fails.
+    ///
+    /// Writing the file is slow, so a caller sharing the authority behind a lock should instead
+    /// call `prepare_update` under a read lock, then `ZoneUpdate::write` without holding the lock,
+    /// and only take a write lock for `apply_update`. The caller must keep other changes to the
+    /// zone from being made in between, and can call `check_update` before writing to make sure
+    /// none were.
+    pub fn update_zone(
+        &mut self,
+        origin: &Name,
+        remove: &[Record],
+        add: &[Record],
+        write: bool,
+    ) -> Result<u32, failure::Error> {
+        let update = self.prepare_update(origin, remove, add)?;
+        if write {
+            update.write()?;
+        }
+        self.apply_update(update)
+    }
+
+    /// Builds an update to a loaded zone as described for `update_zone`, without changing the
+    /// zone.
+    pub fn prepare_update(
+        &self,
+        origin: &Name,
+        remove: &[Record],
+        add: &[Record],
+    ) -> Result<ZoneUpdate, failure::Error> {
+        let mut zone = match self.zones.get(origin) {
+            Some(zone) => zone.clone(),
+            None => bail!("zone {} is not loaded", origin),
+        };
+        for record in remove {
+            let found = match find_record(&zone, record) {
+                Some(found) => found.clone(),
+                None => bail!("record does not exist: {} {}", record.name(), record.rdata()),
+            };
+            zone.remove(&found);
+        }
+        for record in add {
+            if !record.name().ends_with(origin) {
+                bail!("{} is not in zone {}", record.name(), origin);
+            }
+            if find_record(&zone, record).is_some() {
+                bail!("record already exists: {} {}", record.name(), record.rdata());
+            }
+            zone.push(record.clone());
+        }
+        zone.serial = zone.serial.wrapping_add(1);
+        Ok(ZoneUpdate {
+            zone,
+            generation: self.generation(origin),
+            source: self.sources.get(origin).cloned(),
+        })
+    }
+
+    /// Replaces a loaded zone with the result of `prepare_update`. Returns the new serial.
+    ///
+    /// Fails, leaving the zone unchanged, if the zone has been replaced since the update was
+    /// prepared.
+    pub fn apply_update(&mut self, update: ZoneUpdate) -> Result<u32, failure::Error> {
+        self.check_update(&update)?;
+        let origin = update.zone.origin.clone();
+        let serial = update.zone.serial;
+        self.replace_zone(update.zone);
+        info!("updated zone {} to serial {}", origin, serial);
+        Ok(serial)
+    }
+
+    /// Fails if the zone an update was prepared for has been loaded, activated, or updated since,
+    /// in which case applying or writing the update would discard those changes.
+    pub fn check_update(&self, update: &ZoneUpdate) -> Result<(), failure::Error> {
+        let origin = &update.zone.origin;
+        if !self.zones.contains_key(origin) {
+            bail!("zone {} is not loaded", origin);
+        }
+        if self.generation(origin) != update.generation {
+            bail!("zone {} changed while it was being updated", origin);
+        }
+        Ok(())
+    }
+
+    fn generation(&self, origin: &Name) -> u64 {
+        self.generations.get(origin).cloned().unwrap_or(0)
+    }
+
+    /// Replaces the loaded zone for its origin, invalidating updates prepared for it.
+    fn replace_zone(&mut self, zone: Zone) {
+        *self.generations.entry(zone.origin.clone()).or_insert(0) += 1;
+        self.zones.insert(zone.origin.clone(), zone);
+    }
+
+    /// Returns the zone a name belongs to, which is the loaded zone with the longest matching
+    /// origin.
+    pub fn find_zone(&self, name: &Name) -> Option<&Zone> {
+        self.find_zone_in(name, false)
+    }
+
+    /// Like `find_zone`, but if `staged` is true, zones that are staged take the place of the
+    /// loaded zones with the same origin.
+    fn find_zone_in(&self, name: &Name, staged: bool) -> Option<&Zone> {
         let mut name = name.clone();
         while !name.is_empty() {
+            if staged {
+                if let Some(zone) = self.staged_zone(&name) {
+                    return Some(zone);
+                }
+            }
             if let Some(zone) = self.zones.get(&name) {
                 return Some(zone);
             }
@@ -55,21 +382,153 @@ impl Authority {
         None
     }
 
-    pub fn process_message(&self, buf: Bytes) -> Bytes {
+    /// Returns the query statistics for a loaded zone.
+    pub fn zone_stats(&self, origin: &Name) -> Option<ZoneStats> {
+        if !self.zones.contains_key(origin) {
+            return None;
+        }
+        Some(
+            self.stats
+                .get(origin)
+                .map(ZoneCounters::snapshot)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Processes a query received over UDP, returning the response. A message too short to have an
+    /// ID cannot be responded to, so `None` is returned for it.
+    ///
+    /// Responses larger than the client can receive are truncated, leaving the client to retry
+    /// over TCP.
+    pub fn process_message(&self, buf: Bytes) -> Option<Bytes> {
+        self.respond(buf, Channel::Datagram, Answer::Live)
+            .map(|answered| answered.response)
+    }
+
+    /// Processes a query received over `channel`, and also returns what is needed to log and
+    /// count the response.
+    pub fn answer(&self, buf: Bytes, channel: Channel) -> Option<Answered<'_>> {
+        self.respond(buf, channel, Answer::Live)
+    }
+
+    /// Processes a query without recording query statistics or comparing the answer with a staged
+    /// zone, for queries that did not come from a client.
+    pub fn process_unrecorded_message(&self, buf: Bytes) -> Option<Bytes> {
+        self.respond(buf, Channel::Unlimited, Answer::Unrecorded)
+            .map(|answered| answered.response)
+    }
+
+    /// Processes a query as if every staged zone had been activated. Query statistics are not
+    /// recorded.
+    pub fn process_staged_message(&self, buf: Bytes) -> Option<Bytes> {
+        self.respond(buf, Channel::Unlimited, Answer::Staged)
+            .map(|answered| answered.response)
+    }
+
+    /// Processes a query received over a connection-oriented transport.
+    ///
+    /// `keepalive` is the connection's idle timeout in units of 100 milliseconds, which is sent to
+    /// clients that include the `edns-tcp-keepalive` option
+    /// ([RFC 7828](https://tools.ietf.org/html/rfc7828)) in their queries.
+    pub fn process_tcp_message(&self, buf: Bytes, keepalive: u16) -> Option<Bytes> {
+        self.respond(buf, Channel::Stream(keepalive), Answer::Live)
+            .map(|answered| answered.response)
+    }
+
+    fn respond(&self, buf: Bytes, channel: Channel, answer: Answer) -> Option<Answered<'_>> {
+        let keepalive = match channel {
+            Channel::Stream(keepalive) => Some(keepalive),
+            _ => None,
+        };
         let mut buf = Cursor::new(buf);
         let query = match QueryMessage::decode(&mut buf) {
             Ok(query) => query,
-            Err(_) => return encode_err(buf.get_u16_be(), 1),
+            Err(_) => {
+                // The ID is at the start of the message, wherever decoding stopped
+                if buf.get_ref().len() < 2 {
+                    return None;
+                }
+                buf.set_position(0);
+                return Some(Answered::error(encode_err(buf.get_u16_be(), 1)));
+            }
+        };
+        // RFC 7828 § 3.2.1: clients must not send a timeout. The option means nothing over UDP
+        // and is ignored there.
+        let wants_keepalive = match query.edns {
+            Some(Edns {
+                tcp_keepalive: Some(Some(_)),
+                ..
+            }) if keepalive.is_some() => return Some(Answered::error(encode_err(query.id, 1))),
+            Some(Edns {
+                tcp_keepalive: Some(_),
+                ..
+            }) => true,
+            _ => false,
         };
         let name = query.name.clone();
         let record_type = query.record_type;
-        let lookup = match self.find_zone(&name) {
-            Some(zone) => zone.lookup(&name, record_type),
+        let staged = answer == Answer::Staged;
+        let zone = self.find_zone_in(&name, staged);
+        let lookup = self.lookup(zone, &name, record_type, staged);
+        if let (Some(zone), Answer::Live) = (zone, answer) {
+            let outcome = match lookup {
+                LookupResult::NoName(_) => Outcome::NoName,
+                LookupResult::NameExists(_) => Outcome::NoData,
+                _ => Outcome::Answer,
+            };
+            if let Some(stats) = self.stats.get(&zone.origin) {
+                stats.record(&name, record_type, outcome);
+            }
+            self.shadow(zone, &name, record_type, &lookup);
+        }
+        let mut response = query.respond(lookup);
+        if let Some(ref mut edns) = response.edns {
+            if wants_keepalive {
+                edns.tcp_keepalive = keepalive.map(Some);
+            }
+        }
+        let mut buf = BytesMut::new();
+        let mut result = response.encode(&mut buf, &mut HashMap::new());
+        if result.is_ok()
+            && channel == Channel::Datagram
+            && buf.len() > response.query.udp_payload_size()
+        {
+            response.truncated = true;
+            buf.clear();
+            result = response.encode(&mut buf, &mut HashMap::new());
+        }
+        match result {
+            Ok(()) => {
+                let response = Bytes::from(buf);
+                Some(Answered {
+                    summary: ResponseSummary::from_header(&response, Some((name, record_type))),
+                    response,
+                    zone,
+                })
+            }
+            Err(err) => {
+                error!("{:?}", err);
+                Some(Answered::error(encode_err(response.query.id, 2)))
+            }
+        }
+    }
+
+    /// Looks up a name in the zone it belongs to, following a CNAME record that points to another
+    /// zone.
+    fn lookup<'a>(
+        &'a self,
+        zone: Option<&'a Zone>,
+        name: &Name,
+        record_type: u16,
+        staged: bool,
+    ) -> LookupResult<'a> {
+        let lookup = match zone {
+            Some(zone) => zone.lookup(name, record_type),
             None => LookupResult::NoZone,
         };
-        let lookup = if let LookupResult::CNAMELookup(cname) = lookup {
+        if let LookupResult::CNAMELookup(cname) = lookup {
             if let RData::CNAME(target) = cname.rdata() {
-                match self.find_zone(target) {
+                match self.find_zone_in(target, staged) {
                     Some(zone) => LookupResult::CNAME {
                         cname,
                         found: zone
@@ -90,15 +549,293 @@ impl Authority {
             }
         } else {
             lookup
+        }
+    }
+
+    /// Compares a sample of the answers from a loaded zone with those from the zone staged for the
+    /// same origin, if comparison has been started.
+    fn shadow(&self, zone: &Zone, name: &Name, record_type: u16, live: &LookupResult) {
+        let shadow = match self.staged.get(&zone.origin) {
+            Some(Staged {
+                shadow: Some(ref shadow),
+                ..
+            }) => shadow,
+            _ => return,
         };
-        let response = query.respond(lookup);
-        let mut buf = BytesMut::new();
-        match response.encode(&mut buf, &mut HashMap::new()) {
-            Ok(()) => Bytes::from(buf),
-            Err(err) => {
-                error!("{:?}", err);
-                encode_err(response.query.id, 2)
-            }
+        if shadow.sample() {
+            let candidate = self.lookup(self.find_zone_in(name, true), name, record_type, true);
+            shadow.record(name, record_type, live.into(), (&candidate).into());
         }
     }
 }
+
+/// Finds a record in a zone with the same name, type, and data as `record`.
+fn find_record<'a>(zone: &'a Zone, record: &Record) -> Option<&'a Record> {
+    match zone.lookup(record.name(), record.record_type()) {
+        LookupResult::Records(records) => records
+            .iter()
+            .find(|found| found.name() == record.name() && found.rdata() == record.rdata()),
+        _ => None,
+    }
+}
+
+/// Writes a zone file by writing a temporary file next to it, then renaming it into place.
+fn write_zonefile(zone: &Zone, path: &Path) -> Result<(), failure::Error> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    zone.write_to(&mut writer)?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    info!("wrote zone {} to {}", zone.origin, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::slice;
+    use std::str::FromStr;
+
+    use authority::{Authority, Source};
+    use name::Name;
+    use record::{RData, Record};
+    use wire::encode_err;
+
+    #[test]
+    fn update_zone() {
+        let dir = env::temp_dir().join(format!("pepbut-authority-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.invalid.zone");
+        fs::copy(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/example.invalid.zone"),
+            &path,
+        ).unwrap();
+        let mut authority = Authority::new();
+        let (origin, serial) = authority.load_zonefile(&path).unwrap();
+
+        let www = Name::from_str("www.example.invalid").unwrap();
+        let old = Record::new(www.clone(), 0, RData::A("192.0.2.1".parse().unwrap()));
+        let new = Record::new(www.clone(), 60, RData::A("192.0.2.2".parse().unwrap()));
+        let outside = Record::new(
+            Name::from_str("www.example.net").unwrap(),
+            60,
+            RData::A("192.0.2.3".parse().unwrap()),
+        );
+
+        // Nothing changes if any record is invalid
+        assert!(
+            authority
+                .update_zone(&origin, slice::from_ref(&old), &[new.clone(), outside], false)
+                .is_err()
+        );
+        assert!(
+            authority
+                .update_zone(&origin, &[], &[new.clone(), new.clone()], false)
+                .is_err()
+        );
+        assert_eq!(authority.zones[&origin].serial, serial);
+
+        assert_eq!(
+            authority
+                .update_zone(&origin, slice::from_ref(&old), slice::from_ref(&new), true)
+                .unwrap(),
+            serial + 1
+        );
+        assert_eq!(
+            authority.zones[&origin].lookup(&www, 1).records(),
+            Some(&vec![new.clone()])
+        );
+        assert!(authority.update_zone(&origin, &[old], &[], false).is_err());
+
+        let mut reloaded = Authority::new();
+        reloaded.load_zonefile(&path).unwrap();
+        assert_eq!(reloaded.zones[&origin], authority.zones[&origin]);
+
+        // An update prepared before another was applied is refused
+        let stale = authority
+            .prepare_update(&origin, slice::from_ref(&new), &[])
+            .unwrap();
+        authority
+            .update_zone(&origin, slice::from_ref(&new), &[], false)
+            .unwrap();
+        assert!(authority.apply_update(stale).is_err());
+
+        // So is one prepared before the zone was reloaded, even though the serial is unchanged
+        let reload = reloaded
+            .prepare_update(&origin, slice::from_ref(&new), &[])
+            .unwrap();
+        reloaded.load_zonefile(&path).unwrap();
+        assert!(reloaded.check_update(&reload).is_err());
+        assert!(reloaded.apply_update(reload).is_err());
+
+        // After a chroot, files inside it are written at their new path, and others are refused
+        let mut chrooted = Authority::new();
+        chrooted.load_zonefile(&path).unwrap();
+        assert!(chrooted.chroot(&dir).is_empty());
+        assert_eq!(
+            chrooted.prepare_update(&origin, &[], &[]).unwrap().source,
+            Some(Source::Path(PathBuf::from("/example.invalid.zone")))
+        );
+        let mut outside = Authority::new();
+        outside.load_zonefile(&path).unwrap();
+        assert_eq!(outside.chroot(&dir.join("elsewhere")), vec![origin.clone()]);
+        assert!(
+            outside
+                .update_zone(&origin, &[], &[], true)
+                .unwrap_err()
+                .to_string()
+                .contains("outside the chroot")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stage_zone() {
+        let dir = env::temp_dir().join(format!("pepbut-authority-stage-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut authority = Authority::new();
+        let (origin, serial) = authority
+            .load_zonefile(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/example.invalid.zone"
+            )).unwrap();
+
+        let www3 = Name::from_str("www3.example.invalid").unwrap();
+        let mut zone = authority.zones[&origin].clone();
+        zone.push(Record::new(
+            www3.clone(),
+            60,
+            RData::A("192.0.2.3".parse().unwrap()),
+        ));
+        zone.serial += 1;
+        let path = dir.join("example.invalid.zone");
+        zone.write_to(&mut fs::File::create(&path).unwrap()).unwrap();
+
+        assert_eq!(
+            authority.stage_zonefile(&path).unwrap(),
+            (origin.clone(), serial + 1)
+        );
+        assert_eq!(authority.zones[&origin].serial, serial);
+        assert!(authority.find_zone(&www3).unwrap().lookup(&www3, 1).records().is_none());
+        assert!(
+            authority
+                .find_zone_in(&www3, true)
+                .unwrap()
+                .lookup(&www3, 1)
+                .records()
+                .is_some()
+        );
+
+        assert_eq!(authority.activate_zone(&origin), Some(serial + 1));
+        assert_eq!(authority.zones[&origin], zone);
+        assert!(authority.staged_zone(&origin).is_none());
+        assert_eq!(authority.activate_zone(&origin), None);
+
+        authority.stage_zonefile(&path).unwrap();
+        assert!(authority.discard_zone(&origin));
+        assert!(!authority.discard_zone(&origin));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn process_malformed_message() {
+        let authority = Authority::new();
+        assert_eq!(authority.process_message(Bytes::from_static(b"\x12")), None);
+        // A query with an additional record cut off after its name
+        let query = Bytes::from_static(b"\x12\x34\0\0\0\x01\0\0\0\0\0\x01\0\0\x01\0\x01\0");
+        assert_eq!(
+            authority.process_message(query),
+            Some(encode_err(0x1234, 1))
+        );
+    }
+
+    #[test]
+    fn truncate_udp() {
+        let query = |edns: bool| {
+            let mut query = b"\x12\x34\0\0\0\x01\0\0\0\0\0".to_vec();
+            query.push(if edns { 1 } else { 0 });
+            query.extend_from_slice(b"\x03www\x07example\x07invalid\0\0\x01\0\x01");
+            if edns {
+                // OPT with a UDP payload size of 4096
+                query.extend_from_slice(b"\0\0\x29\x10\0\0\0\0\0\0\0");
+            }
+            Bytes::from(query)
+        };
+        let mut authority = Authority::new();
+        let (origin, _) = authority
+            .load_zonefile(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/example.invalid.zone"
+            )).unwrap();
+        let www = Name::from_str("www.example.invalid").unwrap();
+        let records = (0..40)
+            .map(|i| Record::new(www.clone(), 60, RData::A([198, 51, 100, i].into())))
+            .collect::<Vec<_>>();
+        authority.update_zone(&origin, &[], &records, false).unwrap();
+
+        // 41 answers do not fit in 512 bytes, but do fit in the 1232 bytes allowed with EDNS
+        let truncated = authority.process_message(query(false)).unwrap();
+        assert_eq!(truncated[2] & 0x02, 0x02);
+        assert_eq!(&truncated[6..12], &[0, 0, 0, 0, 0, 0]);
+        let response = authority.process_message(query(true)).unwrap();
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(&response[6..8], &[0, 41]);
+        let response = authority.process_tcp_message(query(false), 100).unwrap();
+        assert_eq!(response[2] & 0x02, 0);
+        assert_eq!(&response[6..8], &[0, 41]);
+    }
+
+    #[test]
+    fn shadow_zone() {
+        let query = |record_type: u8| {
+            let mut query = b"\x12\x34\0\0\0\x01\0\0\0\0\0\0".to_vec();
+            query.extend_from_slice(b"\x03www\x07example\x07invalid\0");
+            query.extend_from_slice(&[0, record_type, 0, 1]);
+            Bytes::from(query)
+        };
+        let path = env::temp_dir().join(format!("pepbut-authority-shadow-{}.zone", process::id()));
+        let mut authority = Authority::new();
+        let (origin, _) = authority
+            .load_zonefile(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/data/example.invalid.zone"
+            )).unwrap();
+        let live = authority.process_message(query(1));
+
+        let www = Name::from_str("www.example.invalid").unwrap();
+        let old = Record::new(www.clone(), 300, RData::A("192.0.2.1".parse().unwrap()));
+        let new = Record::new(www.clone(), 300, RData::A("192.0.2.9".parse().unwrap()));
+        let mut zone = authority.zones[&origin].clone();
+        zone.remove(&old);
+        zone.push(new.clone());
+        zone.write_to(&mut fs::File::create(&path).unwrap()).unwrap();
+        authority.stage_zonefile(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(authority.shadow_report(&origin).is_none());
+        assert!(authority.shadow_zone(&origin, 1));
+        assert!(!authority.shadow_zone(&Name::from_str("example.net").unwrap(), 1));
+        assert_eq!(authority.process_message(query(1)), live);
+        authority.process_message(query(28));
+
+        let report = authority.shadow_report(&origin).unwrap();
+        assert_eq!(report.queries, 2);
+        assert_eq!(report.compared, 2);
+        assert_eq!(report.mismatched, 1);
+        assert_eq!(report.recent[0].name, www);
+        assert_eq!(report.recent[0].record_type, 1);
+        assert_eq!(report.recent[0].live.records, vec![old]);
+        assert_eq!(report.recent[0].candidate.records, vec![new]);
+
+        assert!(authority.shadow_zone(&origin, 0));
+        assert!(authority.shadow_report(&origin).is_none());
+    }
+}
@@ -24,6 +24,13 @@ extern crate log;
 extern crate maplit;
 extern crate rmp;
 
+macro_rules! fatal {
+    ($msg:expr) => {{
+        error!("fatal error, cannot recover: {}", $msg);
+        ::std::process::exit(1);
+    }};
+}
+
 macro_rules! read_exact {
     ($r:expr, $c:expr) => {{
         #[allow(unused_imports)]
@@ -39,6 +46,8 @@ pub mod authority;
 mod msgpack;
 pub mod name;
 pub mod record;
+pub mod shadow;
+pub mod stats;
 pub mod wire;
 pub mod zone;
 
@@ -130,6 +130,42 @@ impl Name {
         self.0.as_slice()
     }
 
+    /// Formats the name as a fully qualified domain name, with a trailing dot, as in zone files.
+    ///
+    /// Unlike `Display`, internationalized labels are left in their ASCII form, and special
+    /// characters are escaped ([RFC 1035 § 5.1](https://tools.ietf.org/html/rfc1035#section-5.1)).
+    pub fn to_fqdn(&self) -> String {
+        if self.0.is_empty() {
+            return String::from(".");
+        }
+        let mut s = String::new();
+        for label in &self.0 {
+            for &b in label.iter() {
+                match b {
+                    b'.' | b'\\' | b'"' | b'(' | b')' | b';' | b'@' | b'$' => {
+                        s.push('\\');
+                        s.push(b as char);
+                    }
+                    0x21..=0x7e => s.push(b as char),
+                    _ => s.push_str(&format!("\\{:03}", b)),
+                }
+            }
+            s.push('.');
+        }
+        s
+    }
+
+    /// Returns true if the name is equal to or below `suffix`.
+    pub fn ends_with(&self, suffix: &Name) -> bool {
+        match self.0.len().checked_sub(suffix.0.len()) {
+            Some(skip) => self.0[skip..]
+                .iter()
+                .zip(&suffix.0)
+                .all(|(a, b)| eq_lower(a, b)),
+            None => false,
+        }
+    }
+
     /// Determine the number of bytes it will take to encode this label in a DNS message,
     /// accounting for name compression.
     pub(crate) fn encode_len(
@@ -520,6 +556,9 @@ impl ProtocolDecode for Name {
         let mut jumps = 0;
 
         loop {
+            if buf.remaining() < 1 {
+                return Err(ProtocolDecodeError::Truncated);
+            }
             let length = buf.get_u8();
             if length == 0 {
                 if jumps > 0 {
@@ -527,6 +566,9 @@ impl ProtocolDecode for Name {
                 }
                 return Ok(name);
             } else if length > 63 {
+                if buf.remaining() < 1 {
+                    return Err(ProtocolDecodeError::Truncated);
+                }
                 let offset = ((u16::from(length) & 0x3f) << 8) + u16::from(buf.get_u8());
                 if jumps == 0 {
                     orig_pos = buf.position();
@@ -536,6 +578,9 @@ impl ProtocolDecode for Name {
                 jumps += 1;
                 buf.set_position(offset.into());
             } else {
+                if buf.remaining() < length as usize {
+                    return Err(ProtocolDecodeError::Truncated);
+                }
                 let start = usize(buf.position());
                 let end = start + length as usize;
                 buf.advance(length as usize);
@@ -623,6 +668,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn fqdn() {
+        assert_eq!(Name::from_str("www.example.net").unwrap().to_fqdn(), "www.example.net.");
+        assert_eq!(Name::from_str(".").unwrap().to_fqdn(), ".");
+        assert_eq!(Name::from_str("☃.net").unwrap().to_fqdn(), "xn--n3h.net.");
+        assert_eq!(
+            vec![&b"a.b"[..], &b"c d"[..]].into_iter().collect::<Name>().to_fqdn(),
+            "a\\.b.c\\032d."
+        );
+    }
+
+    #[test]
+    fn ends_with() {
+        let name = Name::from_str("www.Example.net").unwrap();
+        assert!(name.ends_with(&Name::from_str("example.NET").unwrap()));
+        assert!(name.ends_with(&name));
+        assert!(name.ends_with(&Name::from_str(".").unwrap()));
+        assert!(!name.ends_with(&Name::from_str("ample.net").unwrap()));
+        assert!(!name.ends_with(&Name::from_str("a.www.example.net").unwrap()));
+    }
+
     #[test]
     fn empty_label() {
         assert!(Name::from_str("example..invalid").is_err());
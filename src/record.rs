@@ -7,13 +7,66 @@ use cast::{self, u16, u32, u8};
 use rmp;
 use std::cmp::min;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::{Read, Write};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 use msgpack::{check_len, Msgpack, ZoneReadError, ZoneWriteError};
-use name::Name;
+use name::{Name, NameParseError};
 use wire::ProtocolEncode;
 
+/// Record and query types known by their mnemonic. Others are written with their generic name
+/// ([RFC 3597 § 5](https://tools.ietf.org/html/rfc3597#section-5)), such as `TYPE99`.
+static TYPE_NAMES: &[(u16, &str)] = &[
+    (1, "A"),
+    (2, "NS"),
+    (5, "CNAME"),
+    (6, "SOA"),
+    (12, "PTR"),
+    (15, "MX"),
+    (16, "TXT"),
+    (28, "AAAA"),
+    (33, "SRV"),
+    (35, "NAPTR"),
+    (43, "DS"),
+    (48, "DNSKEY"),
+    (64, "SVCB"),
+    (65, "HTTPS"),
+    (252, "AXFR"),
+    (255, "ANY"),
+    (257, "CAA"),
+];
+
+/// Returns the mnemonic for a record or query type, if it has one in `TYPE_NAMES`.
+pub fn type_name(record_type: u16) -> Option<&'static str> {
+    TYPE_NAMES
+        .iter()
+        .find(|&&(t, _)| t == record_type)
+        .map(|&(_, name)| name)
+}
+
+/// Returns the mnemonic for a record or query type, or its generic name if it has none.
+pub fn type_string(record_type: u16) -> String {
+    type_name(record_type).map_or_else(|| format!("TYPE{}", record_type), String::from)
+}
+
+/// Parses a record or query type from its mnemonic (case-insensitively), its generic name, or its
+/// number.
+pub fn type_from_str(s: &str) -> Option<u16> {
+    if let Some(&(record_type, _)) = TYPE_NAMES
+        .iter()
+        .find(|&&(_, name)| name.eq_ignore_ascii_case(s))
+    {
+        return Some(record_type);
+    }
+    let number = match s.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("TYPE") => &s[4..],
+        _ => s,
+    };
+    number.parse().ok()
+}
+
 pub trait RecordTrait {
     /// The name the record belongs to.
     fn name(&self) -> &Name;
@@ -312,6 +365,154 @@ impl Msgpack for RData {
     }
 }
 
+/// Errors that can occur while parsing `RData` from presentation format.
+#[derive(Debug, Fail)]
+pub enum RDataParseError {
+    /// pepbut does not support records of this type.
+    #[fail(display = "unsupported record type {}", _0)]
+    UnsupportedRecordType(u16),
+    /// The record data is malformed.
+    #[fail(display = "invalid record data: {}", _0)]
+    Invalid(&'static str),
+    /// A name in the record data is invalid.
+    #[fail(display = "invalid name: {}", _0)]
+    Name(#[cause] NameParseError),
+}
+
+impl From<NameParseError> for RDataParseError {
+    fn from(err: NameParseError) -> RDataParseError {
+        RDataParseError::Name(err)
+    }
+}
+
+impl RData {
+    /// Parses record data of the given type from zone file presentation format, as produced by
+    /// `Display`. Names are always taken to be fully qualified.
+    ///
+    /// TXT data is joined from one or more quoted strings. If it does not start with a quote, the
+    /// whole string is taken literally instead.
+    pub fn parse(record_type: u16, s: &str) -> Result<RData, RDataParseError> {
+        fn number<T: FromStr>(s: &str) -> Result<T, RDataParseError> {
+            s.parse().map_err(|_| RDataParseError::Invalid("expected a number"))
+        }
+
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        Ok(match (record_type, &fields[..]) {
+            (1, [addr]) => RData::A(
+                addr.parse()
+                    .map_err(|_| RDataParseError::Invalid("expected an IPv4 address"))?,
+            ),
+            (28, [addr]) => RData::AAAA(
+                addr.parse()
+                    .map_err(|_| RDataParseError::Invalid("expected an IPv6 address"))?,
+            ),
+            (5, [name]) => RData::CNAME(Name::from_str(name)?),
+            (2, [name]) => RData::NS(Name::from_str(name)?),
+            (12, [name]) => RData::PTR(Name::from_str(name)?),
+            (15, [preference, exchange]) => RData::MX {
+                preference: number(preference)?,
+                exchange: Name::from_str(exchange)?,
+            },
+            (33, [priority, weight, port, target]) => RData::SRV {
+                priority: number(priority)?,
+                weight: number(weight)?,
+                port: number(port)?,
+                target: Name::from_str(target)?,
+            },
+            (16, _) => RData::TXT(parse_txt(s.trim())?),
+            (1, _) | (28, _) | (5, _) | (2, _) | (12, _) | (15, _) | (33, _) => {
+                return Err(RDataParseError::Invalid("wrong number of fields"))
+            }
+            (record_type, _) => return Err(RDataParseError::UnsupportedRecordType(record_type)),
+        })
+    }
+}
+
+/// Parses TXT data from a sequence of quoted strings, which may contain `\X` and `\DDD` escapes.
+fn parse_txt(s: &str) -> Result<String, RDataParseError> {
+    if !s.starts_with('"') {
+        return Ok(s.to_owned());
+    }
+    let mut data = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'"' => {}
+            b' ' | b'\t' => continue,
+            _ => return Err(RDataParseError::Invalid("TXT strings must be quoted")),
+        }
+        loop {
+            match bytes.next() {
+                Some(b'"') => break,
+                Some(b'\\') => data.push(parse_escape(&mut bytes)?),
+                Some(c) => data.push(c),
+                None => return Err(RDataParseError::Invalid("unterminated string")),
+            }
+        }
+    }
+    String::from_utf8(data).map_err(|_| RDataParseError::Invalid("TXT data is not valid UTF-8"))
+}
+
+/// Parses the rest of an escape sequence after the backslash.
+fn parse_escape(bytes: &mut impl Iterator<Item = u8>) -> Result<u8, RDataParseError> {
+    let malformed = RDataParseError::Invalid("malformed escape");
+    match bytes.next() {
+        Some(d) if d.is_ascii_digit() => {
+            let mut value = u32::from(d - b'0');
+            for _ in 0..2 {
+                match bytes.next() {
+                    Some(d) if d.is_ascii_digit() => value = value * 10 + u32::from(d - b'0'),
+                    _ => return Err(malformed),
+                }
+            }
+            u8(value).map_err(|_| malformed)
+        }
+        Some(c) => Ok(c),
+        None => Err(malformed),
+    }
+}
+
+/// Formats record data in zone file presentation format.
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RData::A(addr) => write!(f, "{}", addr),
+            RData::AAAA(addr) => write!(f, "{}", addr),
+            RData::CNAME(ref name) | RData::NS(ref name) | RData::PTR(ref name) => {
+                write!(f, "{}", name.to_fqdn())
+            }
+            RData::MX {
+                preference,
+                ref exchange,
+            } => write!(f, "{} {}", preference, exchange.to_fqdn()),
+            RData::SRV {
+                priority,
+                weight,
+                port,
+                ref target,
+            } => write!(f, "{} {} {} {}", priority, weight, port, target.to_fqdn()),
+            RData::TXT(ref s) => {
+                // Split as when encoded, escaping anything that is not printable ASCII
+                for (i, chunk) in s.as_bytes().chunks(255).enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "\"")?;
+                    for &b in chunk {
+                        match b {
+                            b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                            0x20..=0x7e => write!(f, "{}", b as char)?,
+                            _ => write!(f, "\\{:03}", b)?,
+                        }
+                    }
+                    write!(f, "\"")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 impl ProtocolEncode for RData {
     fn encode(
         &self,
@@ -361,3 +562,76 @@ impl ProtocolEncode for RData {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use name::Name;
+    use record::{type_from_str, type_string, RData};
+
+    #[test]
+    fn parse_type() {
+        assert_eq!(type_from_str("AAAA"), Some(28));
+        assert_eq!(type_from_str("mx"), Some(15));
+        assert_eq!(type_from_str("TYPE99"), Some(99));
+        assert_eq!(type_from_str("99"), Some(99));
+        assert_eq!(type_from_str("TYPE"), None);
+        assert_eq!(type_from_str("bogus"), None);
+        assert_eq!(type_string(28), "AAAA");
+        assert_eq!(type_string(99), "TYPE99");
+    }
+
+    #[test]
+    fn display_parse() {
+        let long = "x".repeat(300);
+        for &(record_type, ref rdata) in &[
+            (1, RData::A("192.0.2.1".parse().unwrap())),
+            (28, RData::AAAA("2001:db8::1".parse().unwrap())),
+            (5, RData::CNAME(Name::from_str("www.example.invalid").unwrap())),
+            (
+                15,
+                RData::MX {
+                    preference: 10,
+                    exchange: Name::from_str("mx1.mail.invalid").unwrap(),
+                },
+            ),
+            (
+                33,
+                RData::SRV {
+                    priority: 0,
+                    weight: 5,
+                    port: 5060,
+                    target: Name::from_str("sip.example.invalid").unwrap(),
+                },
+            ),
+            (16, RData::TXT(String::from("v=spf1 \"quoted\" \\ ☃"))),
+            (16, RData::TXT(long.clone())),
+        ] {
+            assert_eq!(&RData::parse(record_type, &rdata.to_string()).unwrap(), rdata);
+        }
+        assert_eq!(
+            RData::TXT(String::from("a\"b")).to_string(),
+            "\"a\\\"b\""
+        );
+        assert_eq!(RData::TXT(long).to_string().matches('"').count(), 4);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            RData::parse(16, "v=spf1 -all").unwrap(),
+            RData::TXT(String::from("v=spf1 -all"))
+        );
+        assert_eq!(
+            RData::parse(16, "\"a\" \"\\098c\"").unwrap(),
+            RData::TXT(String::from("abc"))
+        );
+        assert!(RData::parse(16, "\"unterminated").is_err());
+        assert!(RData::parse(16, "\"a\" b").is_err());
+        assert!(RData::parse(1, "2001:db8::1").is_err());
+        assert!(RData::parse(15, "10").is_err());
+        assert!(RData::parse(15, "ten mx1.mail.invalid").is_err());
+        assert!(RData::parse(6, "").is_err());
+    }
+}
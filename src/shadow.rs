@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Comparison of a candidate zone's answers with the live zone's, using a sample of real queries.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use name::Name;
+use record::Record;
+use zone::LookupResult;
+
+/// How many of the most recent mismatches are kept.
+const RECENT_MISMATCHES: usize = 64;
+
+/// The state of shadow comparison for a zone.
+#[derive(Debug, Clone)]
+pub struct ShadowReport {
+    /// One in this many queries for the zone is compared.
+    pub sample_every: u32,
+    /// Queries for the zone seen since comparison started, whether or not they were compared.
+    pub queries: u64,
+    pub compared: u64,
+    pub mismatched: u64,
+    /// The most recent mismatches, oldest first.
+    pub recent: VecDeque<Mismatch>,
+}
+
+/// Shadow comparison for a zone, shared by every thread answering queries. Queries are counted
+/// without locking; the report is only locked to record a query that was compared.
+#[derive(Debug)]
+pub(crate) struct Shadow {
+    sample_every: u32,
+    queries: AtomicUsize,
+    report: Mutex<ShadowReport>,
+}
+
+/// A query that the candidate zone answered differently from the live zone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub name: Name,
+    pub record_type: u16,
+    pub live: Answer,
+    pub candidate: Answer,
+}
+
+/// The parts of an answer that are compared: the response code, and the records in every section
+/// except for the SOA record of a negative answer, as the serial is expected to differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    pub rcode: u8,
+    pub records: Vec<Record>,
+}
+
+impl<'a> From<&'a LookupResult<'a>> for Answer {
+    fn from(lookup: &LookupResult) -> Answer {
+        let records = match lookup {
+            LookupResult::Records(records) => (*records).clone(),
+            LookupResult::CNAME {
+                cname,
+                found,
+                authorities,
+            } => Some((*cname).clone())
+                .into_iter()
+                .chain(found.iter().cloned())
+                .chain(authorities.iter().cloned())
+                .collect(),
+            LookupResult::CNAMELookup(cname) => vec![(*cname).clone()],
+            LookupResult::Delegated {
+                authorities,
+                glue_records,
+            } => authorities
+                .iter()
+                .chain(glue_records.iter())
+                .cloned()
+                .collect(),
+            LookupResult::NameExists(_) | LookupResult::NoName(_) | LookupResult::NoZone => {
+                Vec::new()
+            }
+        };
+        Answer {
+            rcode: lookup.rcode(),
+            records,
+        }
+    }
+}
+
+impl Answer {
+    /// Returns whether two answers are the same, regardless of the order of their records.
+    pub fn matches(&self, other: &Answer) -> bool {
+        self.rcode == other.rcode
+            && self.records.len() == other.records.len()
+            && self.records.iter().all(|record| {
+                let count = |records: &[Record]| records.iter().filter(|r| *r == record).count();
+                count(&self.records) == count(&other.records)
+            })
+    }
+}
+
+impl ShadowReport {
+    pub(crate) fn new(sample_every: u32) -> ShadowReport {
+        ShadowReport {
+            sample_every,
+            queries: 0,
+            compared: 0,
+            mismatched: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn record(
+        &mut self,
+        name: &Name,
+        record_type: u16,
+        live: Answer,
+        candidate: Answer,
+    ) {
+        self.compared += 1;
+        if live.matches(&candidate) {
+            return;
+        }
+        self.mismatched += 1;
+        if self.recent.len() == RECENT_MISMATCHES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Mismatch {
+            name: name.clone(),
+            record_type,
+            live,
+            candidate,
+        });
+    }
+}
+
+impl Shadow {
+    pub(crate) fn new(sample_every: u32) -> Shadow {
+        Shadow {
+            sample_every,
+            queries: AtomicUsize::new(0),
+            report: Mutex::new(ShadowReport::new(sample_every)),
+        }
+    }
+
+    /// Counts a query for the zone, returning whether it should be compared.
+    pub(crate) fn sample(&self) -> bool {
+        let queries = self.queries.fetch_add(1, Ordering::Relaxed) + 1;
+        queries % self.sample_every as usize == 0
+    }
+
+    /// Records the answers to a query that was compared.
+    pub(crate) fn record(&self, name: &Name, record_type: u16, live: Answer, candidate: Answer) {
+        self.lock().record(name, record_type, live, candidate);
+    }
+
+    /// Returns the current state of the comparison.
+    pub(crate) fn report(&self) -> ShadowReport {
+        let mut report = self.lock().clone();
+        report.queries = self.queries.load(Ordering::Relaxed) as u64;
+        report
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ShadowReport> {
+        self.report
+            .lock()
+            .unwrap_or_else(|_| fatal!("shadow report is poisoned"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use name::Name;
+    use record::{RData, Record};
+    use shadow::{Answer, Shadow, RECENT_MISMATCHES};
+
+    fn a(last: u8) -> Record {
+        Record::new(
+            Name::from_str("www.example.invalid").unwrap(),
+            300,
+            RData::A([192, 0, 2, last].into()),
+        )
+    }
+
+    #[test]
+    fn matches() {
+        let answer = |rcode, records| Answer { rcode, records };
+        assert!(answer(0, vec![a(1), a(2)]).matches(&answer(0, vec![a(2), a(1)])));
+        assert!(!answer(0, vec![a(1), a(2)]).matches(&answer(0, vec![a(1)])));
+        assert!(!answer(0, vec![a(1), a(1)]).matches(&answer(0, vec![a(1), a(2)])));
+        assert!(!answer(0, Vec::new()).matches(&answer(3, Vec::new())));
+    }
+
+    #[test]
+    fn report() {
+        let name = Name::from_str("www.example.invalid").unwrap();
+        let shadow = Shadow::new(2);
+        assert!(!shadow.sample());
+        assert!(shadow.sample());
+        assert!(!shadow.sample());
+        assert_eq!(shadow.report().queries, 3);
+
+        let answer = |last| Answer {
+            rcode: 0,
+            records: vec![a(last)],
+        };
+        shadow.record(&name, 1, answer(1), answer(1));
+        for last in 0..=RECENT_MISMATCHES {
+            shadow.record(&name, 1, answer(1), answer(last as u8 + 2));
+        }
+        let report = shadow.report();
+        assert_eq!(report.compared, RECENT_MISMATCHES as u64 + 2);
+        assert_eq!(report.mismatched, RECENT_MISMATCHES as u64 + 1);
+        assert_eq!(report.recent.len(), RECENT_MISMATCHES);
+        assert_eq!(report.recent[0].candidate, answer(3));
+    }
+}
@@ -0,0 +1,323 @@
+// SPDX-License-Identifier: AGPL-3.0-only
+
+//! Query statistics kept for each zone.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use name::Name;
+
+/// How many names are tracked for finding the most queried names in a zone. Counts for names
+/// outside the top few are approximate, so this is several times more than are ever reported.
+const TOP_NAMES_CAPACITY: usize = 128;
+
+/// How many separately locked sketches the tracked names are spread across, so that queries
+/// answered in parallel rarely wait for each other.
+const TOP_NAMES_SHARDS: usize = 8;
+
+/// Record types below this are counted without taking a lock. Queries for other types are rare.
+const COMMON_TYPES: usize = 256;
+
+/// Counts of the queries answered from a zone, as returned by `Authority::zone_stats`.
+#[derive(Debug, Clone, Default)]
+pub struct ZoneStats {
+    /// Queries answered from the zone, including negative answers.
+    pub queries: u64,
+    /// Queries for names that do not exist.
+    pub nxdomain: u64,
+    /// Queries for names that exist, but have no records of the queried type.
+    pub nodata: u64,
+    /// Queries by record type.
+    pub record_types: HashMap<u16, u64>,
+    /// The most queried names with their counts, most counted first.
+    pub top_names: Vec<(Name, u64)>,
+}
+
+/// The counters behind `ZoneStats`, updated as queries are answered.
+#[derive(Debug)]
+pub(crate) struct ZoneCounters {
+    queries: AtomicUsize,
+    nxdomain: AtomicUsize,
+    nodata: AtomicUsize,
+    common_types: Vec<AtomicUsize>,
+    other_types: Mutex<HashMap<u16, usize>>,
+    /// Each name is always tracked by the same shard, picked by its hash.
+    top_names: Vec<Mutex<TopNames>>,
+}
+
+impl Default for ZoneCounters {
+    fn default() -> ZoneCounters {
+        ZoneCounters {
+            queries: AtomicUsize::new(0),
+            nxdomain: AtomicUsize::new(0),
+            nodata: AtomicUsize::new(0),
+            common_types: (0..COMMON_TYPES).map(|_| AtomicUsize::new(0)).collect(),
+            other_types: Mutex::new(HashMap::new()),
+            top_names: (0..TOP_NAMES_SHARDS)
+                .map(|_| {
+                    Mutex::new(TopNames::with_capacity(
+                        TOP_NAMES_CAPACITY / TOP_NAMES_SHARDS,
+                    ))
+                }).collect(),
+        }
+    }
+}
+
+impl ZoneCounters {
+    pub(crate) fn record(&self, name: &Name, record_type: u16, outcome: Outcome) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        match outcome {
+            Outcome::Answer => {}
+            Outcome::NoName => {
+                self.nxdomain.fetch_add(1, Ordering::Relaxed);
+            }
+            Outcome::NoData => {
+                self.nodata.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        match self.common_types.get(usize::from(record_type)) {
+            Some(count) => {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                *self
+                    .other_types
+                    .lock()
+                    .unwrap_or_else(|_| fatal!("record type counts are poisoned"))
+                    .entry(record_type)
+                    .or_insert(0) += 1
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let shard = hasher.finish() as usize % self.top_names.len();
+        self.top_names[shard]
+            .lock()
+            .unwrap_or_else(|_| fatal!("top names are poisoned"))
+            .insert(name);
+    }
+
+    pub(crate) fn snapshot(&self) -> ZoneStats {
+        let mut record_types = self
+            .common_types
+            .iter()
+            .enumerate()
+            .filter_map(|(record_type, count)| match count.load(Ordering::Relaxed) {
+                0 => None,
+                count => Some((record_type as u16, count as u64)),
+            }).collect::<HashMap<_, _>>();
+        for (&record_type, &count) in self
+            .other_types
+            .lock()
+            .unwrap_or_else(|_| fatal!("record type counts are poisoned"))
+            .iter()
+        {
+            record_types.insert(record_type, count as u64);
+        }
+        // The shards track disjoint sets of names, so together they are the top names of the zone
+        let mut top_names = Vec::with_capacity(TOP_NAMES_CAPACITY);
+        for shard in &self.top_names {
+            top_names.extend(
+                shard
+                    .lock()
+                    .unwrap_or_else(|_| fatal!("top names are poisoned"))
+                    .top(TOP_NAMES_CAPACITY)
+                    .into_iter()
+                    .map(|(name, count)| (name.clone(), count)),
+            );
+        }
+        sort_top(&mut top_names);
+        ZoneStats {
+            queries: self.queries.load(Ordering::Relaxed) as u64,
+            nxdomain: self.nxdomain.load(Ordering::Relaxed) as u64,
+            nodata: self.nodata.load(Ordering::Relaxed) as u64,
+            record_types,
+            top_names,
+        }
+    }
+}
+
+/// How a query was answered, as far as statistics are concerned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Outcome {
+    Answer,
+    NoName,
+    NoData,
+}
+
+/// Finds the most frequent names in a stream using a fixed amount of memory, with the Space-Saving
+/// algorithm ([Metwally et al. 2005](https://doi.org/10.1007/978-3-540-30570-5_27)).
+///
+/// Once the sketch is full, a name not already tracked replaces the least counted one and inherits
+/// its count. Counts may therefore be overestimated by up to the count they inherited, but any name
+/// queried more often than the least counted name is guaranteed to be tracked.
+///
+/// The names are kept in a binary min-heap by count, so that the least counted name is found in
+/// constant time and each insert takes logarithmic time.
+#[derive(Debug, Clone)]
+pub struct TopNames {
+    capacity: usize,
+    heap: Vec<(u64, Name)>,
+    /// The position of each tracked name in `heap`.
+    positions: HashMap<Name, usize>,
+}
+
+impl Default for TopNames {
+    fn default() -> TopNames {
+        TopNames::with_capacity(TOP_NAMES_CAPACITY)
+    }
+}
+
+impl TopNames {
+    pub fn with_capacity(capacity: usize) -> TopNames {
+        TopNames {
+            capacity,
+            heap: Vec::with_capacity(capacity),
+            positions: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn insert(&mut self, name: &Name) {
+        if let Some(&i) = self.positions.get(name) {
+            self.heap[i].0 += 1;
+            self.sift_down(i);
+            return;
+        }
+        if self.heap.len() < self.capacity {
+            self.heap.push((1, name.clone()));
+            let i = self.heap.len() - 1;
+            self.positions.insert(name.clone(), i);
+            self.sift_up(i);
+            return;
+        }
+        let evicted = match self.heap.first_mut() {
+            Some(least) => {
+                least.0 += 1;
+                ::std::mem::replace(&mut least.1, name.clone())
+            }
+            None => return,
+        };
+        self.positions.remove(&evicted);
+        self.positions.insert(name.clone(), 0);
+        self.sift_down(0);
+    }
+
+    /// Returns up to `n` of the most counted names with their counts, most counted first.
+    pub fn top(&self, n: usize) -> Vec<(&Name, u64)> {
+        let mut top = self
+            .heap
+            .iter()
+            .map(|(count, name)| (name, *count))
+            .collect::<Vec<_>>();
+        sort_top(&mut top);
+        top.truncate(n);
+        top
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if self.heap[parent].0 <= self.heap[i].0 {
+                break;
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let mut least = i;
+            for child in &[2 * i + 1, 2 * i + 2] {
+                if *child < self.heap.len() && self.heap[*child].0 < self.heap[least].0 {
+                    least = *child;
+                }
+            }
+            if least == i {
+                break;
+            }
+            self.swap(i, least);
+            i = least;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        for &i in &[a, b] {
+            if let Some(position) = self.positions.get_mut(&self.heap[i].1) {
+                *position = i;
+            }
+        }
+    }
+}
+
+/// Sorts names by count, most counted first, and then by name.
+fn sort_top<N: ToString>(top: &mut [(N, u64)]) {
+    top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.to_string().cmp(&b.0.to_string())));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use name::Name;
+    use stats::{Outcome, TopNames, ZoneCounters};
+
+    #[test]
+    fn zone_stats() {
+        let www = Name::from_str("www.example.invalid").unwrap();
+        let nope = Name::from_str("nope.example.invalid").unwrap();
+        let counters = ZoneCounters::default();
+        counters.record(&www, 1, Outcome::Answer);
+        counters.record(&www, 28, Outcome::NoData);
+        counters.record(&nope, 1, Outcome::NoName);
+        counters.record(&www, 257, Outcome::NoData);
+        let stats = counters.snapshot();
+        assert_eq!(stats.queries, 4);
+        assert_eq!(stats.nxdomain, 1);
+        assert_eq!(stats.nodata, 2);
+        assert_eq!(stats.record_types[&1], 2);
+        assert_eq!(stats.record_types[&28], 1);
+        assert_eq!(stats.record_types[&257], 1);
+        assert_eq!(stats.record_types.len(), 3);
+        assert_eq!(stats.top_names, vec![(www, 3), (nope, 1)]);
+    }
+
+    #[test]
+    fn top_names() {
+        let hot = Name::from_str("hot.example.invalid").unwrap();
+        let mut top = TopNames::with_capacity(4);
+        // Each rare name evicts another, but a name making up more than 1/4 of the queries must
+        // stay tracked
+        for i in 0..50 {
+            top.insert(&Name::from_str(&format!("{}.example.invalid", i)).unwrap());
+            top.insert(&hot);
+        }
+        let found = top.top(10);
+        assert_eq!(found.len(), 4);
+        assert_eq!(found[0].0, &hot);
+        assert!(found[0].1 >= 50);
+    }
+
+    #[test]
+    fn top_names_counts() {
+        // With room for every name, counts are exact
+        let names = (0..20)
+            .map(|i| Name::from_str(&format!("{}.example.invalid", i)).unwrap())
+            .collect::<Vec<_>>();
+        let mut top = TopNames::with_capacity(names.len());
+        for (i, name) in names.iter().enumerate() {
+            for _ in 0..i + 1 {
+                top.insert(name);
+            }
+        }
+        let found = top.top(3);
+        assert_eq!(
+            found,
+            vec![(&names[19], 20), (&names[18], 19), (&names[17], 18)]
+        );
+    }
+}
@@ -8,6 +8,7 @@ use std::collections::HashMap;
 use std::io::Cursor;
 
 use name::Name;
+use record::RData;
 use zone::LookupResult;
 
 pub fn encode_err(id: u16, rcode: u8) -> Bytes {
@@ -23,6 +24,310 @@ pub fn encode_err(id: u16, rcode: u8) -> Bytes {
     buf.freeze()
 }
 
+/// Returns the mnemonic for a response code, or its number if it has none here.
+pub fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        0 => String::from("NOERROR"),
+        1 => String::from("FORMERR"),
+        2 => String::from("SERVFAIL"),
+        3 => String::from("NXDOMAIN"),
+        4 => String::from("NOTIMP"),
+        5 => String::from("REFUSED"),
+        _ => rcode.to_string(),
+    }
+}
+
+/// The RR type of the EDNS OPT pseudo-record.
+const OPT_TYPE: u16 = 41;
+/// The EDNS option code for `edns-tcp-keepalive`.
+const TCP_KEEPALIVE_OPTION: u16 = 11;
+
+/// The UDP payload size we advertise in responses, as recommended for avoiding IP fragmentation.
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// Returns the smallest TTL of the records in an encoded response, for deciding how long the
+/// response may be cached. For SOA records, the SOA MINIMUM is also considered, as it limits how
+/// long negative responses may be cached
+/// ([RFC 2308 § 5](https://tools.ietf.org/html/rfc2308#section-5)).
+///
+/// Returns `None` if the response has no records, or if it cannot be parsed.
+pub fn response_min_ttl(message: &Bytes) -> Option<u32> {
+    let mut buf = Cursor::new(message.clone());
+    if buf.remaining() < 12 {
+        return None;
+    }
+    buf.advance(4);
+    let qdcount = buf.get_u16_be();
+    let rrcount = u32::from(buf.get_u16_be()) + u32::from(buf.get_u16_be())
+        + u32::from(buf.get_u16_be());
+    for _ in 0..qdcount {
+        Name::decode(&mut buf).ok()?;
+        if buf.remaining() < 4 {
+            return None;
+        }
+        buf.advance(4);
+    }
+
+    let mut min_ttl = None;
+    for _ in 0..rrcount {
+        Name::decode(&mut buf).ok()?;
+        if buf.remaining() < 10 {
+            return None;
+        }
+        let record_type = buf.get_u16_be();
+        buf.advance(2);
+        let mut ttl = buf.get_u32_be();
+        let rdlen = buf.get_u16_be() as usize;
+        if buf.remaining() < rdlen {
+            return None;
+        }
+        if record_type == OPT_TYPE {
+            buf.advance(rdlen);
+            continue;
+        }
+        if record_type == 6 {
+            // MINIMUM is the last field of the SOA RDATA
+            if rdlen < 4 {
+                return None;
+            }
+            buf.advance(rdlen - 4);
+            ttl = ttl.min(buf.get_u32_be());
+        } else {
+            buf.advance(rdlen);
+        }
+        min_ttl = Some(min_ttl.map_or(ttl, |min: u32| min.min(ttl)));
+    }
+    min_ttl
+}
+
+/// The header and question of an encoded response, for logging and statistics.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseSummary {
+    pub id: u16,
+    pub rcode: u8,
+    /// Whether the TC bit is set.
+    pub truncated: bool,
+    /// The name and type queried for. Responses to queries that could not be decoded have no
+    /// question.
+    pub question: Option<(Name, u16)>,
+    /// The number of records in the answer, authority, and additional sections.
+    pub counts: [u16; 3],
+}
+
+impl ResponseSummary {
+    /// Reads the header of an encoded response, which must be at least 12 bytes long. The
+    /// question is given by the caller, who already knows it.
+    pub fn from_header(message: &Bytes, question: Option<(Name, u16)>) -> ResponseSummary {
+        let u16_at = |i: usize| u16::from(message[i]) << 8 | u16::from(message[i + 1]);
+        ResponseSummary {
+            id: u16_at(0),
+            rcode: message[3] & 0xf,
+            truncated: message[2] & 0x02 != 0,
+            question,
+            counts: [u16_at(6), u16_at(8), u16_at(10)],
+        }
+    }
+
+    /// Reads the summary of an encoded response. Returns `None` if it cannot be parsed.
+    pub fn parse(message: &Bytes) -> Option<ResponseSummary> {
+        let mut buf = Cursor::new(message.clone());
+        if buf.remaining() < 12 {
+            return None;
+        }
+        let id = buf.get_u16_be();
+        let flags = buf.get_u16_be();
+        let qdcount = buf.get_u16_be();
+        let counts = [buf.get_u16_be(), buf.get_u16_be(), buf.get_u16_be()];
+        let question = if qdcount == 0 {
+            None
+        } else {
+            let name = Name::decode(&mut buf).ok()?;
+            if buf.remaining() < 2 {
+                return None;
+            }
+            Some((name, buf.get_u16_be()))
+        };
+        Some(ResponseSummary {
+            id,
+            rcode: (flags & 0xf) as u8,
+            truncated: flags & 0x0200 != 0,
+            question,
+            counts,
+        })
+    }
+}
+
+/// An encoded response decoded in full, for showing to people.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedResponse {
+    pub id: u16,
+    /// The second 16 bits of the header, holding the flags and RCODE.
+    pub flags: u16,
+    /// The name and type of each question.
+    pub questions: Vec<(Name, u16)>,
+    pub answer: Vec<DecodedRecord>,
+    pub authority: Vec<DecodedRecord>,
+    pub additional: Vec<DecodedRecord>,
+}
+
+/// A resource record read from an encoded message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedRecord {
+    pub name: Name,
+    pub record_type: u16,
+    pub ttl: u32,
+    /// The record data in presentation format. Types pepbut does not serve are shown in the
+    /// generic format ([RFC 3597 § 5](https://tools.ietf.org/html/rfc3597#section-5)).
+    pub rdata: String,
+}
+
+impl DecodedResponse {
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0xf) as u8
+    }
+}
+
+impl ProtocolDecode for DecodedResponse {
+    fn decode(buf: &mut Cursor<Bytes>) -> Result<DecodedResponse, ProtocolDecodeError> {
+        if buf.remaining() < 12 {
+            return Err(ProtocolDecodeError::Truncated);
+        }
+        let id = buf.get_u16_be();
+        let flags = buf.get_u16_be();
+        let qdcount = buf.get_u16_be();
+        let counts = [buf.get_u16_be(), buf.get_u16_be(), buf.get_u16_be()];
+
+        let mut questions = Vec::with_capacity(usize::from(qdcount));
+        for _ in 0..qdcount {
+            let name = Name::decode(buf)?;
+            if buf.remaining() < 4 {
+                return Err(ProtocolDecodeError::Truncated);
+            }
+            questions.push((name, buf.get_u16_be()));
+            buf.advance(2);
+        }
+        let mut sections = counts.iter().map(|&count| {
+            (0..count)
+                .map(|_| DecodedRecord::decode(buf))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        // Sections are decoded in order, as each starts where the last ended
+        let answer = sections.next().expect("cannot fail")?;
+        let authority = sections.next().expect("cannot fail")?;
+        let additional = sections.next().expect("cannot fail")?;
+        Ok(DecodedResponse {
+            id,
+            flags,
+            questions,
+            answer,
+            authority,
+            additional,
+        })
+    }
+}
+
+impl ProtocolDecode for DecodedRecord {
+    fn decode(buf: &mut Cursor<Bytes>) -> Result<DecodedRecord, ProtocolDecodeError> {
+        let name = Name::decode(buf)?;
+        if buf.remaining() < 10 {
+            return Err(ProtocolDecodeError::Truncated);
+        }
+        let record_type = buf.get_u16_be();
+        buf.advance(2);
+        let ttl = buf.get_u32_be();
+        let rdlen = usize::from(buf.get_u16_be());
+        if buf.remaining() < rdlen {
+            return Err(ProtocolDecodeError::Truncated);
+        }
+        let end = buf.position() + rdlen as u64;
+        // Names in the record data may point anywhere in the message, so the whole message stays
+        // readable, but the data must not run past its own length
+        let rdata = decode_rdata(buf, record_type, rdlen)?;
+        if buf.position() > end {
+            return Err(ProtocolDecodeError::Truncated);
+        }
+        buf.set_position(end);
+        Ok(DecodedRecord {
+            name,
+            record_type,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+/// Reads `rdlen` bytes of record data and formats it in presentation format.
+fn decode_rdata(
+    buf: &mut Cursor<Bytes>,
+    record_type: u16,
+    rdlen: usize,
+) -> Result<String, ProtocolDecodeError> {
+    Ok(match record_type {
+        1 if rdlen == 4 => RData::A(buf.get_u32_be().into()).to_string(),
+        28 if rdlen == 16 => {
+            let mut octets = [0; 16];
+            buf.copy_to_slice(&mut octets);
+            RData::AAAA(octets.into()).to_string()
+        }
+        2 => RData::NS(Name::decode(buf)?).to_string(),
+        5 => RData::CNAME(Name::decode(buf)?).to_string(),
+        12 => RData::PTR(Name::decode(buf)?).to_string(),
+        15 if rdlen > 2 => RData::MX {
+            preference: buf.get_u16_be(),
+            exchange: Name::decode(buf)?,
+        }.to_string(),
+        33 if rdlen > 6 => RData::SRV {
+            priority: buf.get_u16_be(),
+            weight: buf.get_u16_be(),
+            port: buf.get_u16_be(),
+            target: Name::decode(buf)?,
+        }.to_string(),
+        6 => {
+            let mname = Name::decode(buf)?;
+            let rname = Name::decode(buf)?;
+            if buf.remaining() < 20 {
+                return Err(ProtocolDecodeError::Truncated);
+            }
+            format!(
+                "{} {} {} {} {} {} {}",
+                mname.to_fqdn(),
+                rname.to_fqdn(),
+                buf.get_u32_be(),
+                buf.get_u32_be(),
+                buf.get_u32_be(),
+                buf.get_u32_be(),
+                buf.get_u32_be()
+            )
+        }
+        16 => {
+            let mut data = vec![0; rdlen];
+            buf.copy_to_slice(&mut data);
+            // Each string is a length byte followed by that many bytes
+            let mut text = Vec::with_capacity(rdlen);
+            let mut rest = &data[..];
+            while let Some((&len, tail)) = rest.split_first() {
+                let len = usize::from(len);
+                if tail.len() < len {
+                    return Err(ProtocolDecodeError::Truncated);
+                }
+                text.extend_from_slice(&tail[..len]);
+                rest = &tail[len..];
+            }
+            RData::TXT(String::from_utf8_lossy(&text).into_owned()).to_string()
+        }
+        _ => {
+            let mut data = vec![0; rdlen];
+            buf.copy_to_slice(&mut data);
+            let mut generic = format!("\\# {}", rdlen);
+            if rdlen > 0 {
+                generic.push(' ');
+                generic.extend(data.iter().map(|b| format!("{:02x}", b)));
+            }
+            generic
+        }
+    })
+}
+
 /// Types that implement `ProtocolDecode` can be decoded from a DNS message packet.
 pub trait ProtocolDecode: Sized {
     /// Read this type off the buffer.
@@ -55,6 +360,12 @@ pub enum ProtocolDecodeError {
     /// pepbut only responds to queries where QR, OPCODE, and TC are all 0.
     #[fail(display = "unacceptable query header")]
     UnacceptableHeader,
+    /// The EDNS OPT pseudo-record was malformed, or more than one was present.
+    #[fail(display = "malformed EDNS OPT record")]
+    MalformedEdns,
+    /// The message ended in the middle of a name, question, or record.
+    #[fail(display = "message is truncated")]
+    Truncated,
 }
 
 /// A query message, one of the two message types in the DNS protocol (the other being
@@ -68,14 +379,33 @@ pub struct QueryMessage {
     pub name: Name,
     /// The record type being queried.
     pub record_type: u16,
+    /// The EDNS OPT pseudo-record, if the client sent one.
+    pub edns: Option<Edns>,
 }
 
 impl QueryMessage {
     /// Creates a [`ResponseMessage`] given a [`LookupResult`].
+    ///
+    /// If the query used EDNS, the response does as well, with no options set.
     pub fn respond(self, answer: LookupResult) -> ResponseMessage {
+        let edns = self.edns.as_ref().map(|_| Edns::default());
         ResponseMessage {
             query: self,
             answer,
+            edns,
+            truncated: false,
+        }
+    }
+
+    /// The largest response that can be sent to the client over UDP: the UDP payload size from
+    /// its EDNS OPT record, but no more than we advertise, or 512 bytes without EDNS
+    /// ([RFC 6891 § 6.2.5](https://tools.ietf.org/html/rfc6891#section-6.2.5)).
+    pub fn udp_payload_size(&self) -> usize {
+        match self.edns {
+            Some(ref edns) => {
+                usize::from(edns.udp_payload_size.min(EDNS_UDP_PAYLOAD_SIZE).max(512))
+            }
+            None => 512,
         }
     }
 }
@@ -120,6 +450,9 @@ impl ProtocolDecode for QueryMessage {
         //
         // First, check the length of `buf` is at least 12. Then, verify that QR, OPCODE, and TC
         // are all 0 (which is relatively easy as they all reside in the same byte).
+        if buf.remaining() < 12 {
+            return Err(ProtocolDecodeError::Truncated);
+        }
         let id = buf.get_u16_be();
         if buf.get_u8() & 0b1111_1010 != 0 {
             return Err(ProtocolDecodeError::UnacceptableHeader);
@@ -132,7 +465,9 @@ impl ProtocolDecode for QueryMessage {
         if qdcount < 1 {
             return Err(ProtocolDecodeError::NoQuestions);
         }
-        let other_count = buf.get_u16_be() + buf.get_u16_be() + buf.get_u16_be();
+        let other_count = u32::from(buf.get_u16_be())
+            + u32::from(buf.get_u16_be())
+            + u32::from(buf.get_u16_be());
 
         // Next after the header is the question section.
         //
@@ -156,6 +491,9 @@ impl ProtocolDecode for QueryMessage {
         //     +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
         // ```
         let name = Name::decode(buf)?;
+        if buf.remaining() < 4 {
+            return Err(ProtocolDecodeError::Truncated);
+        }
         let record_type = buf.get_u16_be();
         if buf.get_u16_be() != 1 {
             return Err(ProtocolDecodeError::UnacceptableClass);
@@ -164,27 +502,173 @@ impl ProtocolDecode for QueryMessage {
         // Read off the rest of the questions and other sections to reach EDNS
         for _ in 1..qdcount {
             Name::decode(buf)?;
+            if buf.remaining() < 4 {
+                return Err(ProtocolDecodeError::Truncated);
+            }
             buf.advance(4);
         }
+        let mut edns = None;
         for _ in 0..other_count {
-            Name::decode(buf)?;
-            buf.advance(8);
-            let len = buf.get_u16_be();
-            buf.advance(len as usize);
+            let rr_name = Name::decode(buf)?;
+            if buf.remaining() < 2 {
+                return Err(ProtocolDecodeError::Truncated);
+            }
+            if buf.get_u16_be() == OPT_TYPE {
+                if edns.is_some() || !rr_name.is_empty() {
+                    return Err(ProtocolDecodeError::MalformedEdns);
+                }
+                edns = Some(Edns::decode(buf)?);
+            } else {
+                // CLASS and TTL, then RDLENGTH and RDATA
+                if buf.remaining() < 8 {
+                    return Err(ProtocolDecodeError::Truncated);
+                }
+                buf.advance(6);
+                let len = buf.get_u16_be() as usize;
+                if buf.remaining() < len {
+                    return Err(ProtocolDecodeError::Truncated);
+                }
+                buf.advance(len);
+            }
         }
 
         Ok(QueryMessage {
             id,
             name,
             record_type,
+            edns,
         })
     }
 }
 
+/// The contents of an EDNS OPT pseudo-record
+/// ([RFC 6891 § 6](https://tools.ietf.org/html/rfc6891#section-6)).
+///
+/// Options other than those represented here are ignored when decoding.
+#[derive(Debug, PartialEq)]
+pub struct Edns {
+    /// The largest UDP payload the sender can reassemble.
+    pub udp_payload_size: u16,
+    /// The EDNS version.
+    pub version: u8,
+    /// The `edns-tcp-keepalive` option
+    /// ([RFC 7828](https://tools.ietf.org/html/rfc7828)), if present. Clients send the option
+    /// without a timeout; servers respond with their idle timeout in units of 100 milliseconds.
+    pub tcp_keepalive: Option<Option<u16>>,
+}
+
+impl Default for Edns {
+    fn default() -> Edns {
+        Edns {
+            udp_payload_size: EDNS_UDP_PAYLOAD_SIZE,
+            version: 0,
+            tcp_keepalive: None,
+        }
+    }
+}
+
+impl ProtocolDecode for Edns {
+    /// Reads the OPT record following its owner name and type.
+    fn decode(buf: &mut Cursor<Bytes>) -> Result<Edns, ProtocolDecodeError> {
+        // RFC 6891 § 6.1.2: CLASS is the requestor's UDP payload size, and TTL is the extended
+        // RCODE, version, and flags.
+        //
+        // ```text
+        //                 +0 (MSB)                            +1 (LSB)
+        //      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+        //   0: |         EXTENDED-RCODE        |            VERSION            |
+        //      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+        //   2: | DO|                           Z                               |
+        //      +---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+---+
+        // ```
+        if buf.remaining() < 8 {
+            return Err(ProtocolDecodeError::MalformedEdns);
+        }
+        let udp_payload_size = buf.get_u16_be();
+        buf.advance(1);
+        let version = buf.get_u8();
+        buf.advance(2);
+        let rdlen = buf.get_u16_be() as usize;
+        if buf.remaining() < rdlen {
+            return Err(ProtocolDecodeError::MalformedEdns);
+        }
+
+        // The RDATA is a sequence of {OPTION-CODE, OPTION-LENGTH, OPTION-DATA} triples.
+        let mut edns = Edns {
+            udp_payload_size,
+            version,
+            tcp_keepalive: None,
+        };
+        let mut remaining = rdlen;
+        while remaining > 0 {
+            if remaining < 4 {
+                return Err(ProtocolDecodeError::MalformedEdns);
+            }
+            let code = buf.get_u16_be();
+            let len = buf.get_u16_be() as usize;
+            if remaining - 4 < len {
+                return Err(ProtocolDecodeError::MalformedEdns);
+            }
+            match (code, len) {
+                (TCP_KEEPALIVE_OPTION, 0) => edns.tcp_keepalive = Some(None),
+                (TCP_KEEPALIVE_OPTION, 2) => edns.tcp_keepalive = Some(Some(buf.get_u16_be())),
+                (TCP_KEEPALIVE_OPTION, _) => return Err(ProtocolDecodeError::MalformedEdns),
+                _ => buf.advance(len),
+            }
+            remaining -= 4 + len;
+        }
+        Ok(edns)
+    }
+}
+
+impl ProtocolEncode for Edns {
+    /// Writes the complete OPT record, including its owner name and type.
+    fn encode(
+        &self,
+        buf: &mut BytesMut,
+        _names: &mut HashMap<Name, u16>,
+    ) -> Result<(), cast::Error> {
+        let rdlen = match self.tcp_keepalive {
+            Some(Some(_)) => 6,
+            Some(None) => 4,
+            None => 0,
+        };
+        buf.reserve(11 + rdlen);
+        // NAME (the root), TYPE, CLASS
+        buf.put_u8(0);
+        buf.put_u16_be(OPT_TYPE);
+        buf.put_u16_be(self.udp_payload_size);
+        // EXTENDED-RCODE, VERSION, DO + Z
+        buf.put_u8(0);
+        buf.put_u8(self.version);
+        buf.put_u16_be(0);
+        // RDLEN, RDATA
+        buf.put_u16_be(u16(rdlen)?);
+        match self.tcp_keepalive {
+            Some(Some(timeout)) => {
+                buf.put_u16_be(TCP_KEEPALIVE_OPTION);
+                buf.put_u16_be(2);
+                buf.put_u16_be(timeout);
+            }
+            Some(None) => {
+                buf.put_u16_be(TCP_KEEPALIVE_OPTION);
+                buf.put_u16_be(0);
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ResponseMessage<'a> {
     pub query: QueryMessage,
     pub answer: LookupResult<'a>,
+    /// The EDNS OPT pseudo-record to include in the additional section, if any.
+    pub edns: Option<Edns>,
+    /// Whether the response is too large for the transport. If set, TC is set and only the
+    /// question and the EDNS OPT pseudo-record are included, so that the client retries over TCP.
+    pub truncated: bool,
 }
 
 impl<'a> ProtocolEncode for ResponseMessage<'a> {
@@ -200,11 +684,15 @@ impl<'a> ProtocolEncode for ResponseMessage<'a> {
         // +--+--+--+--+--+--+--+--+
         // |QR|   Opcode  |AA|TC|RD|
         // +--+--+--+--+--+--+--+--+
-        buf.put_u8(if self.answer.authoritative() {
+        let mut flags = if self.answer.authoritative() {
             0b1000_0100_u8
         } else {
             0b1000_0000_u8
-        });
+        };
+        if self.truncated {
+            flags |= 0b0000_0010;
+        }
+        buf.put_u8(flags);
 
         // +--+--+--+--+--+--+--+--+
         // |RA|   Z    |   RCODE   |
@@ -214,7 +702,15 @@ impl<'a> ProtocolEncode for ResponseMessage<'a> {
         // QDCOUNT
         buf.put_u16_be(1);
         // ANCOUNT, NSCOUNT, ARCOUNT
-        for x in &self.answer.counts() {
+        let mut counts = if self.truncated {
+            [0; 3]
+        } else {
+            self.answer.counts()
+        };
+        if self.edns.is_some() {
+            counts[2] += 1;
+        }
+        for x in &counts {
             buf.put_u16_be(u16(*x)?);
         }
 
@@ -225,7 +721,13 @@ impl<'a> ProtocolEncode for ResponseMessage<'a> {
         buf.put_u16_be(1);
 
         // Answer, authority, and additional sections
-        self.answer.encode(buf, names)
+        if !self.truncated {
+            self.answer.encode(buf, names)?;
+        }
+        match self.edns {
+            Some(ref edns) => edns.encode(buf, names),
+            None => Ok(()),
+        }
     }
 }
 
@@ -235,11 +737,15 @@ mod tests {
     use std::collections::HashMap;
     use std::io::Cursor;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use name::Name;
     use record::{RData, Record};
-    use wire::{ProtocolDecode, ProtocolEncode, QueryMessage, ResponseMessage};
-    use zone::LookupResult;
+    use wire::{
+        encode_err, response_min_ttl, DecodedRecord, DecodedResponse, Edns, ProtocolDecode,
+        ProtocolEncode, QueryMessage, ResponseMessage, ResponseSummary,
+    };
+    use zone::{LookupResult, SOAParams, Zone};
 
     #[test]
     fn decode_query() {
@@ -252,10 +758,30 @@ mod tests {
                 id: 0x862a,
                 name: Name::from_str("google.com").unwrap(),
                 record_type: 1,
+                edns: None,
             }
         );
     }
 
+    #[test]
+    fn decode_truncated_query() {
+        // A query for google.com. IN A with an A record in the additional section
+        let query = [
+            0x86, 0x2a, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, 0x67,
+            0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x01, 0x25, 0x00, 0x04, 0xd8, 0x3a,
+            0xd3, 0x8e,
+        ];
+        assert!(QueryMessage::decode(&mut Cursor::new(Bytes::from(&query[..]))).is_ok());
+        for len in 0..query.len() {
+            assert!(QueryMessage::decode(&mut Cursor::new(Bytes::from(&query[..len]))).is_err());
+        }
+        // A compression pointer past the end of the message
+        let mut bad = query.to_vec();
+        bad[29] = 0xff;
+        assert!(QueryMessage::decode(&mut Cursor::new(Bytes::from(bad))).is_err());
+    }
+
     #[test]
     fn encode_response() {
         let mut buf = BytesMut::new();
@@ -265,12 +791,15 @@ mod tests {
                 id: 0x862a,
                 name: Name::from_str("google.com").unwrap(),
                 record_type: 1,
+                edns: None,
             },
             answer: LookupResult::Records(&vec![Record::new(
                 Name::from_str("google.com").unwrap(),
                 293,
                 RData::A([216, 58, 211, 142].into()),
             )]),
+            edns: None,
+            truncated: false,
         }.encode(&mut buf, &mut names)
         .unwrap();
         assert_eq!(
@@ -282,5 +811,222 @@ mod tests {
                 0xd3, 0x8e,
             ]
         );
+        assert_eq!(response_min_ttl(&buf.freeze()), Some(293));
+    }
+
+    #[test]
+    fn encode_truncated_response() {
+        let mut buf = BytesMut::new();
+        ResponseMessage {
+            query: QueryMessage {
+                id: 0x862a,
+                name: Name::from_str("google.com").unwrap(),
+                record_type: 1,
+                edns: None,
+            },
+            answer: LookupResult::Records(&vec![Record::new(
+                Name::from_str("google.com").unwrap(),
+                293,
+                RData::A([216, 58, 211, 142].into()),
+            )]),
+            edns: None,
+            truncated: true,
+        }.encode(&mut buf, &mut HashMap::new())
+        .unwrap();
+        assert_eq!(
+            buf,
+            vec![
+                0x86, 0x2a, 0x86, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x67,
+                0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+            ]
+        );
+    }
+
+    #[test]
+    fn min_ttl_negative() {
+        let mut zone = Zone::new(Name::from_str("example.invalid").unwrap(), 1);
+        zone.soa = Arc::new(SOAParams {
+            minimum: 300,
+            ..SOAParams::default()
+        });
+        let mut buf = BytesMut::new();
+        ResponseMessage {
+            query: QueryMessage {
+                id: 0x862a,
+                name: Name::from_str("nope.example.invalid").unwrap(),
+                record_type: 1,
+                edns: Some(Edns::default()),
+            },
+            answer: LookupResult::NoName(zone.soa_record()),
+            edns: Some(Edns::default()),
+            truncated: false,
+        }.encode(&mut buf, &mut HashMap::new())
+        .unwrap();
+        assert_eq!(response_min_ttl(&buf.freeze()), Some(300));
+        assert_eq!(response_min_ttl(&encode_err(0x862a, 5)), None);
+    }
+
+    #[test]
+    fn summarize_response() {
+        let zone = Zone::new(Name::from_str("example.invalid").unwrap(), 1);
+        let mut buf = BytesMut::new();
+        ResponseMessage {
+            query: QueryMessage {
+                id: 0x862a,
+                name: Name::from_str("nope.example.invalid").unwrap(),
+                record_type: 28,
+                edns: Some(Edns::default()),
+            },
+            answer: LookupResult::NoName(zone.soa_record()),
+            edns: Some(Edns::default()),
+            truncated: false,
+        }.encode(&mut buf, &mut HashMap::new())
+        .unwrap();
+        let message = buf.freeze();
+        let question = (Name::from_str("nope.example.invalid").unwrap(), 28);
+        let summary = ResponseSummary {
+            id: 0x862a,
+            rcode: 3,
+            truncated: false,
+            question: Some(question.clone()),
+            counts: [0, 0, 2],
+        };
+        assert_eq!(ResponseSummary::parse(&message), Some(summary.clone()));
+        assert_eq!(ResponseSummary::from_header(&message, Some(question)), summary);
+        assert_eq!(
+            ResponseSummary::parse(&encode_err(0x862a, 1)),
+            Some(ResponseSummary {
+                id: 0x862a,
+                rcode: 1,
+                truncated: false,
+                question: None,
+                counts: [0, 0, 0],
+            })
+        );
+        assert_eq!(ResponseSummary::parse(&Bytes::from_static(&[0x86, 0x2a])), None);
+    }
+
+    #[test]
+    fn decode_response() {
+        let zone = Zone::new(Name::from_str("example.invalid").unwrap(), 1);
+        let mut buf = BytesMut::new();
+        ResponseMessage {
+            query: QueryMessage {
+                id: 0x862a,
+                name: Name::from_str("nope.example.invalid").unwrap(),
+                record_type: 28,
+                edns: None,
+            },
+            answer: LookupResult::NoName(zone.soa_record()),
+            edns: None,
+            truncated: false,
+        }.encode(&mut buf, &mut HashMap::new())
+        .unwrap();
+        let message = buf.freeze();
+        let response = DecodedResponse::decode(&mut Cursor::new(message.clone())).unwrap();
+        assert_eq!(response.id, 0x862a);
+        assert_eq!(response.rcode(), 3);
+        assert_eq!(
+            response.questions,
+            vec![(Name::from_str("nope.example.invalid").unwrap(), 28)]
+        );
+        assert!(response.answer.is_empty());
+        let soa = response
+            .authority
+            .iter()
+            .chain(&response.additional)
+            .map(|rr| (rr.name.to_fqdn(), rr.record_type))
+            .collect::<Vec<_>>();
+        assert_eq!(soa, vec![("example.invalid.".to_owned(), 6)]);
+
+        // Every prefix of the message is truncated
+        for len in 0..message.len() {
+            assert!(DecodedResponse::decode(&mut Cursor::new(message.slice_to(len))).is_err());
+        }
+    }
+
+    #[test]
+    fn decode_record() {
+        let record = |rdata: &[u8], record_type: u16| {
+            // The root name, then the type, class IN, a TTL of 60, and RDLENGTH
+            let mut message = vec![0, 0, record_type as u8, 0, 1, 0, 0, 0, 60, 0];
+            message.push(rdata.len() as u8);
+            message.extend_from_slice(rdata);
+            DecodedRecord::decode(&mut Cursor::new(Bytes::from(message))).map(|rr| rr.rdata)
+        };
+        assert_eq!(record(&[192, 0, 2, 1], 1).unwrap(), "192.0.2.1");
+        assert_eq!(record(&[0, 10, 2, 0x6d, 0x78, 0], 15).unwrap(), "10 mx.");
+        assert_eq!(
+            record(&[3, 0x61, 0x62, 0x63, 1, 0x22], 16).unwrap(),
+            "\"abc\\\"\""
+        );
+        assert_eq!(record(&[1, 2], 99).unwrap(), "\\# 2 0102");
+        // Data must not run past its length
+        assert!(record(&[2, 0x6d, 0x78], 2).is_err());
+        assert!(record(&[3, 0x61], 16).is_err());
+    }
+
+    #[test]
+    fn decode_query_edns() {
+        let query = [
+            0x86, 0x2a, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x06, 0x67,
+            0x6f, 0x6f, 0x67, 0x6c, 0x65, 0x03, 0x63, 0x6f, 0x6d, 0x00, 0x00, 0x01, 0x00, 0x01,
+            // OPT: root, type 41, payload size 4096, version 0, RDLEN 8
+            0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x08,
+            // An unknown option (COOKIE), then edns-tcp-keepalive with no timeout
+            0x00, 0x0a, 0x00, 0x00, 0x00, 0x0b, 0x00, 0x00,
+        ];
+        assert_eq!(
+            QueryMessage::decode(&mut Cursor::new(Bytes::from(&query[..])))
+                .unwrap()
+                .edns,
+            Some(Edns {
+                udp_payload_size: 4096,
+                version: 0,
+                tcp_keepalive: Some(None),
+            })
+        );
+
+        // An option length that runs past the end of RDATA
+        let mut bad = query.to_vec();
+        bad[38] = 0x01;
+        assert!(QueryMessage::decode(&mut Cursor::new(Bytes::from(bad))).is_err());
+        // A keepalive option with an odd length
+        let mut bad = query.to_vec();
+        bad[38] = 0x09;
+        bad[46] = 0x01;
+        bad.push(0);
+        assert!(QueryMessage::decode(&mut Cursor::new(Bytes::from(bad))).is_err());
+    }
+
+    #[test]
+    fn encode_edns() {
+        let mut buf = BytesMut::new();
+        Edns::default().encode(&mut buf, &mut HashMap::new()).unwrap();
+        assert_eq!(
+            buf,
+            vec![0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]
+        );
+
+        let mut buf = BytesMut::new();
+        Edns {
+            tcp_keepalive: Some(Some(100)),
+            ..Edns::default()
+        }.encode(&mut buf, &mut HashMap::new())
+        .unwrap();
+        assert_eq!(
+            buf,
+            vec![
+                0x00, 0x00, 0x29, 0x04, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x06, 0x00, 0x0b, 0x00,
+                0x02, 0x00, 0x64,
+            ]
+        );
+        assert_eq!(
+            Edns::decode(&mut Cursor::new(Bytes::from(&buf[3..]))).unwrap(),
+            Edns {
+                tcp_keepalive: Some(Some(100)),
+                ..Edns::default()
+            }
+        );
     }
 }
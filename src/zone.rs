@@ -8,6 +8,7 @@ use rmp::{self, Marker};
 use std::collections::{HashMap, HashSet};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::str::FromStr;
+use std::sync::Arc;
 
 use msgpack::{check_len, Msgpack, ZoneReadError, ZoneWriteError};
 use name::Name;
@@ -22,6 +23,8 @@ pub struct Zone {
     /// The serial. This should generally always increase with zone updates, but pepbut does not
     /// implement zone transfers so the point is rather moot.
     pub serial: u32,
+    /// The values used for the zone's SOA record, other than the origin and serial.
+    pub soa: Arc<SOAParams>,
     /// The collection of records in the zone.
     records: HashMap<Name, HashMap<u16, Vec<Record>>>,
 }
@@ -32,6 +35,7 @@ impl Zone {
         Zone {
             origin,
             serial,
+            soa: Arc::new(SOAParams::default()),
             records: HashMap::new(),
         }
     }
@@ -118,6 +122,7 @@ impl Zone {
         SOARecord {
             origin: self.origin.clone(),
             serial: self.serial,
+            params: self.soa.clone(),
         }
     }
 
@@ -191,19 +196,53 @@ impl Zone {
     }
 }
 
+/// The parts of an SOA record that pepbut does not store in zone files.
+///
+/// pepbut does not implement zone transfers, so the timers are only meaningful for negative
+/// caching (see [RFC 2308 § 5](https://tools.ietf.org/html/rfc2308#section-5)).
+#[derive(Debug, Clone, PartialEq)]
+pub struct SOAParams {
+    /// The name of the primary name server for the zone.
+    pub mname: Name,
+    /// The mailbox of the person responsible for the zone, encoded as a name.
+    pub rname: Name,
+    /// The TTL of the SOA record itself.
+    pub ttl: u32,
+    pub refresh: u32,
+    pub retry: u32,
+    pub expire: u32,
+    /// The TTL used for negative caching.
+    pub minimum: u32,
+}
+
+impl Default for SOAParams {
+    fn default() -> SOAParams {
+        SOAParams {
+            mname: Name::from_str("ns1.wob.zone").expect("cannot fail"),
+            rname: Name::from_str("hostmistress.as64241.net").expect("cannot fail"),
+            ttl: 3600,
+            refresh: 1000,
+            retry: 2400,
+            expire: 604_800,
+            minimum: 3600,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct SOARecord {
     origin: Name,
     serial: u32,
+    params: Arc<SOAParams>,
 }
 
 impl SOARecord {
-    fn mname(&self) -> Name {
-        Name::from_str("ns1.wob.zone").expect("cannot fail")
+    fn mname(&self) -> &Name {
+        &self.params.mname
     }
 
-    fn rname(&self) -> Name {
-        Name::from_str("hostmistress.as64241.net").expect("cannot fail")
+    fn rname(&self) -> &Name {
+        &self.params.rname
     }
 }
 
@@ -217,7 +256,7 @@ impl RecordTrait for SOARecord {
     }
 
     fn ttl(&self) -> u32 {
-        3600
+        self.params.ttl
     }
 
     fn encode_rdata_len(&self, names: &HashSet<Name>) -> Result<u16, cast::Error> {
@@ -234,10 +273,10 @@ impl RecordTrait for SOARecord {
         self.rname().encode(buf, names)?;
         buf.reserve(20);
         buf.put_u32_be(self.serial);
-        buf.put_u32_be(1000);
-        buf.put_u32_be(2400);
-        buf.put_u32_be(604_800);
-        buf.put_u32_be(3600);
+        buf.put_u32_be(self.params.refresh);
+        buf.put_u32_be(self.params.retry);
+        buf.put_u32_be(self.params.expire);
+        buf.put_u32_be(self.params.minimum);
         Ok(())
     }
 }
@@ -371,10 +410,11 @@ impl<'a> ProtocolEncode for LookupResult<'a> {
 mod tests {
     use std::io::Cursor;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use name::Name;
     use record::{RData, Record};
-    use zone::{LookupResult, SOARecord, Zone};
+    use zone::{LookupResult, SOAParams, SOARecord, Zone};
 
     impl<'a> LookupResult<'a> {
         /// Returns `true` if the lookup contains no records other than the SOA record.
@@ -489,6 +529,7 @@ mod tests {
             SOARecord {
                 origin: Name::from_str("example.invalid.").unwrap(),
                 serial: 1234567890,
+                params: Arc::new(SOAParams::default()),
             }
         );
     }